@@ -0,0 +1,3 @@
+fn main() -> anyhow::Result<()> {
+    vorpal_build::Builder::new("project.vor").write("vorpal.rs")
+}
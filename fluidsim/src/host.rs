@@ -0,0 +1,33 @@
+//! Safe wrappers around the functions the host provides in the `vorpal_host` module
+
+#[link(wasm_import_module = "vorpal_host")]
+extern "C" {
+    #[link_name = "log"]
+    fn host_log(ptr: *const u8, len: usize);
+    #[link_name = "frame_index"]
+    fn host_frame_index() -> u64;
+    #[link_name = "delta_time"]
+    fn host_delta_time() -> f32;
+    #[link_name = "request_resolution"]
+    fn host_request_resolution(width: u32, height: u32);
+}
+
+/// Print a message to the host's console
+pub fn log(message: &str) {
+    unsafe { host_log(message.as_ptr(), message.len()) }
+}
+
+/// Number of frames drawn since the plugin was loaded
+pub fn frame_index() -> u64 {
+    unsafe { host_frame_index() }
+}
+
+/// Seconds since the previous frame
+pub fn delta_time() -> f32 {
+    unsafe { host_delta_time() }
+}
+
+/// Ask for the following frames to be drawn at a different size
+pub fn request_resolution(width: u32, height: u32) {
+    unsafe { host_request_resolution(width, height) }
+}
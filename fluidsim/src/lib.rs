@@ -5,6 +5,10 @@ use fluid::{FluidSim, SmokeSim};
 
 mod array2d;
 mod fluid;
+mod host;
+
+/// How often the frame time is logged
+const LOG_INTERVAL_FRAMES: u64 = 600;
 
 /// This is the "main" function, generating an image and saving it in a
 /// place we may retrieve it later from the outside
@@ -18,48 +22,50 @@ pub extern "C" fn make_image(
 ) -> *const f32 {
     thread_local! {
         static BUFFER: RefCell<Option<Plugin>> = RefCell::new(None);
+        static BLANK: RefCell<Vec<f32>> = const { RefCell::new(Vec::new()) };
+    }
+
+    // The simulation is square, so draw nothing until the host switches to a square image
+    if width != height {
+        let side = width.min(height);
+        host::log(&format!(
+            "fluidsim: requires a square image, requesting {side}x{side}"
+        ));
+        host::request_resolution(side, side);
+
+        return BLANK.with(|blank| {
+            let mut blank = blank.borrow_mut();
+            blank.clear();
+            blank.resize((width * height * 4) as usize, 0.);
+            blank.as_ptr()
+        });
+    }
+
+    let frame_index = host::frame_index();
+    if frame_index.is_multiple_of(LOG_INTERVAL_FRAMES) {
+        host::log(&format!(
+            "fluidsim: frame {frame_index}, {:.1} ms since the previous one",
+            host::delta_time() * 1000.
+        ));
     }
 
     BUFFER.with(|buffer| {
         let mut maybe_plugin = buffer.borrow_mut();
-        let plugin = maybe_plugin.get_or_insert_with(|| Plugin::new(width, height));
+        let resized = maybe_plugin
+            .as_ref()
+            .is_none_or(|plugin| plugin.fluid_sim.width() != width as usize);
+        if resized {
+            host::log(&format!("fluidsim: simulating {width}x{height}"));
+            *maybe_plugin = Some(Plugin::new(width, height));
+        }
+        let plugin = maybe_plugin.as_mut().unwrap();
 
         plugin.get_image(time, cursor_x, cursor_y).as_ptr()
     })
 }
 
-#[link(wasm_import_module = "add_velocity")]
-extern "C" {
-    fn add_velocity(
-        out_ptr: *mut f32,
-        cursor_position_pixels_x: f32,
-        cursor_position_pixels_y: f32,
-        last_cursor_position_pixels_x: f32,
-        last_cursor_position_pixels_y: f32,
-        position_pixels_x: f32,
-        position_pixels_y: f32,
-        resolution_pixels_x: f32,
-        resolution_pixels_y: f32,
-        time_seconds: f32,
-    );
-}
-
-#[link(wasm_import_module = "get_color")]
-extern "C" {
-    fn get_color(
-        out_ptr: *mut f32,
-        cursor_position_pixels_x: f32,
-        cursor_position_pixels_y: f32,
-        fluid_velocity_pixelsframe_x: f32,
-        fluid_velocity_pixelsframe_y: f32,
-        position_pixels_x: f32,
-        position_pixels_y: f32,
-        resolution_pixels_x: f32,
-        resolution_pixels_y: f32,
-        smoke_quantity: f32,
-        time_seconds: f32,
-    );
-}
+// Declarations of add_velocity() and get_color(), generated from project.vor
+include!(concat!(env!("OUT_DIR"), "/vorpal.rs"));
 
 struct Plugin {
     out_rgba: Vec<f32>,
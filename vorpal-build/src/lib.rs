@@ -0,0 +1,196 @@
+//! Generates Rust bindings for the functions of a `.vor` project at build time, so that host code
+//! always matches the node graphs it calls. From a build script:
+//!
+//! ```no_run
+//! vorpal_build::Builder::new("project.vor").write("vorpal.rs").unwrap();
+//! ```
+//!
+//! and then in the crate itself:
+//!
+//! ```ignore
+//! include!(concat!(env!("OUT_DIR"), "/vorpal.rs"));
+//! ```
+use anyhow::{Context, Result};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use vorpal_core::graph::Graph;
+use vorpal_core::project::ProjectFile;
+use vorpal_core::*;
+use vorpal_wasm::CodeAnalysis;
+
+/// The functions of a project, lowered to graphs
+pub struct Project {
+    pub functions: Vec<(String, Graph, ParameterList)>,
+    pub precision: Precision,
+}
+
+/// How the generated functions are provided
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Declare each function as an import from a wasm module of the same name, exactly as
+    /// `CodeAnalysis::func_name_rust` does. The host supplies the implementation at link time.
+    Extern,
+    /// Compile each function to Rust and include it directly, behind the same signature as the
+    /// import would have
+    Native,
+}
+
+/// Writes bindings for a project into `OUT_DIR`, for use in build scripts
+pub struct Builder {
+    project_path: PathBuf,
+    mode: Mode,
+}
+
+impl Project {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Opening project {}", path.display()))?;
+        let project: ProjectFile = serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Reading project {}", path.display()))?;
+
+        let functions = project
+            .functions
+            .into_iter()
+            .map(|(func_name, function)| {
+                let graph = function
+                    .extract_output_graph()
+                    .with_context(|| format!("Reading {func_name}()"))?;
+                Ok((func_name, graph, function.params()))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            functions,
+            precision: project.precision,
+        })
+    }
+
+    /// Generate the declaration or implementation of every function in the project
+    pub fn bindings(&self, mode: Mode) -> Result<String> {
+        let mut text = String::new();
+        for (func_name, graph, params) in &self.functions {
+            let binding = match mode {
+                Mode::Extern => CodeAnalysis::new(graph, params).and_then(|analysis| {
+                    analysis
+                        .with_precision(self.precision)
+                        .func_name_rust(func_name)
+                }),
+                Mode::Native => native_binding(graph, params, func_name, self.precision),
+            }
+            .with_context(|| format!("Generating {func_name}()"))?;
+            writeln!(&mut text, "{binding}")?;
+        }
+        Ok(text)
+    }
+}
+
+impl Builder {
+    pub fn new(project_path: impl Into<PathBuf>) -> Self {
+        Self {
+            project_path: project_path.into(),
+            mode: Mode::Extern,
+        }
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Write the bindings to `file_name` within `OUT_DIR`, and rebuild whenever the project
+    /// changes
+    pub fn write(self, file_name: impl AsRef<Path>) -> Result<()> {
+        println!("cargo:rerun-if-changed={}", self.project_path.display());
+
+        let bindings = Project::load(&self.project_path)?.bindings(self.mode)?;
+
+        let out_dir = std::env::var_os("OUT_DIR").context("OUT_DIR is not set")?;
+        let out_path = Path::new(&out_dir).join(file_name);
+        std::fs::write(&out_path, bindings)
+            .with_context(|| format!("Writing {}", out_path.display()))?;
+
+        Ok(())
+    }
+}
+
+/// A native implementation with the same signature as the extern declaration, so that host code
+/// does not change between modes
+fn native_binding(
+    graph: &Graph,
+    params: &ParameterList,
+    func_name: &str,
+    precision: Precision,
+) -> Result<String> {
+    let native = vorpal_rust::compile_to_rust_with_precision(graph, params, func_name, precision)?;
+    let float = precision.type_name();
+    let param_names = vorpal_rust::parameter_names(params);
+    let func_name = sanitize_identifier(func_name);
+
+    let mut text = String::new();
+    writeln!(&mut text, "#[allow(clippy::too_many_arguments)]")?;
+    writeln!(&mut text, "unsafe fn {func_name}(")?;
+    writeln!(&mut text, "    out_ptr: *mut {float},")?;
+    for name in &param_names {
+        writeln!(&mut text, "    {name}: {float},")?;
+    }
+    writeln!(&mut text, ") {{")?;
+    writeln!(
+        &mut text,
+        "    {}",
+        native.replace('\n', "\n    ").trim_end()
+    )?;
+    writeln!(&mut text)?;
+    writeln!(
+        &mut text,
+        "    let out = {func_name}({});",
+        param_names.join(", ")
+    )?;
+    writeln!(
+        &mut text,
+        "    std::ptr::copy_nonoverlapping(out.as_ptr(), out_ptr, out.len());"
+    )?;
+    writeln!(&mut text, "}}")?;
+
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fluidsim() -> Project {
+        Project::load(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../fluidsim/project.vor"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn projects_load_without_the_editor() {
+        let project = fluidsim();
+        let names: Vec<&str> = project
+            .functions
+            .iter()
+            .map(|(name, _, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["add_velocity", "get_color"]);
+
+        let bindings = project.bindings(Mode::Extern).unwrap();
+        assert!(bindings.contains(r#"#[link(wasm_import_module = "add_velocity")]"#));
+        assert!(bindings.contains("fn get_color("));
+    }
+
+    #[test]
+    fn native_bindings_follow_the_precision() {
+        let mut project = fluidsim();
+        for precision in [Precision::Single, Precision::Double] {
+            project.precision = precision;
+            let float = precision.type_name();
+            let bindings = project.bindings(Mode::Native).unwrap();
+            assert!(bindings.contains(&format!("out_ptr: *mut {float},")));
+            assert!(bindings.contains(&format!(") -> [{float}; 4] {{")));
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt::Write;
+use vorpal_core::graph::{Graph, NodeId, NodeKind};
+use vorpal_core::*;
+
+/// Vector types shared by every generated header
+const VECTOR_TYPES: &str = r#"#ifndef VORPAL_VECTOR_TYPES
+#define VORPAL_VECTOR_TYPES
+typedef struct { float x, y; } vorpal_vec2;
+typedef struct { float x, y, z; } vorpal_vec3;
+typedef struct { float x, y, z, w; } vorpal_vec4;
+#endif"#;
+
+/// Clamps a vector component index the same way the native backend does
+const INDEX_HELPER: &str = r#"static inline int vorpal_index(float index, int n_lanes) {
+    int i = (int)fminf(fmaxf(index, 0.0f), (float)n_lanes);
+    return i < n_lanes - 1 ? i : n_lanes - 1;
+}"#;
+
+/// A C header and source file pair, implementing a single function
+pub struct CSource {
+    /// Name of the function, which is also the name of the files: the header should be saved as
+    /// `{name}.h`, which is what the source includes
+    pub name: String,
+    pub header: String,
+    pub source: String,
+}
+
+/// Generate a self-contained C function which evaluates the graph. Each input in the parameter
+/// list becomes a parameter of the function, in order; vectors are passed and returned as
+/// `vorpal_vec2`, `vorpal_vec3` or `vorpal_vec4`. The function and its parameters are named with
+/// `sanitize_identifier` and `ParameterList::identifiers`.
+pub fn compile_to_c(graph: &Graph, params: &ParameterList, func_name: &str) -> Result<CSource> {
+    let dtypes = typecheck::infer_dtypes(graph, params).map_err(EvalError::TypeCheck)?;
+    let output_dtype = dtypes[graph.root().index()];
+    let func_name = sanitize_identifier(func_name);
+    let names: HashMap<&ExternInputId, String> = params
+        .inputs()
+        .iter()
+        .map(|(id, _)| id)
+        .zip(params.identifiers())
+        .collect();
+
+    let param_list = params
+        .inputs()
+        .iter()
+        .map(|(name, dtype)| format!("{} {}", c_type(*dtype), names[name]))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let param_list = if param_list.is_empty() {
+        "void".to_string()
+    } else {
+        param_list
+    };
+    let signature = format!("{} {func_name}({param_list})", c_type(output_dtype));
+
+    let guard = format!("VORPAL_{}_H", func_name.to_uppercase());
+    let header = format!(
+        r#"#ifndef {guard}
+#define {guard}
+
+{VECTOR_TYPES}
+
+{signature};
+
+#endif
+"#
+    );
+
+    let mut body = String::new();
+    for (name, _) in params.inputs() {
+        let used = graph
+            .nodes()
+            .iter()
+            .any(|node| matches!(node, NodeKind::ExternInput(id, _) if id == name));
+        if !used {
+            writeln!(&mut body, "    (void){};", names[name])?;
+        }
+    }
+
+    // Every node is computed once, in topological order, as an array of its lanes
+    for (node_id, node) in graph.iter() {
+        let n_lanes = dtypes[node_id.index()].n_lanes();
+        let lanes = compile_node(node, &dtypes, &names).join(", ");
+        writeln!(
+            &mut body,
+            "    const float {}[{n_lanes}] = {{{lanes}}};",
+            local(node_id)
+        )?;
+    }
+
+    let root = local(graph.root());
+    let ret = match output_dtype {
+        DataType::Scalar => format!("{root}[0]"),
+        dtype => {
+            let lanes = (0..dtype.n_lanes())
+                .map(|lane| format!("{root}[{lane}]"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("({}){{{lanes}}}", c_type(dtype))
+        }
+    };
+
+    let source = format!(
+        r#"#include <math.h>
+#include "{func_name}.h"
+
+{INDEX_HELPER}
+
+{signature} {{
+{body}    return {ret};
+}}
+"#
+    );
+
+    Ok(CSource {
+        name: func_name,
+        header,
+        source,
+    })
+}
+
+/// Expression for each lane of the node's output
+fn compile_node(
+    node: &NodeKind,
+    dtypes: &[DataType],
+    names: &HashMap<&ExternInputId, String>,
+) -> Vec<String> {
+    let lanes_of = |id: &NodeId| -> Vec<String> {
+        (0..dtypes[id.index()].n_lanes())
+            .map(|lane| format!("{}[{lane}]", local(*id)))
+            .collect()
+    };
+
+    match node {
+        NodeKind::ExternInput(name, dtype) => {
+            let name = &names[name];
+            if dtype.n_lanes() == 1 {
+                vec![name.clone()]
+            } else {
+                dtype
+                    .lane_names()
+                    .map(|lane| format!("{name}.{lane}"))
+                    .collect()
+            }
+        }
+        NodeKind::Constant(value) => value.iter_vector_floats().map(float_literal).collect(),
+        NodeKind::Make(components, _) => components
+            .iter()
+            .map(|component| format!("{}[0]", local(*component)))
+            .collect(),
+        NodeKind::ComponentInfixOp(a, op, b) => lanes_of(a)
+            .into_iter()
+            .zip(lanes_of(b))
+            .map(|(a, b)| infix_expr(*op, &a, &b))
+            .collect(),
+        NodeKind::ComponentFn(func, a) => lanes_of(a)
+            .into_iter()
+            .map(|a| format!("{}({a})", fn_name(*func)))
+            .collect(),
+        NodeKind::GetComponent(vector, index) => vec![format!(
+            "{}[vorpal_index({}[0], {})]",
+            local(*vector),
+            local(*index),
+            dtypes[vector.index()].n_lanes()
+        )],
+        NodeKind::Dot(a, b) => {
+            let products: Vec<String> = lanes_of(a)
+                .into_iter()
+                .zip(lanes_of(b))
+                .map(|(a, b)| format!("{a} * {b}"))
+                .collect();
+            vec![products.join(" + ")]
+        }
+    }
+}
+
+fn c_type(dtype: DataType) -> &'static str {
+    match dtype {
+        DataType::Scalar => "float",
+        DataType::Vec2 => "vorpal_vec2",
+        DataType::Vec3 => "vorpal_vec3",
+        DataType::Vec4 => "vorpal_vec4",
+    }
+}
+
+fn local(id: NodeId) -> String {
+    format!("node_{}", id.index())
+}
+
+fn infix_expr(op: ComponentInfixOp, a: &str, b: &str) -> String {
+    match op {
+        ComponentInfixOp::Add => format!("{a} + {b}"),
+        ComponentInfixOp::Subtract => format!("{a} - {b}"),
+        ComponentInfixOp::Multiply => format!("{a} * {b}"),
+        ComponentInfixOp::Divide => format!("{a} / {b}"),
+        ComponentInfixOp::Power => format!("powf({a}, {b})"),
+        ComponentInfixOp::Logbase => format!("logf({a}) / logf({b})"),
+        ComponentInfixOp::GreaterThan => format!("(float)({a} > {b})"),
+        ComponentInfixOp::LessThan => format!("(float)({a} < {b})"),
+        ComponentInfixOp::EqualTo => format!("(float)({a} == {b})"),
+    }
+}
+
+/// Name of the `math.h` function matching `ComponentFn::native`
+fn fn_name(func: ComponentFn) -> &'static str {
+    match func {
+        ComponentFn::Cosine => "cosf",
+        ComponentFn::Sine => "sinf",
+        ComponentFn::Tangent => "tanf",
+        ComponentFn::NaturalLog => "logf",
+        ComponentFn::NaturalExp => "expf",
+        ComponentFn::Ceil => "ceilf",
+        ComponentFn::Floor => "floorf",
+        ComponentFn::Abs => "fabsf",
+    }
+}
+
+/// A literal which round-trips exactly, including values which have no literal syntax
+fn float_literal(value: f32) -> String {
+    if value.is_nan() {
+        "NAN".into()
+    } else if value == f32::INFINITY {
+        "INFINITY".into()
+    } else if value == f32::NEG_INFINITY {
+        "-INFINITY".into()
+    } else {
+        format!("{value:?}f")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use vorpal_core::native_backend::evaluate_node;
+    use vorpal_test_support::{
+        assert_matches_native, awkward_names_case, component_and_dot_cases, component_fn_cases,
+        infix_op_cases, Case,
+    };
+
+    /// Compile every case into one program with cc, then check that each call gives the same
+    /// lanes as the interpreter
+    fn assert_cases_match_native(test_name: &str, func_name: &str, cases: &[Case]) {
+        let dir = std::env::temp_dir().join(format!("vorpal-c-{test_name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut includes = String::new();
+        let mut main = String::new();
+        let mut files = vec!["main.c".to_string()];
+        for (idx, (node, ctx)) in cases.iter().enumerate() {
+            let graph = Graph::from_node(node);
+            let params = ctx.build_parameter_list();
+            let c = compile_to_c(&graph, &params, &format!("{func_name}{idx}")).unwrap();
+            std::fs::write(dir.join(format!("{}.h", c.name)), &c.header).unwrap();
+            std::fs::write(dir.join(format!("{}.c", c.name)), &c.source).unwrap();
+            writeln!(&mut includes, "#include \"{}.h\"", c.name).unwrap();
+            files.push(format!("{}.c", c.name));
+
+            let args = params
+                .inputs()
+                .iter()
+                .map(|(id, dtype)| {
+                    let lanes = ctx.inputs()[id]
+                        .iter_vector_floats()
+                        .map(|arg| format!("from_bits({:#x}u)", arg.to_bits()))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    match dtype {
+                        DataType::Scalar => lanes,
+                        dtype => format!("({}){{{lanes}}}", c_type(*dtype)),
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            let output_dtype = evaluate_node(&graph, ctx).unwrap().dtype();
+            writeln!(
+                &mut main,
+                "    {{ {} out = {}({args}); print_lanes(&out, sizeof out); }}",
+                c_type(output_dtype),
+                c.name
+            )
+            .unwrap();
+        }
+
+        let program = format!(
+            r#"#include <stdint.h>
+#include <stdio.h>
+#include <string.h>
+{includes}
+static float from_bits(uint32_t bits) {{
+    float value;
+    memcpy(&value, &bits, sizeof value);
+    return value;
+}}
+
+static void print_lanes(const void *lanes, size_t size) {{
+    for (size_t i = 0; i < size / 4; i++) {{
+        uint32_t bits;
+        memcpy(&bits, (const char *)lanes + 4 * i, 4);
+        printf("%u ", bits);
+    }}
+    printf("\n");
+}}
+
+int main(void) {{
+{main}    return 0;
+}}
+"#
+        );
+        std::fs::write(dir.join("main.c"), &program).unwrap();
+
+        let status = Command::new("cc")
+            .current_dir(&dir)
+            .args(["-std=c99", "-Wall", "-Werror", "-o", "main"])
+            .args(&files)
+            .arg("-lm")
+            .status()
+            .unwrap();
+        assert!(status.success(), "Generated source failed to compile");
+        let output = Command::new(dir.join("main")).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(stdout.lines().count(), cases.len());
+        for (case, line) in cases.iter().zip(stdout.lines()) {
+            let generated: Vec<f32> = line
+                .split_whitespace()
+                .map(|bits| f32::from_bits(bits.parse().unwrap()))
+                .collect();
+            assert_matches_native(case, &generated);
+        }
+    }
+
+    #[test]
+    fn component_fns_match_native() {
+        assert_cases_match_native("component_fns", "f", &component_fn_cases());
+    }
+
+    #[test]
+    fn infix_ops_match_native() {
+        assert_cases_match_native("infix_ops", "f", &infix_op_cases());
+    }
+
+    #[test]
+    fn components_and_dot_match_native() {
+        assert_cases_match_native("components", "f", &component_and_dot_cases());
+    }
+
+    #[test]
+    fn awkward_names_compile() {
+        assert_cases_match_native("names", "static ", &[awkward_names_case()]);
+    }
+}
@@ -0,0 +1,378 @@
+//! Forward-mode automatic differentiation of high-level nodes
+use std::{collections::HashMap, rc::Rc};
+
+use crate::highlevel::{expand_normalize, HighNode};
+use crate::{ComponentFn, ComponentInfixOp, DataType, HashRcByPtr, Value};
+
+/// Build the nodes computing `HighNode::Gradient { f, wrt }`. The graph of `f` is differentiated
+/// once for each component of `wrt`; shared subexpressions are only differentiated once each.
+pub fn gradient(f: &Rc<HighNode>, wrt: &Rc<HighNode>) -> Rc<HighNode> {
+    let mut dtypes = Dtypes::default();
+    let wrt_dtype = dtypes.of(wrt);
+    let f_dtype = dtypes.of(f);
+
+    let mut components: Vec<Rc<HighNode>> = (0..wrt_dtype.n_lanes())
+        .map(|lane| {
+            let mut tangents = Tangents {
+                wrt,
+                seed: constant_lanes(wrt_dtype, |idx| f32::from(idx == lane)),
+                dtypes: &mut dtypes,
+                cache: HashMap::new(),
+            };
+            tangents.of(f).unwrap_or_else(|| constant(0., f_dtype))
+        })
+        .collect();
+
+    if components.len() == 1 {
+        components.remove(0)
+    } else {
+        Rc::new(HighNode::Make(components, wrt_dtype))
+    }
+}
+
+/// Datatype of each high-level node
+#[derive(Default)]
+struct Dtypes {
+    cache: HashMap<HashRcByPtr<HighNode>, DataType>,
+}
+
+impl Dtypes {
+    fn of(&mut self, node: &Rc<HighNode>) -> DataType {
+        let key = HashRcByPtr(node.clone());
+        if let Some(dtype) = self.cache.get(&key) {
+            return *dtype;
+        }
+
+        let dtype = match &**node {
+            HighNode::ExternInput(_, dtype)
+            | HighNode::Make(_, dtype)
+            | HighNode::Normalize(_, dtype)
+            | HighNode::Splat(_, dtype) => *dtype,
+            HighNode::Constant(value) => value.dtype(),
+            HighNode::ComponentInfixOp(a, _, _) | HighNode::ComponentFn(_, a) => self.of(a),
+            HighNode::GetComponent(_, _) | HighNode::Dot(_, _) => DataType::Scalar,
+            HighNode::Swizzle {
+                output_vector_dtype,
+                ..
+            } => *output_vector_dtype,
+            HighNode::Gradient { wrt, .. } => self.of(wrt),
+        };
+
+        self.cache.insert(key, dtype);
+        dtype
+    }
+}
+
+/// Derivatives of nodes along a single direction. `None` stands for a derivative which is zero
+/// everywhere, so that constant subgraphs are never differentiated.
+struct Tangents<'a> {
+    wrt: &'a Rc<HighNode>,
+    /// Derivative of `wrt` with respect to itself, along the chosen direction
+    seed: Rc<HighNode>,
+    dtypes: &'a mut Dtypes,
+    cache: HashMap<HashRcByPtr<HighNode>, Option<Rc<HighNode>>>,
+}
+
+impl Tangents<'_> {
+    fn of(&mut self, node: &Rc<HighNode>) -> Option<Rc<HighNode>> {
+        let key = HashRcByPtr(node.clone());
+        if let Some(tangent) = self.cache.get(&key) {
+            return tangent.clone();
+        }
+
+        let tangent = self.of_uncached(node);
+        self.cache.insert(key, tangent.clone());
+        tangent
+    }
+
+    fn is_wrt(&self, node: &Rc<HighNode>) -> bool {
+        match (&**node, &**self.wrt) {
+            (HighNode::ExternInput(a, _), HighNode::ExternInput(b, _)) => a == b,
+            _ => Rc::ptr_eq(node, self.wrt),
+        }
+    }
+
+    fn of_uncached(&mut self, node: &Rc<HighNode>) -> Option<Rc<HighNode>> {
+        if self.is_wrt(node) {
+            return Some(self.seed.clone());
+        }
+
+        match &**node {
+            HighNode::ExternInput(_, _) | HighNode::Constant(_) => None,
+            HighNode::Make(components, dtype) => {
+                let tangents: Vec<Option<Rc<HighNode>>> =
+                    components.iter().map(|c| self.of(c)).collect();
+                if tangents.iter().all(Option::is_none) {
+                    return None;
+                }
+                let tangents = tangents
+                    .into_iter()
+                    .map(|t| t.unwrap_or_else(|| constant(0., DataType::Scalar)))
+                    .collect();
+                Some(Rc::new(HighNode::Make(tangents, *dtype)))
+            }
+            HighNode::ComponentInfixOp(u, op, v) => {
+                let dtype = self.dtypes.of(u);
+                let du = self.of(u);
+                let dv = self.of(v);
+                use ComponentInfixOp as Op;
+                match op {
+                    Op::Add => sum(du, dv),
+                    Op::Subtract => sum(du, dv.map(|dv| neg(dv, dtype))),
+                    // (uv)' = u'v + uv'
+                    Op::Multiply => sum(
+                        du.map(|du| infix(du, Op::Multiply, v.clone())),
+                        dv.map(|dv| infix(u.clone(), Op::Multiply, dv)),
+                    ),
+                    // (u/v)' = u'/v - (u/v) v'/v
+                    Op::Divide => sum(
+                        du.map(|du| infix(du, Op::Divide, v.clone())),
+                        dv.map(|dv| {
+                            let quotient = infix(node.clone(), Op::Multiply, dv);
+                            neg(infix(quotient, Op::Divide, v.clone()), dtype)
+                        }),
+                    ),
+                    // (u^v)' = v u^(v-1) u' + u^v ln(u) v'
+                    Op::Power => sum(
+                        du.map(|du| {
+                            let v_minus_one = infix(v.clone(), Op::Subtract, constant(1., dtype));
+                            let slope = infix(u.clone(), Op::Power, v_minus_one);
+                            let slope = infix(v.clone(), Op::Multiply, slope);
+                            infix(slope, Op::Multiply, du)
+                        }),
+                        dv.map(|dv| {
+                            let ln_u = func(ComponentFn::NaturalLog, u.clone());
+                            let slope = infix(node.clone(), Op::Multiply, ln_u);
+                            infix(slope, Op::Multiply, dv)
+                        }),
+                    ),
+                    // log_v(u) = ln(u)/ln(v), so the derivative is
+                    // u'/(u ln(v)) - log_v(u) v'/(v ln(v))
+                    Op::Logbase => {
+                        let ln_v = func(ComponentFn::NaturalLog, v.clone());
+                        sum(
+                            du.map(|du| {
+                                let denom = infix(u.clone(), Op::Multiply, ln_v.clone());
+                                infix(du, Op::Divide, denom)
+                            }),
+                            dv.map(|dv| {
+                                let denom = infix(v.clone(), Op::Multiply, ln_v.clone());
+                                let slope = infix(node.clone(), Op::Divide, denom);
+                                neg(infix(slope, Op::Multiply, dv), dtype)
+                            }),
+                        )
+                    }
+                    // Piecewise constant
+                    Op::GreaterThan | Op::LessThan | Op::EqualTo => None,
+                }
+            }
+            HighNode::ComponentFn(f, u) => {
+                let dtype = self.dtypes.of(u);
+                let du = self.of(u)?;
+                use ComponentInfixOp as Op;
+                let slope = match f {
+                    ComponentFn::Cosine => neg(func(ComponentFn::Sine, u.clone()), dtype),
+                    ComponentFn::Sine => func(ComponentFn::Cosine, u.clone()),
+                    ComponentFn::Tangent => {
+                        let cos = func(ComponentFn::Cosine, u.clone());
+                        let cos2 = infix(cos.clone(), Op::Multiply, cos);
+                        infix(constant(1., dtype), Op::Divide, cos2)
+                    }
+                    ComponentFn::NaturalLog => infix(constant(1., dtype), Op::Divide, u.clone()),
+                    ComponentFn::NaturalExp => node.clone(),
+                    ComponentFn::Ceil | ComponentFn::Floor => return None,
+                    ComponentFn::Abs => {
+                        // The sign of u
+                        let zero = constant(0., dtype);
+                        let positive = infix(u.clone(), Op::GreaterThan, zero.clone());
+                        let negative = infix(u.clone(), Op::LessThan, zero);
+                        infix(positive, Op::Subtract, negative)
+                    }
+                };
+                Some(infix(slope, Op::Multiply, du))
+            }
+            HighNode::GetComponent(vector, index) => {
+                let dvector = self.of(vector)?;
+                Some(Rc::new(HighNode::GetComponent(dvector, index.clone())))
+            }
+            // (a.b)' = a'.b + a.b'
+            HighNode::Dot(a, b) => {
+                let da = self.of(a);
+                let db = self.of(b);
+                sum(
+                    da.map(|da| Rc::new(HighNode::Dot(da, b.clone()))),
+                    db.map(|db| Rc::new(HighNode::Dot(a.clone(), db))),
+                )
+            }
+            HighNode::Normalize(vect, dtype) => self.of(&expand_normalize(vect, *dtype)),
+            HighNode::Splat(scalar, dtype) => {
+                let dscalar = self.of(scalar)?;
+                Some(Rc::new(HighNode::Splat(dscalar, *dtype)))
+            }
+            HighNode::Swizzle {
+                input_vector,
+                component_vector,
+                input_vector_dtype,
+                output_vector_dtype,
+            } => {
+                let dinput = self.of(input_vector)?;
+                Some(Rc::new(HighNode::Swizzle {
+                    input_vector: dinput,
+                    component_vector: component_vector.clone(),
+                    input_vector_dtype: *input_vector_dtype,
+                    output_vector_dtype: *output_vector_dtype,
+                }))
+            }
+            // Higher derivatives
+            HighNode::Gradient { f, wrt } => self.of(&gradient(f, wrt)),
+        }
+    }
+}
+
+fn sum(a: Option<Rc<HighNode>>, b: Option<Rc<HighNode>>) -> Option<Rc<HighNode>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(infix(a, ComponentInfixOp::Add, b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn neg(x: Rc<HighNode>, dtype: DataType) -> Rc<HighNode> {
+    infix(x, ComponentInfixOp::Multiply, constant(-1., dtype))
+}
+
+fn infix(a: Rc<HighNode>, op: ComponentInfixOp, b: Rc<HighNode>) -> Rc<HighNode> {
+    Rc::new(HighNode::ComponentInfixOp(a, op, b))
+}
+
+fn func(f: ComponentFn, x: Rc<HighNode>) -> Rc<HighNode> {
+    Rc::new(HighNode::ComponentFn(f, x))
+}
+
+/// A constant with every component equal to `x`
+fn constant(x: f32, dtype: DataType) -> Rc<HighNode> {
+    constant_lanes(dtype, |_| x)
+}
+
+fn constant_lanes(dtype: DataType, lane: impl Fn(usize) -> f32) -> Rc<HighNode> {
+    let value = match dtype {
+        DataType::Scalar => Value::Scalar(lane(0)),
+        DataType::Vec2 => Value::Vec2(std::array::from_fn(lane)),
+        DataType::Vec3 => Value::Vec3(std::array::from_fn(lane)),
+        DataType::Vec4 => Value::Vec4(std::array::from_fn(lane)),
+    };
+    Rc::new(HighNode::Constant(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::highlevel::convert_graph;
+    use crate::native_backend::evaluate_node;
+    use crate::{ExternInputId, ExternParameters};
+
+    fn input(dtype: DataType) -> Rc<HighNode> {
+        Rc::new(HighNode::ExternInput(ExternInputId::new("x".into()), dtype))
+    }
+
+    fn evaluate(node: &Rc<HighNode>, x: Value) -> Value {
+        let ctx = ExternParameters::new([(ExternInputId::new("x".into()), x)].into());
+        evaluate_node(&convert_graph(node.clone()), &ctx).unwrap()
+    }
+
+    fn component(vector: &Rc<HighNode>, index: f32) -> Rc<HighNode> {
+        Rc::new(HighNode::GetComponent(
+            vector.clone(),
+            constant(index, DataType::Scalar),
+        ))
+    }
+
+    /// Check the gradient of the scalar `f` with respect to the input `x` against central
+    /// differences
+    fn assert_matches_finite_differences(f: &Rc<HighNode>, x: Value) {
+        let dtype = x.dtype();
+        let grad: Vec<f32> = evaluate(&gradient(f, &input(dtype)), x)
+            .iter_vector_floats()
+            .collect();
+        assert_eq!(grad.len(), dtype.n_lanes());
+
+        let h = 1e-2;
+        let lanes: Vec<f32> = x.iter_vector_floats().collect();
+        let f_at = |lane: usize, delta: f32| {
+            let moved = constant_lanes(dtype, |idx| {
+                lanes[idx] + if idx == lane { delta } else { 0. }
+            });
+            let HighNode::Constant(moved) = *moved else {
+                unreachable!()
+            };
+            match evaluate(f, moved) {
+                Value::Scalar(y) => y,
+                other => panic!("{:?} is not a scalar", other),
+            }
+        };
+
+        for (lane, grad) in grad.into_iter().enumerate() {
+            let expected = (f_at(lane, h) - f_at(lane, -h)) / (2. * h);
+            assert!(
+                (grad - expected).abs() <= 1e-2 * (1. + expected.abs()),
+                "{:?} at {:?}, lane {}: {} != {}",
+                f,
+                x,
+                lane,
+                grad,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn component_fns_match_finite_differences() {
+        let x = input(DataType::Scalar);
+        for func in ComponentFn::all() {
+            let f = Rc::new(HighNode::ComponentFn(func, x.clone()));
+            for x in [0.3, 0.9, 2.6] {
+                assert_matches_finite_differences(&f, Value::Scalar(x));
+            }
+        }
+    }
+
+    #[test]
+    fn infix_ops_match_finite_differences() {
+        let x = input(DataType::Vec2);
+        for op in ComponentInfixOp::all() {
+            let f = infix(component(&x, 0.), op, component(&x, 1.));
+            for x in [[1.3, 0.7], [0.4, 2.2], [2.5, 1.5]] {
+                assert_matches_finite_differences(&f, Value::Vec2(x));
+            }
+        }
+    }
+
+    #[test]
+    fn vector_nodes_match_finite_differences() {
+        let x = input(DataType::Vec3);
+        let normalized = Rc::new(HighNode::Normalize(x.clone(), DataType::Vec3));
+        let weights = constant_lanes(DataType::Vec3, |idx| [2., -1., 0.5][idx]);
+        let f = Rc::new(HighNode::Dot(normalized, weights));
+        assert_matches_finite_differences(&f, Value::Vec3([0.5, -1.5, 2.]));
+
+        // Every component of x feeds into both operands
+        let splat = Rc::new(HighNode::Splat(component(&x, 2.), DataType::Vec3));
+        let product = infix(x.clone(), ComponentInfixOp::Multiply, splat);
+        let f = Rc::new(HighNode::Dot(product, x.clone()));
+        assert_matches_finite_differences(&f, Value::Vec3([0.5, -1.5, 2.]));
+    }
+
+    #[test]
+    fn second_derivative_matches_finite_differences() {
+        // The derivative of x sin(x) is sin(x) + x cos(x)
+        let x = input(DataType::Scalar);
+        let f = infix(
+            x.clone(),
+            ComponentInfixOp::Multiply,
+            func(ComponentFn::Sine, x.clone()),
+        );
+        let df = Rc::new(HighNode::Gradient { f, wrt: x });
+        for x in [-1.2, 0.4, 3.] {
+            assert_matches_finite_differences(&df, Value::Scalar(x));
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use std::ops::Index;
+
+use crate::*;
+
+/// Index of a node within a `Graph`
+#[cfg_attr(feature = "persistence", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+/// The same operations as `Node`, but referring to other nodes by their index in a `Graph`
+#[cfg_attr(feature = "persistence", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug, PartialEq, Hash)]
+pub enum NodeKind {
+    ExternInput(ExternInputId, DataType),
+    Constant(Value),
+    Make(Vec<NodeId>, DataType),
+    ComponentInfixOp(NodeId, ComponentInfixOp, NodeId),
+    ComponentFn(ComponentFn, NodeId),
+    GetComponent(NodeId, NodeId),
+    Dot(NodeId, NodeId),
+}
+
+/// A node graph stored as a flat list. Nodes may only refer to nodes which come before them, so
+/// the list is always in topological order. `root` is the output of the graph.
+#[cfg_attr(feature = "persistence", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "persistence", serde(try_from = "UncheckedGraph"))]
+#[derive(Clone, Debug, PartialEq, Hash)]
+pub struct Graph {
+    nodes: Vec<NodeKind>,
+    root: NodeId,
+}
+
+/// The fields of a `Graph` as they were saved, before checking that they form a valid graph
+#[cfg(feature = "persistence")]
+#[derive(serde::Deserialize)]
+struct UncheckedGraph {
+    nodes: Vec<NodeKind>,
+    root: NodeId,
+}
+
+/// Why a list of nodes is not a valid `Graph`
+#[derive(Clone, Debug, PartialEq)]
+pub enum InvalidGraph {
+    /// A node refers to itself or to a node after it
+    ForwardReference {
+        node: NodeId,
+        input: NodeId,
+    },
+    RootOutOfRange(NodeId),
+}
+
+/// Builds a `Graph` one node at a time
+#[derive(Default)]
+pub struct GraphBuilder {
+    nodes: Vec<NodeKind>,
+}
+
+impl NodeId {
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+impl NodeKind {
+    /// Nodes this node takes as inputs, in order
+    pub fn inputs(&self) -> Vec<NodeId> {
+        match self {
+            Self::ExternInput(_, _) | Self::Constant(_) => vec![],
+            Self::Make(components, _) => components.clone(),
+            Self::ComponentInfixOp(a, _, b) | Self::GetComponent(a, b) | Self::Dot(a, b) => {
+                vec![*a, *b]
+            }
+            Self::ComponentFn(_, a) => vec![*a],
+        }
+    }
+}
+
+impl GraphBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a node, whose inputs must already have been added
+    #[track_caller]
+    pub fn push(&mut self, node: NodeKind) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        for input in node.inputs() {
+            assert!(input < id, "Node {:?} refers to later node {:?}", id, input);
+        }
+        self.nodes.push(node);
+        id
+    }
+
+    /// Finish the graph with the given output node
+    #[track_caller]
+    pub fn finish(self, root: NodeId) -> Graph {
+        assert!(
+            root.0 < self.nodes.len(),
+            "Root {:?} is not in the graph",
+            root
+        );
+        Graph {
+            nodes: self.nodes,
+            root,
+        }
+    }
+}
+
+impl Graph {
+    /// Convert a tree of nodes, preserving the identity of shared nodes
+    pub fn from_node(node: &Rc<Node>) -> Self {
+        let mut builder = GraphBuilder::new();
+        let root = push_node_recursive(&mut builder, node, &mut HashMap::new());
+        builder.finish(root)
+    }
+
+    /// Check the same rules as `GraphBuilder`, for nodes which did not come from one
+    pub fn from_parts(nodes: Vec<NodeKind>, root: NodeId) -> Result<Self, InvalidGraph> {
+        for (idx, node) in nodes.iter().enumerate() {
+            let id = NodeId(idx);
+            if let Some(input) = node.inputs().into_iter().find(|input| *input >= id) {
+                return Err(InvalidGraph::ForwardReference { node: id, input });
+            }
+        }
+        if root.0 >= nodes.len() {
+            return Err(InvalidGraph::RootOutOfRange(root));
+        }
+
+        Ok(Self { nodes, root })
+    }
+
+    pub fn root(&self) -> NodeId {
+        self.root
+    }
+
+    pub fn nodes(&self) -> &[NodeKind] {
+        &self.nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Iterate over every node in topological order
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, &NodeKind)> + '_ {
+        self.nodes
+            .iter()
+            .enumerate()
+            .map(|(idx, node)| (NodeId(idx), node))
+    }
+}
+
+#[cfg(feature = "persistence")]
+impl TryFrom<UncheckedGraph> for Graph {
+    type Error = InvalidGraph;
+
+    fn try_from(unchecked: UncheckedGraph) -> Result<Self, Self::Error> {
+        Self::from_parts(unchecked.nodes, unchecked.root)
+    }
+}
+
+impl std::error::Error for InvalidGraph {}
+
+impl std::fmt::Display for InvalidGraph {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ForwardReference { node, input } => {
+                write!(f, "Node {} refers to later node {}", node, input)
+            }
+            Self::RootOutOfRange(root) => write!(f, "Root {} is not in the graph", root),
+        }
+    }
+}
+
+impl Index<NodeId> for Graph {
+    type Output = NodeKind;
+    fn index(&self, id: NodeId) -> &Self::Output {
+        &self.nodes[id.0]
+    }
+}
+
+impl std::fmt::Display for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+fn push_node_recursive(
+    builder: &mut GraphBuilder,
+    node: &Rc<Node>,
+    cache: &mut HashMap<HashRcByPtr<Node>, NodeId>,
+) -> NodeId {
+    if let Some(id) = cache.get(&HashRcByPtr(node.clone())) {
+        return *id;
+    }
+
+    let mut push = |node: &Rc<Node>| push_node_recursive(builder, node, cache);
+
+    let kind = match &**node {
+        Node::ExternInput(id, dtype) => NodeKind::ExternInput(id.clone(), *dtype),
+        Node::Constant(value) => NodeKind::Constant(*value),
+        Node::Make(components, dtype) => {
+            NodeKind::Make(components.iter().map(&mut push).collect(), *dtype)
+        }
+        Node::ComponentInfixOp(a, op, b) => NodeKind::ComponentInfixOp(push(a), *op, push(b)),
+        Node::ComponentFn(func, a) => NodeKind::ComponentFn(*func, push(a)),
+        Node::GetComponent(a, b) => NodeKind::GetComponent(push(a), push(b)),
+        Node::Dot(a, b) => NodeKind::Dot(push(a), push(b)),
+    };
+
+    let id = builder.push(kind);
+    cache.insert(HashRcByPtr(node.clone()), id);
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_parts_checks_order_and_root() {
+        let constant = NodeKind::Constant(Value::Scalar(1.));
+        let add = |a, b| NodeKind::ComponentInfixOp(NodeId(a), ComponentInfixOp::Add, NodeId(b));
+
+        let graph = Graph::from_parts(vec![constant.clone(), add(0, 0)], NodeId(1)).unwrap();
+        assert_eq!(graph.len(), 2);
+
+        assert_eq!(
+            Graph::from_parts(vec![constant.clone(), add(0, 1)], NodeId(1)),
+            Err(InvalidGraph::ForwardReference {
+                node: NodeId(1),
+                input: NodeId(1)
+            })
+        );
+        assert_eq!(
+            Graph::from_parts(vec![constant], NodeId(1)),
+            Err(InvalidGraph::RootOutOfRange(NodeId(1)))
+        );
+        assert_eq!(
+            Graph::from_parts(vec![], NodeId(0)),
+            Err(InvalidGraph::RootOutOfRange(NodeId(0)))
+        );
+    }
+}
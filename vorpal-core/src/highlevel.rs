@@ -1,9 +1,11 @@
 use std::{collections::HashMap, rc::Rc};
 
-use crate::{ComponentFn, ComponentInfixOp, DataType, ExternInputId, HashRcByPtr, Node, Value};
+use crate::autodiff;
+use crate::graph::{Graph, GraphBuilder, NodeId, NodeKind};
+use crate::{ComponentFn, ComponentInfixOp, DataType, ExternInputId, HashRcByPtr, Value};
 
 /// A higher-level, nicer set of nodes. Compiles to the lower-level set ...
-/// HighNode is a strict superset of Node. Is always directly convertible to Node.
+/// HighNode is a strict superset of Node. Is always directly convertible to a Graph.
 #[derive(Clone, Debug)]
 pub enum HighNode {
     // Components of Node
@@ -29,100 +31,139 @@ pub enum HighNode {
         /// Matches component vector dtype
         output_vector_dtype: DataType,
     },
+    /// Derivative of the scalar `f` with respect to each component of `wrt`, which is either an
+    /// input (matched by name) or any other node (matched by identity). Has the datatype of `wrt`.
+    Gradient {
+        f: Rc<HighNode>,
+        wrt: Rc<HighNode>,
+    },
 }
 
-/// This preserves the identity of each individual node (so its tree will not be copied!)
-type Cache = HashMap<HashRcByPtr<HighNode>, Rc<Node>>;
+/// Lower a tree of high-level nodes into a graph
+pub fn convert_graph(high: Rc<HighNode>) -> Graph {
+    let (graph, _) = convert_graph_with_origins(high, &|_| None::<()>);
+    graph
+}
 
-pub fn convert_node(high: Rc<HighNode>) -> Rc<Node> {
-    convert_rc_highnode(high, &mut HashMap::new())
+/// Lower a tree of high-level nodes into a graph, and find where each node of the graph came
+/// from. `origin_of` is asked about every high-level node; graph nodes produced while lowering a
+/// node without an origin inherit the origin of the nearest high-level node above it.
+pub fn convert_graph_with_origins<T: Clone>(
+    high: Rc<HighNode>,
+    origin_of: &dyn Fn(&Rc<HighNode>) -> Option<T>,
+) -> (Graph, Vec<Option<T>>) {
+    let mut lowering = Lowering {
+        builder: GraphBuilder::new(),
+        cache: HashMap::new(),
+        origin_of,
+        current_origin: None,
+        origins: vec![],
+    };
+    let root = lowering.convert(high);
+    (lowering.builder.finish(root), lowering.origins)
 }
 
-fn convert_rc_highnode(high: Rc<HighNode>, cache: &mut Cache) -> Rc<Node> {
-    if let Some(cached) = cache.get(&HashRcByPtr(high.clone())) {
-        // Use the exact same pointer, so that the wasm assembler code using HashRcByPtr later down the line
-        // can just assume that the Rc<> has a refcount equal to the number of times it's used in
-        // the code instead of re-computing everything each time ...
-        //
-        // ... I should just use slotmap instead lol
-        cached.clone()
-    } else {
-        lower_node_recursive(high, cache)
-    }
+struct Lowering<'a, T> {
+    builder: GraphBuilder,
+    /// This preserves the identity of each individual node (so its tree will not be copied!)
+    cache: HashMap<HashRcByPtr<HighNode>, NodeId>,
+    origin_of: &'a dyn Fn(&Rc<HighNode>) -> Option<T>,
+    /// Origin of the high-level node currently being lowered
+    current_origin: Option<T>,
+    /// Origin of each node pushed to the graph so far
+    origins: Vec<Option<T>>,
 }
 
-fn lower_node_recursive(high: Rc<HighNode>, cache: &mut Cache) -> Rc<Node> {
-    match Rc::unwrap_or_clone(high) {
-        HighNode::ExternInput(id, dtype) => Rc::new(Node::ExternInput(id, dtype)),
-        HighNode::Constant(value) => Rc::new(Node::Constant(value)),
-        HighNode::Make(components, dtype) => Rc::new(Node::Make(
-            components
-                .into_iter()
-                .map(|c| convert_rc_highnode(c, cache))
-                .collect(),
-            dtype,
-        )),
-        HighNode::ComponentInfixOp(left, op, right) => Rc::new(Node::ComponentInfixOp(
-            convert_rc_highnode(left, cache),
-            op,
-            convert_rc_highnode(right, cache),
-        )),
-        HighNode::ComponentFn(op, data) => {
-            Rc::new(Node::ComponentFn(op, convert_rc_highnode(data, cache)))
-        }
-        HighNode::GetComponent(left, right) => Rc::new(Node::GetComponent(
-            convert_rc_highnode(left, cache),
-            convert_rc_highnode(right, cache),
-        )),
-        HighNode::Dot(left, right) => Rc::new(Node::Dot(
-            convert_rc_highnode(left, cache),
-            convert_rc_highnode(right, cache),
-        )),
-        // Now here's the more useful stuff
-        HighNode::Splat(scalar, dtype) => {
-            let scalar = convert_rc_highnode(scalar, cache);
-            let copies = (0..dtype.n_lanes()).map(|_| scalar.clone()).collect();
-            Rc::new(Node::Make(copies, dtype))
-        }
-        HighNode::Normalize(vect, dtype) => {
-            let len2 = Rc::new(HighNode::Dot(vect.clone(), vect.clone()));
+impl<T: Clone> Lowering<'_, T> {
+    fn convert(&mut self, high: Rc<HighNode>) -> NodeId {
+        let key = HashRcByPtr(high.clone());
+        if let Some(cached) = self.cache.get(&key) {
+            // Use the exact same node, so that shared subexpressions are only computed once
+            *cached
+        } else {
+            let origin = (self.origin_of)(&high).or_else(|| self.current_origin.clone());
+            let parent_origin = std::mem::replace(&mut self.current_origin, origin);
+            let id = self.lower_node_recursive(high);
+            self.current_origin = parent_origin;
 
-            let half = Rc::new(HighNode::Constant(Value::Scalar(0.5)));
-            let len = Rc::new(HighNode::ComponentInfixOp(
-                len2,
-                ComponentInfixOp::Power,
-                half,
-            ));
-            let len = Rc::new(HighNode::Splat(len, dtype));
+            self.cache.insert(key, id);
+            id
+        }
+    }
 
-            let normed = Rc::new(HighNode::ComponentInfixOp(
-                vect.clone(),
-                ComponentInfixOp::Divide,
-                len,
-            ));
+    fn push(&mut self, kind: NodeKind) -> NodeId {
+        self.origins.push(self.current_origin.clone());
+        self.builder.push(kind)
+    }
 
-            convert_rc_highnode(normed, cache)
-        }
-        HighNode::Swizzle {
-            input_vector,
-            component_vector,
-            input_vector_dtype: _,
-            output_vector_dtype,
-        } => convert_rc_highnode(
-            Rc::new(HighNode::Make(
-                (0..output_vector_dtype.n_lanes()).map(|lane_idx| {
-                    Rc::new(HighNode::GetComponent(
-                        input_vector.clone(),
-                        Rc::new(HighNode::GetComponent(
-                            component_vector.clone(),
-                            Rc::new(HighNode::Constant(Value::Scalar(lane_idx as f32))),
-                        )),
-                    ))
-                })
-                .collect(),
+    fn lower_node_recursive(&mut self, high: Rc<HighNode>) -> NodeId {
+        let kind = match Rc::unwrap_or_clone(high) {
+            HighNode::ExternInput(id, dtype) => NodeKind::ExternInput(id, dtype),
+            HighNode::Constant(value) => NodeKind::Constant(value),
+            HighNode::Make(components, dtype) => NodeKind::Make(
+                components.into_iter().map(|c| self.convert(c)).collect(),
+                dtype,
+            ),
+            HighNode::ComponentInfixOp(left, op, right) => {
+                NodeKind::ComponentInfixOp(self.convert(left), op, self.convert(right))
+            }
+            HighNode::ComponentFn(op, data) => NodeKind::ComponentFn(op, self.convert(data)),
+            HighNode::GetComponent(left, right) => {
+                NodeKind::GetComponent(self.convert(left), self.convert(right))
+            }
+            HighNode::Dot(left, right) => NodeKind::Dot(self.convert(left), self.convert(right)),
+            // Now here's the more useful stuff
+            HighNode::Splat(scalar, dtype) => {
+                let scalar = self.convert(scalar);
+                let copies = (0..dtype.n_lanes()).map(|_| scalar).collect();
+                NodeKind::Make(copies, dtype)
+            }
+            HighNode::Normalize(vect, dtype) => {
+                return self.convert(expand_normalize(&vect, dtype))
+            }
+            HighNode::Swizzle {
+                input_vector,
+                component_vector,
+                input_vector_dtype: _,
                 output_vector_dtype,
-            )),
-            cache,
-        ),
+            } => {
+                return self.convert(Rc::new(HighNode::Make(
+                    (0..output_vector_dtype.n_lanes())
+                        .map(|lane_idx| {
+                            Rc::new(HighNode::GetComponent(
+                                input_vector.clone(),
+                                Rc::new(HighNode::GetComponent(
+                                    component_vector.clone(),
+                                    Rc::new(HighNode::Constant(Value::Scalar(lane_idx as f32))),
+                                )),
+                            ))
+                        })
+                        .collect(),
+                    output_vector_dtype,
+                )))
+            }
+            HighNode::Gradient { f, wrt } => return self.convert(autodiff::gradient(&f, &wrt)),
+        };
+
+        self.push(kind)
     }
 }
+
+/// Divide a vector by its length
+pub(crate) fn expand_normalize(vect: &Rc<HighNode>, dtype: DataType) -> Rc<HighNode> {
+    let len2 = Rc::new(HighNode::Dot(vect.clone(), vect.clone()));
+
+    let half = Rc::new(HighNode::Constant(Value::Scalar(0.5)));
+    let len = Rc::new(HighNode::ComponentInfixOp(
+        len2,
+        ComponentInfixOp::Power,
+        half,
+    ));
+    let len = Rc::new(HighNode::Splat(len, dtype));
+
+    Rc::new(HighNode::ComponentInfixOp(
+        vect.clone(),
+        ComponentInfixOp::Divide,
+        len,
+    ))
+}
@@ -3,9 +3,15 @@ use std::hash::{Hash, Hasher};
 
 use ndarray::NdArray;
 
+pub mod autodiff;
+pub mod graph;
 pub mod native_backend;
+pub mod range_analysis;
 pub mod ndarray;
 pub mod highlevel;
+#[cfg(feature = "persistence")]
+pub mod project;
+pub mod typecheck;
 
 pub type Scalar = f32;
 pub type Vec2 = [f32; 2];
@@ -13,7 +19,7 @@ pub type Vec3 = [f32; 3];
 pub type Vec4 = [f32; 4];
 
 #[cfg_attr(feature = "persistence", derive(serde::Serialize, serde::Deserialize))]
-#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Hash)]
 pub enum DataType {
     Scalar,
     Vec2,
@@ -32,7 +38,7 @@ pub enum Value {
 
 /// Componentwise infix operation
 #[cfg_attr(feature = "persistence", derive(serde::Serialize, serde::Deserialize))]
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum ComponentInfixOp {
     Add,
     Subtract,
@@ -47,7 +53,7 @@ pub enum ComponentInfixOp {
 
 /// Function on components
 #[cfg_attr(feature = "persistence", derive(serde::Serialize, serde::Deserialize))]
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum ComponentFn {
     Cosine,
     Sine,
@@ -59,15 +65,64 @@ pub enum ComponentFn {
     Abs,
 }
 
+/// Floating point type which graphs are compiled to and evaluated in. Constants and `Value`s
+/// are always stored as `f32`, and every backend widens them exactly, so in double precision
+/// 0.1 becomes 0.100000001490116...; only the arithmetic, and batched inputs, are `f64`.
+#[cfg_attr(feature = "persistence", derive(serde::Serialize, serde::Deserialize))]
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default, Hash)]
+pub enum Precision {
+    /// `f32`
+    #[default]
+    Single,
+    /// `f64`
+    Double,
+}
+
+/// Floating point types which graphs may be evaluated in natively
+pub trait Float:
+    Copy
+    + Default
+    + PartialOrd
+    + std::fmt::Debug
+    + std::ops::Add<Output = Self>
+    + std::ops::Sub<Output = Self>
+    + std::ops::Mul<Output = Self>
+    + std::ops::Div<Output = Self>
+    + std::ops::AddAssign
+{
+    const PRECISION: Precision;
+
+    fn from_f32(x: f32) -> Self;
+    fn from_f64(x: f64) -> Self;
+    fn to_f64(self) -> f64;
+    fn from_bool(b: bool) -> Self;
+
+    fn cos(self) -> Self;
+    fn sin(self) -> Self;
+    fn tan(self) -> Self;
+    fn ln(self) -> Self;
+    fn exp(self) -> Self;
+    fn ceil(self) -> Self;
+    fn floor(self) -> Self;
+    fn abs(self) -> Self;
+    fn powf(self, exponent: Self) -> Self;
+    fn log(self, base: Self) -> Self;
+    fn clamp(self, min: Self, max: Self) -> Self;
+}
+
 #[derive(Clone, Debug)]
 pub enum EvalError {
     TypeMismatch,
     BadInputId(ExternInputId),
+    /// Batched inputs did not share the same shape
+    ShapeMismatch,
+    /// The graph failed to typecheck
+    TypeCheck(Vec<typecheck::Diagnostic>),
 }
 
 /// Names and corresponding datatype for each parameter
 #[cfg_attr(feature = "persistence", derive(serde::Serialize, serde::Deserialize))]
-#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
 pub struct ParameterList(pub Vec<(ExternInputId, DataType)>);
 
 /// Unique name of external value input
@@ -158,6 +213,16 @@ impl std::fmt::Display for ComponentFn {
     }
 }
 
+/// Hashes the exact bits of each component. Note that `0.0` and `-0.0` hash differently.
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.dtype().hash(state);
+        for float in self.iter_vector_floats() {
+            float.to_bits().hash(state);
+        }
+    }
+}
+
 impl Value {
     pub fn default_of_dtype(dtype: DataType) -> Self {
         match dtype {
@@ -193,6 +258,77 @@ macro_rules! impl_value_try_into {
     };
 }
 
+macro_rules! impl_float {
+    ($float:ident, $precision:ident) => {
+        impl Float for $float {
+            const PRECISION: Precision = Precision::$precision;
+
+            fn from_f32(x: f32) -> Self {
+                x as $float
+            }
+
+            fn from_f64(x: f64) -> Self {
+                x as $float
+            }
+
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+
+            fn from_bool(b: bool) -> Self {
+                $float::from(u8::from(b))
+            }
+
+            fn cos(self) -> Self {
+                $float::cos(self)
+            }
+
+            fn sin(self) -> Self {
+                $float::sin(self)
+            }
+
+            fn tan(self) -> Self {
+                $float::tan(self)
+            }
+
+            fn ln(self) -> Self {
+                $float::ln(self)
+            }
+
+            fn exp(self) -> Self {
+                $float::exp(self)
+            }
+
+            fn ceil(self) -> Self {
+                $float::ceil(self)
+            }
+
+            fn floor(self) -> Self {
+                $float::floor(self)
+            }
+
+            fn abs(self) -> Self {
+                $float::abs(self)
+            }
+
+            fn powf(self, exponent: Self) -> Self {
+                $float::powf(self, exponent)
+            }
+
+            fn log(self, base: Self) -> Self {
+                $float::log(self, base)
+            }
+
+            fn clamp(self, min: Self, max: Self) -> Self {
+                $float::clamp(self, min, max)
+            }
+        }
+    };
+}
+
+impl_float!(f32, Single);
+impl_float!(f64, Double);
+
 impl_value_try_into!(f32, Scalar);
 impl_value_try_into!(Vec2, Vec2);
 impl_value_try_into!(Vec3, Vec3);
@@ -212,7 +348,7 @@ impl ComponentFn {
         ]
     }
 
-    pub fn native(&self, x: f32) -> f32 {
+    pub fn native<F: Float>(&self, x: F) -> F {
         match self {
             Self::Cosine => x.cos(),
             Self::Sine => x.sin(),
@@ -254,7 +390,7 @@ impl ComponentInfixOp {
         ]
     }
 
-    pub fn native(&self, a: f32, b: f32) -> f32 {
+    pub fn native<F: Float>(&self, a: F, b: F) -> F {
         match self {
             Self::Add => a + b,
             Self::Subtract => a - b,
@@ -262,9 +398,9 @@ impl ComponentInfixOp {
             Self::Divide => a / b,
             Self::Power => a.powf(b),
             Self::Logbase => a.log(b),
-            Self::GreaterThan => f32::from(a > b),
-            Self::LessThan => f32::from(a < b),
-            Self::EqualTo => f32::from(a == b),
+            Self::GreaterThan => F::from_bool(a > b),
+            Self::LessThan => F::from_bool(a < b),
+            Self::EqualTo => F::from_bool(a == b),
         }
     }
 
@@ -283,6 +419,38 @@ impl ComponentInfixOp {
     }
 }
 
+impl Precision {
+    pub fn all() -> [Self; 2] {
+        [Self::Single, Self::Double]
+    }
+
+    /// Name of the float type, which is the same in Rust and WebAssembly
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Single => "f32",
+            Self::Double => "f64",
+        }
+    }
+
+    /// Size of the float type in bytes
+    pub fn size_of(&self) -> usize {
+        match self {
+            Self::Single => 4,
+            Self::Double => 8,
+        }
+    }
+}
+
+impl std::fmt::Display for Precision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Single => "single (f32)",
+            Self::Double => "double (f64)",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 impl DataType {
     pub fn all() -> [Self; 4] {
         [Self::Scalar, Self::Vec2, Self::Vec3, Self::Vec4]
@@ -335,6 +503,20 @@ impl ExternInputId {
     pub fn new(name: String) -> Self {
         Self(name)
     }
+
+    /// Lowercase name with whitespace replaced by underscores and other punctuation removed,
+    /// for use as a parameter name in generated code
+    pub fn identifier(&self) -> String {
+        self.0
+            .to_lowercase()
+            .chars()
+            .filter_map(|c| match c {
+                c if c.is_alphanumeric() => Some(c),
+                c if c.is_whitespace() => Some('_'),
+                _ => None,
+            })
+            .collect()
+    }
 }
 
 impl ExternSamplerId {
@@ -375,6 +557,14 @@ impl std::fmt::Display for EvalError {
         match self {
             EvalError::TypeMismatch => write!(f, "Type mismatch"),
             EvalError::BadInputId(id) => write!(f, "Bad input id: {:?}", id),
+            EvalError::ShapeMismatch => write!(f, "Shape mismatch"),
+            EvalError::TypeCheck(diagnostics) => {
+                write!(f, "Type check failed")?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n{}", diagnostic)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -399,9 +589,76 @@ impl ParameterList {
     pub fn inputs(&self) -> &[(ExternInputId, DataType)] {
         &self.0
     }
+
+    /// A distinct identifier for each input, in order, for use as parameter names in generated
+    /// code. Names which would clash with an earlier input, or with one of its lanes when they
+    /// are passed separately as `{name}_x` and so on, get a numeric suffix.
+    pub fn identifiers(&self) -> Vec<String> {
+        let mut taken = std::collections::HashSet::new();
+        self.inputs()
+            .iter()
+            .map(|(id, dtype)| {
+                let base = sanitize_identifier(&id.identifier());
+                let names = |name: &str| {
+                    let mut names = vec![name.to_string()];
+                    if dtype.n_lanes() > 1 {
+                        names.extend(dtype.lane_names().map(|lane| format!("{name}_{lane}")));
+                    }
+                    names
+                };
+
+                let name = std::iter::once(base.clone())
+                    .chain((2..).map(|n| format!("{base}_{n}")))
+                    .find(|name| names(name).iter().all(|name| !taken.contains(name)))
+                    .unwrap();
+                taken.extend(names(&name));
+                name
+            })
+            .collect()
+    }
+}
+
+const RUST_KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+    "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in",
+    "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref",
+    "return", "self", "static", "struct", "super", "trait", "true", "try", "type", "typeof",
+    "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+const C_KEYWORDS: &[&str] = &[
+    "auto", "bool", "break", "case", "char", "const", "continue", "default", "do", "double",
+    "else", "enum", "extern", "false", "float", "for", "goto", "if", "inline", "int", "long",
+    "register", "restrict", "return", "short", "signed", "sizeof", "static", "struct", "switch",
+    "true", "typedef", "union", "unsigned", "void", "volatile", "while",
+];
+
+/// Functions called by the generated C, and locals of the generated bindings
+const GENERATED_NAMES: &[&str] = &[
+    "cosf", "sinf", "tanf", "logf", "expf", "ceilf", "floorf", "fabsf", "powf", "fminf", "fmaxf",
+    "out", "out_ptr",
+];
+
+/// `name` reduced to ASCII letters, digits and underscores, and prefixed with `v_` if it then
+/// doesn't start with a letter, or is a keyword or a name the generated code uses. The result
+/// is a valid identifier in both Rust and C.
+pub fn sanitize_identifier(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+        .collect();
+    let reserved = [RUST_KEYWORDS, C_KEYWORDS, GENERATED_NAMES]
+        .iter()
+        .any(|names| names.contains(&name.as_str()))
+        || name.starts_with("node_")
+        || name.starts_with("vorpal_");
+    if reserved || !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        format!("v_{name}")
+    } else {
+        name
+    }
 }
 
-/*
 impl ExternParameters {
     pub fn build_parameter_list(&self) -> ParameterList {
         ParameterList(
@@ -412,7 +669,6 @@ impl ExternParameters {
         )
     }
 }
-*/
 
 /// Instead of hashing by the _contents_ of an Rc smart pointer,
 /// we are hashing by its pointer. This makes it such that we can store
@@ -433,3 +689,35 @@ impl<T> PartialEq for HashRcByPtr<T> {
         Rc::as_ptr(&self.0).eq(&Rc::as_ptr(&other.0))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identifiers_are_valid_and_distinct() {
+        assert_eq!(sanitize_identifier("type"), "v_type");
+        assert_eq!(sanitize_identifier("1x"), "v_1x");
+        assert_eq!(sanitize_identifier(""), "v_");
+        assert_eq!(sanitize_identifier("node_3"), "v_node_3");
+        assert_eq!(sanitize_identifier("héllo-world"), "hlloworld");
+
+        let params = ParameterList(
+            [
+                ("a b", DataType::Scalar),
+                ("a_b", DataType::Scalar),
+                ("A B", DataType::Scalar),
+                ("c", DataType::Vec2),
+                ("c x", DataType::Scalar),
+                ("fn", DataType::Scalar),
+            ]
+            .into_iter()
+            .map(|(name, dtype)| (ExternInputId::new(name.into()), dtype))
+            .collect(),
+        );
+        assert_eq!(
+            params.identifiers(),
+            ["a_b", "ab", "a_b_2", "c", "c_x_2", "v_fn"]
+        );
+    }
+}
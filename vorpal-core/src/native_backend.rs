@@ -1,93 +1,174 @@
+use crate::graph::{Graph, NodeKind};
+use crate::typecheck;
 use crate::*;
 
-pub fn evaluate_node(node: &Node, ctx: &ExternParameters) -> Result<Value, EvalError> {
-    fn comp_infix<const N: usize>(
-        mut a: [f32; N],
-        infix: ComponentInfixOp,
-        b: [f32; N],
-    ) -> [f32; N] {
-        a.iter_mut()
-            .zip(&b)
-            .for_each(|(a, b)| *a = infix.native(*a, *b));
-        a
+/// Evaluate a node graph once. Shared subexpressions are only evaluated once.
+pub fn evaluate_node(graph: &Graph, ctx: &ExternParameters) -> Result<Value, EvalError> {
+    Program::new(graph, &ctx.build_parameter_list())?.evaluate(ctx)
+}
+
+/// A node graph annotated with the datatype of each node, ready to be evaluated in topological
+/// order. Each node writes its output to the slot with the same index.
+#[derive(Clone, Debug)]
+pub struct Program {
+    graph: Graph,
+    dtypes: Vec<DataType>,
+}
+
+impl Program {
+    /// Inputs not found in the parameter list are rejected by the type checker
+    pub fn new(graph: &Graph, params: &ParameterList) -> Result<Self, EvalError> {
+        let dtypes = typecheck::infer_dtypes(graph, params).map_err(EvalError::TypeCheck)?;
+        Ok(Self {
+            graph: graph.clone(),
+            dtypes,
+        })
     }
 
-    fn comp_func<const N: usize>(mut x: [f32; N], func: ComponentFn) -> [f32; N] {
-        x.iter_mut().for_each(|x| *x = func.native(*x));
-        x
+    pub fn graph(&self) -> &Graph {
+        &self.graph
     }
 
-    fn dot(a: &[f32], b: &[f32]) -> f32 {
-        a.iter().zip(b).map(|(a, b)| a * b).sum()
+    /// Output datatype of the root node
+    pub fn output_dtype(&self) -> DataType {
+        self.dtypes[self.graph.root().index()]
     }
 
-    match node {
-        Node::Make(nodes, dtype) => {
-            let mut val = Value::default_of_dtype(*dtype);
-            let fill = |arr: &mut [f32]| {
-                for (node, out) in nodes.iter().zip(arr) {
-                    let part = evaluate_node(node, ctx)?;
-                    *out = part.try_into()?;
-                }
-                Ok(())
-            };
-            match &mut val {
-                Value::Scalar(scalar) => {
-                    let mut arr = [*scalar];
-                    fill(&mut arr)?;
-                    *scalar = arr[0];
-                }
-                Value::Vec2(arr) => fill(arr)?,
-                Value::Vec3(arr) => fill(arr)?,
-                Value::Vec4(arr) => fill(arr)?,
+    /// Evaluate the program for a single set of inputs
+    pub fn evaluate(&self, ctx: &ExternParameters) -> Result<Value, EvalError> {
+        let out = self.evaluate_batch(ctx, &HashMap::new())?;
+        let out = out.data();
+        Ok(match self.output_dtype() {
+            DataType::Scalar => Value::Scalar(out[0]),
+            DataType::Vec2 => Value::Vec2([out[0], out[1]]),
+            DataType::Vec3 => Value::Vec3([out[0], out[1], out[2]]),
+            DataType::Vec4 => Value::Vec4([out[0], out[1], out[2], out[3]]),
+        })
+    }
+
+    /// Evaluate the program over a whole batch at once, in the precision of `F`. Each array in
+    /// `varying` has the shape `[batch dims..., n_lanes]`, and all of them must share the same
+    /// batch dimensions. Arrays of shape `[n_lanes]`, and inputs not found in `varying` which are
+    /// taken from `uniforms` instead, are the same for every element.
+    ///
+    /// Returns an array of shape `[batch dims..., output lanes]`
+    pub fn evaluate_batch<F: Float>(
+        &self,
+        uniforms: &ExternParameters,
+        varying: &HashMap<ExternInputId, NdArray<F>>,
+    ) -> Result<NdArray<F>, EvalError> {
+        let mut batch_dims: Option<&[usize]> = None;
+        for array in varying.values().filter(|array| array.shape().len() != 1) {
+            let (_, dims) = array.shape().split_last().ok_or(EvalError::ShapeMismatch)?;
+            if batch_dims.is_some_and(|batch_dims| batch_dims != dims) {
+                return Err(EvalError::ShapeMismatch);
             }
-            Ok(val)
+            batch_dims = Some(dims);
         }
-        Node::Constant(value) => Ok(value.clone()),
-        Node::ComponentInfixOp(a, op, b) => {
-            match (evaluate_node(a, ctx)?, evaluate_node(b, ctx)?) {
-                (Value::Scalar(a), Value::Scalar(b)) => {
-                    Ok(Value::Scalar(comp_infix([a], *op, [b])[0]))
+        let batch_dims = batch_dims.unwrap_or(&[]).to_vec();
+        let n: usize = batch_dims.iter().product();
+
+        let out_lanes = self.output_dtype().n_lanes();
+        let mut out_dims = batch_dims;
+        out_dims.push(out_lanes);
+        if n == 0 {
+            return Ok(NdArray::zeros(out_dims));
+        }
+
+        // Each slot stores its lanes one after the other; all of the x values, then all of the
+        // y values, and so on
+        let mut slots: Vec<Vec<F>> = Vec::with_capacity(self.graph.len());
+
+        for ((_, node), dtype) in self.graph.iter().zip(&self.dtypes) {
+            let lanes = dtype.n_lanes();
+            let slot = match node {
+                NodeKind::ExternInput(id, _) => {
+                    if let Some(array) = varying.get(id) {
+                        if array.shape().last() != Some(&lanes) {
+                            return Err(EvalError::TypeMismatch);
+                        }
+                        if array.shape().len() == 1 {
+                            splat_lanes(array.data().iter().copied(), n)
+                        } else {
+                            let mut slot = vec![F::default(); lanes * n];
+                            for (idx, elem) in array.data().chunks_exact(lanes).enumerate() {
+                                for (lane, value) in elem.iter().enumerate() {
+                                    slot[lane * n + idx] = *value;
+                                }
+                            }
+                            slot
+                        }
+                    } else {
+                        let value = uniforms
+                            .inputs()
+                            .get(id)
+                            .ok_or_else(|| EvalError::BadInputId(id.clone()))?;
+                        if value.dtype() != *dtype {
+                            return Err(EvalError::TypeMismatch);
+                        }
+                        splat(*value, n)
+                    }
                 }
-                (Value::Vec2(a), Value::Vec2(b)) => Ok(Value::Vec2(comp_infix(a, *op, b))),
-                (Value::Vec3(a), Value::Vec3(b)) => Ok(Value::Vec3(comp_infix(a, *op, b))),
-                (Value::Vec4(a), Value::Vec4(b)) => Ok(Value::Vec4(comp_infix(a, *op, b))),
-                _ => Err(EvalError::TypeMismatch),
-            }
+                NodeKind::Constant(value) => splat(*value, n),
+                NodeKind::Make(components, _) => components
+                    .iter()
+                    .flat_map(|slot_id| slots[slot_id.index()].iter().copied())
+                    .collect(),
+                NodeKind::ComponentInfixOp(a, infix, b) => slots[a.index()]
+                    .iter()
+                    .zip(&slots[b.index()])
+                    .map(|(a, b)| infix.native(*a, *b))
+                    .collect(),
+                NodeKind::ComponentFn(func, a) => {
+                    slots[a.index()].iter().map(|a| func.native(*a)).collect()
+                }
+                NodeKind::GetComponent(vector, index) => {
+                    let vector = &slots[vector.index()];
+                    let vector_lanes = vector.len() / n;
+                    slots[index.index()]
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, index)| {
+                            let index = index.clamp(F::default(), F::from_f32(vector_lanes as f32));
+                            let index = (index.to_f64() as usize).clamp(0, vector_lanes - 1);
+                            vector[index * n + idx]
+                        })
+                        .collect()
+                }
+                NodeKind::Dot(a, b) => {
+                    let mut slot = vec![F::default(); n];
+                    for (lane_a, lane_b) in slots[a.index()]
+                        .chunks_exact(n)
+                        .zip(slots[b.index()].chunks_exact(n))
+                    {
+                        for ((out, a), b) in slot.iter_mut().zip(lane_a).zip(lane_b) {
+                            *out += *a * *b;
+                        }
+                    }
+                    slot
+                }
+            };
+            slots.push(slot);
         }
-        Node::ComponentFn(func, a) => match evaluate_node(a, ctx)? {
-            Value::Scalar(a) => Ok(Value::Scalar(comp_func([a], *func)[0])),
-            Value::Vec2(a) => Ok(Value::Vec2(comp_func(a, *func))),
-            Value::Vec3(a) => Ok(Value::Vec3(comp_func(a, *func))),
-            Value::Vec4(a) => Ok(Value::Vec4(comp_func(a, *func))),
-        },
-        Node::GetComponent(value, index) => {
-            let value = evaluate_node(value, ctx)?;
-            if let Value::Scalar(index) = evaluate_node(index, ctx)? {
-                let index = index.clamp(0., value.dtype().n_lanes() as f32);
-                let index = (index as usize).clamp(0, value.dtype().n_lanes() - 1);
-                Ok(Value::Scalar(match value {
-                    Value::Scalar(val) => val,
-                    Value::Vec2(arr) => arr[index],
-                    Value::Vec3(arr) => arr[index],
-                    Value::Vec4(arr) => arr[index],
-                }))
-            } else {
-                Err(EvalError::TypeMismatch)
+
+        // Interleave the lanes of the output slot
+        let out_slot = &slots[self.graph.root().index()];
+        let mut out = NdArray::zeros(out_dims);
+        for (lane, values) in out_slot.chunks_exact(n).enumerate() {
+            for (idx, value) in values.iter().enumerate() {
+                out.data_mut()[idx * out_lanes + lane] = *value;
             }
         }
-        // TODO: Typecheck dtype here!
-        Node::ExternInput(id, _dtype) => ctx
-            .inputs
-            .get(id)
-            .copied()
-            .ok_or_else(|| EvalError::BadInputId(id.clone())),
-        Node::Dot(a, b) => match (evaluate_node(a, ctx)?, evaluate_node(b, ctx)?) {
-            (Value::Scalar(a), Value::Scalar(b)) => Ok(Value::Scalar(a * b)),
-            (Value::Vec2(a), Value::Vec2(b)) => Ok(Value::Scalar(dot(&a, &b))),
-            (Value::Vec3(a), Value::Vec3(b)) => Ok(Value::Scalar(dot(&a, &b))),
-            (Value::Vec4(a), Value::Vec4(b)) => Ok(Value::Scalar(dot(&a, &b))),
-            _ => Err(EvalError::TypeMismatch),
-        },
+
+        Ok(out)
     }
 }
+
+fn splat<F: Float>(value: Value, n: usize) -> Vec<F> {
+    splat_lanes(value.iter_vector_floats().map(F::from_f32), n)
+}
+
+/// Repeat each lane `n` times
+fn splat_lanes<F: Float>(lanes: impl Iterator<Item = F>, n: usize) -> Vec<F> {
+    lanes.flat_map(|float| std::iter::repeat_n(float, n)).collect()
+}
@@ -0,0 +1,408 @@
+//! Reading the functions of a saved `.vor` project without the editor. The editor saves each
+//! function as the node graph laid out on screen; this mirrors just enough of that format to
+//! lower the graphs again, so that build scripts need not depend on the GUI.
+use std::collections::{BTreeMap, HashSet};
+use std::rc::Rc;
+
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+
+use crate::graph::Graph;
+use crate::highlevel::{convert_graph, HighNode};
+use crate::*;
+
+/// Only the parts of a saved project needed to rebuild its functions
+#[derive(Deserialize)]
+pub struct ProjectFile {
+    pub functions: Vec<(String, FunctionFile)>,
+    #[serde(default)]
+    pub precision: Precision,
+}
+
+/// One function of a saved project
+#[derive(Deserialize)]
+pub struct FunctionFile {
+    params: SavedParams,
+    state: EditorState,
+}
+
+/// Why a saved function could not be rebuilt
+#[derive(Clone, Debug, PartialEq)]
+pub enum InvalidProject {
+    /// A node, input or output refers to one which is not in the graph
+    DanglingKey,
+    /// A node lacks an input which nodes of its kind always have
+    MissingInput { node: String, input: String },
+    /// The output of the node depends on itself
+    Cycle(String),
+    /// The graph has no output node
+    NoOutput,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedParams {
+    List(ParameterList),
+    /// Older projects stored parameters by name, and they were passed in sorted order
+    Map(BTreeMap<ExternInputId, DataType>),
+}
+
+#[derive(Deserialize)]
+struct EditorState {
+    graph: EditorGraph,
+}
+
+/// The graph as saved by the editor. Nodes, inputs and outputs are kept in slot maps, and refer
+/// to each other by key.
+#[derive(Deserialize)]
+struct EditorGraph {
+    nodes: SlotMap<EditorNode>,
+    inputs: SlotMap<EditorInput>,
+    outputs: SlotMap<EditorOutput>,
+    /// Output connected to each input, in the slot of the input
+    connections: SlotMap<Key>,
+}
+
+#[derive(Deserialize)]
+struct SlotMap<T> {
+    map: Vec<Slot<T>>,
+}
+
+#[derive(Deserialize)]
+struct Slot<T> {
+    value: Option<T>,
+    version: u32,
+}
+
+/// Index into a slot map, followed by an ID of the map which is not needed here
+#[derive(Deserialize, Clone, Copy)]
+struct Key(KeyData, IgnoredAny);
+
+#[derive(Deserialize, Clone, Copy)]
+struct KeyData {
+    idx: u32,
+    version: u32,
+}
+
+#[derive(Deserialize)]
+struct EditorNode {
+    label: String,
+    inputs: Vec<(String, Key)>,
+    user_data: NodeData,
+}
+
+#[derive(Deserialize)]
+struct NodeData {
+    template: NodeTemplate,
+}
+
+/// Value of an input, used when nothing is connected to it
+#[derive(Deserialize)]
+struct EditorInput {
+    value: Value,
+}
+
+#[derive(Deserialize)]
+struct EditorOutput {
+    node: Key,
+}
+
+/// Kind of an editor node. Saved in the same way as `MyNodeTemplate` in `vorpal-widgets`, whose
+/// variants this must follow.
+#[derive(Deserialize)]
+enum NodeTemplate {
+    Input(ExternInputId, DataType),
+    Make(DataType),
+    ComponentInfixOp(ComponentInfixOp, DataType),
+    ComponentFn(ComponentFn, DataType),
+    GetComponent(DataType),
+    Output(DataType),
+    Dot(DataType),
+    Normalize(DataType),
+    Splat(DataType),
+    Swizzle(DataType, DataType),
+    Gradient(DataType),
+    Comment,
+}
+
+impl FunctionFile {
+    pub fn params(&self) -> ParameterList {
+        match &self.params {
+            SavedParams::List(params) => params.clone(),
+            SavedParams::Map(params) => ParameterList(
+                params
+                    .iter()
+                    .map(|(id, dtype)| (id.clone(), *dtype))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Extract and lower the output of the graph, giving the same graph as the editor would
+    pub fn extract_output_graph(&self) -> Result<Graph, InvalidProject> {
+        let graph = &self.state.graph;
+        let output = graph
+            .nodes
+            .iter()
+            .find(|(_, node)| matches!(node.user_data.template, NodeTemplate::Output(_)))
+            .map(|(key, _)| key)
+            .ok_or(InvalidProject::NoOutput)?;
+
+        let mut extraction = Extraction {
+            graph,
+            cache: HashMap::new(),
+            stack: HashSet::new(),
+        };
+        Ok(convert_graph(extraction.node(output)?))
+    }
+}
+
+impl<T> SlotMap<T> {
+    fn get(&self, key: Key) -> Option<&T> {
+        let slot = self.map.get(key.0.idx as usize)?;
+        if slot.version == key.0.version {
+            slot.value.as_ref()
+        } else {
+            None
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Key, &T)> {
+        self.map.iter().enumerate().filter_map(|(idx, slot)| {
+            let data = KeyData {
+                idx: idx as u32,
+                version: slot.version,
+            };
+            slot.value
+                .as_ref()
+                .map(|value| (Key(data, IgnoredAny), value))
+        })
+    }
+}
+
+/// Converts editor nodes to high-level nodes, sharing the conversion of each node between
+/// everything connected to it
+struct Extraction<'graph> {
+    graph: &'graph EditorGraph,
+    /// Conversion of each node by slot, once it has been converted
+    cache: HashMap<u32, Rc<HighNode>>,
+    /// Nodes being converted, to detect cycles
+    stack: HashSet<u32>,
+}
+
+impl Extraction<'_> {
+    fn node(&mut self, key: Key) -> Result<Rc<HighNode>, InvalidProject> {
+        let idx = key.0.idx;
+        if let Some(cached) = self.cache.get(&idx) {
+            return Ok(cached.clone());
+        }
+
+        let graph = self.graph;
+        let node = graph.nodes.get(key).ok_or(InvalidProject::DanglingKey)?;
+        if !self.stack.insert(idx) {
+            return Err(InvalidProject::Cycle(node.label.clone()));
+        }
+
+        let extracted = match &node.user_data.template {
+            NodeTemplate::ComponentFn(func, _dtype) => {
+                Rc::new(HighNode::ComponentFn(*func, self.input(node, "x")?))
+            }
+            NodeTemplate::GetComponent(_dtype) => Rc::new(HighNode::GetComponent(
+                self.input(node, "value")?,
+                self.input(node, "index")?,
+            )),
+            NodeTemplate::ComponentInfixOp(op, _dtype) => Rc::new(HighNode::ComponentInfixOp(
+                self.input(node, "x")?,
+                *op,
+                self.input(node, "y")?,
+            )),
+            NodeTemplate::Make(dtype) => Rc::new(HighNode::Make(
+                dtype
+                    .lane_names()
+                    .map(|lane| self.input(node, &lane.to_string()))
+                    .collect::<Result<_, _>>()?,
+                *dtype,
+            )),
+            NodeTemplate::Input(name, dtype) => {
+                Rc::new(HighNode::ExternInput(name.clone(), *dtype))
+            }
+            NodeTemplate::Output(_dtype) => self.input(node, "x")?,
+            NodeTemplate::Dot(_dtype) => Rc::new(HighNode::Dot(
+                self.input(node, "x")?,
+                self.input(node, "y")?,
+            )),
+            NodeTemplate::Normalize(dtype) => {
+                Rc::new(HighNode::Normalize(self.input(node, "x")?, *dtype))
+            }
+            NodeTemplate::Splat(dtype) => Rc::new(HighNode::Splat(self.input(node, "x")?, *dtype)),
+            NodeTemplate::Swizzle(input_dtype, output_dtype) => Rc::new(HighNode::Swizzle {
+                input_vector: self.input(node, "x")?,
+                component_vector: self.input(node, "indices")?,
+                input_vector_dtype: *input_dtype,
+                output_vector_dtype: *output_dtype,
+            }),
+            NodeTemplate::Gradient(_dtype) => Rc::new(HighNode::Gradient {
+                f: self.input(node, "f")?,
+                wrt: self.input(node, "wrt")?,
+            }),
+            // Comments have no output, so nothing can be connected to them
+            NodeTemplate::Comment => return Err(InvalidProject::DanglingKey),
+        };
+
+        self.stack.remove(&idx);
+        self.cache.insert(idx, extracted.clone());
+        Ok(extracted)
+    }
+
+    /// The node connected to the named input, or its inline value if there is none
+    fn input(&mut self, node: &EditorNode, name: &str) -> Result<Rc<HighNode>, InvalidProject> {
+        let key = node
+            .inputs
+            .iter()
+            .find(|(input_name, _)| input_name == name)
+            .map(|(_, key)| *key)
+            .ok_or_else(|| InvalidProject::MissingInput {
+                node: node.label.clone(),
+                input: name.to_string(),
+            })?;
+
+        match self.graph.connections.get(key) {
+            Some(output_key) => {
+                let output = self
+                    .graph
+                    .outputs
+                    .get(*output_key)
+                    .ok_or(InvalidProject::DanglingKey)?;
+                self.node(output.node)
+            }
+            None => {
+                let input = self
+                    .graph
+                    .inputs
+                    .get(key)
+                    .ok_or(InvalidProject::DanglingKey)?;
+                Ok(Rc::new(HighNode::Constant(input.value)))
+            }
+        }
+    }
+}
+
+impl std::error::Error for InvalidProject {}
+
+impl std::fmt::Display for InvalidProject {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DanglingKey => write!(f, "The graph refers to a node which is not in it"),
+            Self::MissingInput { node, input } => {
+                write!(f, "Node {:?} has no input {:?}", node, input)
+            }
+            Self::Cycle(node) => write!(f, "Node {:?} depends on itself", node),
+            Self::NoOutput => write!(f, "The graph has no output node"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::native_backend::evaluate_node;
+    use serde_json::{json, Value as Json};
+
+    fn key(idx: u32) -> Json {
+        json!([{ "idx": idx, "version": 1 }, 0])
+    }
+
+    /// A slot map as the editor saves it, with the first slot left empty
+    fn slot_map(values: Vec<Json>) -> Json {
+        let slots: Vec<Json> = [json!({ "value": null, "version": 0 })]
+            .into_iter()
+            .chain(values.into_iter().map(|value| {
+                let version = if value.is_null() { 0 } else { 1 };
+                json!({ "value": value, "version": version })
+            }))
+            .collect();
+        json!({ "map": slots })
+    }
+
+    fn node(label: &str, inputs: &[(&str, u32)], template: Json) -> Json {
+        let inputs: Vec<Json> = inputs
+            .iter()
+            .map(|(name, idx)| json!([name, key(*idx)]))
+            .collect();
+        json!({ "label": label, "inputs": inputs, "user_data": { "template": template } })
+    }
+
+    /// An input node "p", and an output node of `p + y`. `y_connection` is the output connected
+    /// to y, if any; output 1 is that of "p" and output 2 that of the sum.
+    fn function(params: Json, y_connection: Option<u32>) -> FunctionFile {
+        let nodes = vec![
+            node("Output", &[("x", 1)], json!({ "Output": "Vec2" })),
+            node("p", &[], json!({ "Input": ["p", "Vec2"] })),
+            node(
+                "Add",
+                &[("x", 2), ("y", 3)],
+                json!({ "ComponentInfixOp": ["Add", "Vec2"] }),
+            ),
+        ];
+        let inputs = vec![
+            json!({ "value": { "Vec2": [0.0, 0.0] } }),
+            json!({ "value": { "Vec2": [0.0, 0.0] } }),
+            json!({ "value": { "Vec2": [1.0, 2.0] } }),
+        ];
+        let outputs = vec![json!({ "node": key(2) }), json!({ "node": key(3) })];
+        let connections = vec![key(2), key(1), y_connection.map_or(Json::Null, key)];
+
+        serde_json::from_value(json!({
+            "params": params,
+            "state": {
+                "graph": {
+                    "nodes": slot_map(nodes),
+                    "inputs": slot_map(inputs),
+                    "outputs": slot_map(outputs),
+                    "connections": slot_map(connections),
+                },
+                "node_order": [],
+            },
+            "user_state": {},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn connections_and_inline_values_are_extracted() {
+        let function = function(json!([["p", "Vec2"]]), None);
+        let graph = function.extract_output_graph().unwrap();
+
+        let ctx = ExternParameters::new(
+            [(ExternInputId::new("p".into()), Value::Vec2([0.5, -0.5]))]
+                .into_iter()
+                .collect(),
+        );
+        assert_eq!(function.params(), ctx.build_parameter_list());
+        assert_eq!(
+            evaluate_node(&graph, &ctx).unwrap(),
+            Value::Vec2([1.5, 1.5])
+        );
+    }
+
+    #[test]
+    fn parameters_saved_by_name_are_sorted() {
+        let function = function(json!({ "p": "Vec2", "b": "Scalar", "a": "Vec4" }), None);
+        let names: Vec<String> = function
+            .params()
+            .inputs()
+            .iter()
+            .map(|(id, _)| id.to_string())
+            .collect();
+        assert_eq!(names, ["a", "b", "p"]);
+    }
+
+    #[test]
+    fn cycles_are_errors() {
+        let function = function(json!([["p", "Vec2"]]), Some(2));
+        assert_eq!(
+            function.extract_output_graph(),
+            Err(InvalidProject::Cycle("Add".into()))
+        );
+    }
+}
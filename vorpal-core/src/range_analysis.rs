@@ -0,0 +1,568 @@
+use crate::graph::{Graph, NodeId, NodeKind};
+use crate::*;
+
+/// Set of values a single lane may take
+#[cfg_attr(feature = "persistence", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Interval {
+    /// Lower bound. An infinite bound means the lane is unbounded, not that it may be infinite.
+    pub lo: f32,
+    /// Upper bound
+    pub hi: f32,
+    /// May be positive or negative infinity
+    pub inf: bool,
+    /// May be NaN
+    pub nan: bool,
+}
+
+/// Interval of each lane of a value
+pub type Range = Vec<Interval>;
+
+/// Declared bounds of each parameter. Parameters without bounds are assumed to be finite, but
+/// otherwise unbounded.
+pub type ParameterBounds = HashMap<ExternInputId, Range>;
+
+/// Results of propagating parameter bounds through a graph
+#[derive(Clone, Debug)]
+pub struct RangeAnalysis {
+    /// Range of every node in the graph
+    pub ranges: Vec<Range>,
+    /// Nodes which may produce NaN or infinity from inputs which can't be either
+    pub warnings: Vec<RangeWarning>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RangeWarning {
+    /// The node at fault
+    pub node: NodeId,
+    pub kind: RangeWarningKind,
+    /// Ranges of the inputs to the node
+    pub inputs: Vec<Range>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RangeWarningKind {
+    MayBeNan,
+    MayBeInfinite,
+}
+
+/// Largest argument to `exp()` which does not overflow
+const EXP_MAX: f32 = 88.72283;
+
+impl Interval {
+    /// Any finite value
+    pub const UNBOUNDED: Self = Self {
+        lo: f32::NEG_INFINITY,
+        hi: f32::INFINITY,
+        inf: false,
+        nan: false,
+    };
+
+    /// Values between `lo` and `hi` inclusive
+    pub fn new(lo: f32, hi: f32) -> Self {
+        Self {
+            lo,
+            hi,
+            inf: false,
+            nan: false,
+        }
+    }
+
+    /// Exactly one value
+    pub fn point(x: f32) -> Self {
+        if x.is_nan() {
+            Self {
+                lo: f32::INFINITY,
+                hi: f32::NEG_INFINITY,
+                inf: false,
+                nan: true,
+            }
+        } else {
+            Self {
+                lo: x,
+                hi: x,
+                inf: x.is_infinite(),
+                nan: false,
+            }
+        }
+    }
+
+    pub fn contains(&self, x: f32) -> bool {
+        self.lo <= x && x <= self.hi
+    }
+
+    fn union(self, other: Self) -> Self {
+        Self {
+            lo: self.lo.min(other.lo),
+            hi: self.hi.max(other.hi),
+            inf: self.inf || other.inf,
+            nan: self.nan || other.nan,
+        }
+    }
+
+    /// Extend `[lo, hi]` to cover the given values, which are calculated from the bounds of
+    /// `inputs`. A bound which overflowed to infinity means the result may actually be infinite.
+    fn from_candidates(candidates: &[f32], inputs: &[Self]) -> Self {
+        let lo = candidates
+            .iter()
+            .copied()
+            .filter(|x| !x.is_nan())
+            .fold(f32::INFINITY, f32::min);
+        let hi = candidates
+            .iter()
+            .copied()
+            .filter(|x| !x.is_nan())
+            .fold(f32::NEG_INFINITY, f32::max);
+        let bounded = inputs
+            .iter()
+            .all(|input| input.lo.is_finite() && input.hi.is_finite());
+        Self {
+            lo,
+            hi,
+            inf: inputs.iter().any(|input| input.inf)
+                || (bounded && !(lo.is_finite() && hi.is_finite())),
+            nan: inputs.iter().any(|input| input.nan),
+        }
+    }
+
+    fn add(self, other: Self) -> Self {
+        let mut out =
+            Self::from_candidates(&[self.lo + other.lo, self.hi + other.hi], &[self, other]);
+        // Infinity minus infinity
+        out.nan |= self.inf && other.inf;
+        out
+    }
+
+    fn neg(self) -> Self {
+        Self {
+            lo: -self.hi,
+            hi: -self.lo,
+            ..self
+        }
+    }
+
+    fn mul(self, other: Self) -> Self {
+        // Unbounded lanes are finite, so an infinite bound times zero is zero
+        let mul = |a: f32, b: f32| if a == 0. || b == 0. { 0. } else { a * b };
+        let mut out = Self::from_candidates(
+            &[
+                mul(self.lo, other.lo),
+                mul(self.lo, other.hi),
+                mul(self.hi, other.lo),
+                mul(self.hi, other.hi),
+            ],
+            &[self, other],
+        );
+        // Infinity times zero
+        out.nan |= (self.inf && other.contains(0.)) || (other.inf && self.contains(0.));
+        out
+    }
+
+    fn div(self, other: Self) -> Self {
+        if other.contains(0.) {
+            let mut out =
+                Self::from_candidates(&[f32::NEG_INFINITY, f32::INFINITY], &[self, other]);
+            out.inf = true;
+            // Zero divided by zero
+            out.nan |= self.contains(0.) || (self.inf && other.inf);
+            return out;
+        }
+
+        let mut out = Self::from_candidates(
+            &[
+                self.lo / other.lo,
+                self.lo / other.hi,
+                self.hi / other.lo,
+                self.hi / other.hi,
+            ],
+            &[self, other],
+        );
+        // Infinity divided by infinity
+        out.nan |= self.inf && other.inf;
+        out
+    }
+
+    fn powf(self, other: Self) -> Self {
+        let integer_exponent = other.lo == other.hi && other.lo.fract() == 0.;
+        if self.lo < 0. && !integer_exponent {
+            // Negative numbers raised to fractional powers
+            let mut out = Self::UNBOUNDED;
+            out.nan = true;
+            out.inf = self.inf || other.inf || (self.contains(0.) && other.lo < 0.);
+            return out;
+        }
+
+        let mut candidates = vec![];
+        for base in [self.lo, self.hi] {
+            for exponent in [other.lo, other.hi] {
+                candidates.push(base.powf(exponent));
+            }
+        }
+        if self.contains(0.) {
+            candidates.extend([0_f32.powf(other.lo), 0_f32.powf(other.hi)]);
+        }
+        if self.lo < 0. && self.contains(0.) {
+            // Approaching zero from below, which goes to negative infinity for odd negative powers
+            candidates.extend([(-0_f32).powf(other.lo), (-0_f32).powf(other.hi)]);
+        }
+        let mut out = Self::from_candidates(&candidates, &[self, other]);
+        // Zero raised to a negative power
+        out.inf |= self.contains(0.) && other.lo < 0.;
+        out
+    }
+
+    fn ln(self) -> Self {
+        let mut out = Self::from_candidates(&[self.lo.max(0.).ln(), self.hi.ln()], &[self]);
+        out.nan |= self.lo < 0.;
+        out.inf |= self.contains(0.);
+        out
+    }
+
+    fn exp(self) -> Self {
+        let mut out = Self::from_candidates(&[self.lo.exp(), self.hi.exp()], &[self]);
+        out.inf |= self.hi > EXP_MAX;
+        out
+    }
+
+    fn sin(self) -> Self {
+        self.wave(f32::sin, std::f32::consts::FRAC_PI_2)
+    }
+
+    fn cos(self) -> Self {
+        self.wave(f32::cos, 0.)
+    }
+
+    /// Range of sin or cos, which peak at `peak + 2k pi` and bottom out half a period later.
+    /// Both are evaluated directly at the bounds, since shifting one into the other rounds.
+    fn wave(self, f: fn(f32) -> f32, peak: f32) -> Self {
+        use std::f32::consts::{PI, TAU};
+        let mut out = if self.hi - self.lo >= TAU || !(self.lo.is_finite() && self.hi.is_finite()) {
+            Self::new(-1., 1.)
+        } else {
+            let mut candidates = vec![f(self.lo), f(self.hi)];
+            let first_peak = ((self.lo - peak) / TAU).ceil() * TAU + peak;
+            if first_peak <= self.hi {
+                candidates.push(1.);
+            }
+            let trough = peak + PI;
+            let first_trough = ((self.lo - trough) / TAU).ceil() * TAU + trough;
+            if first_trough <= self.hi {
+                candidates.push(-1.);
+            }
+            Self::from_candidates(&candidates, &[])
+        };
+        out.nan = self.nan || self.inf;
+        out
+    }
+
+    fn tan(self) -> Self {
+        use std::f32::consts::{FRAC_PI_2, PI};
+        // Asymptotes at pi/2 + k pi
+        let first_asymptote = ((self.lo - FRAC_PI_2) / PI).ceil() * PI + FRAC_PI_2;
+        let mut out = if first_asymptote <= self.hi || !(self.lo.is_finite() && self.hi.is_finite())
+        {
+            Self::UNBOUNDED
+        } else {
+            Self::from_candidates(&[self.lo.tan(), self.hi.tan()], &[])
+        };
+        out.nan = self.nan || self.inf;
+        out
+    }
+
+    fn abs(self) -> Self {
+        let mut out = Self::from_candidates(&[self.lo.abs(), self.hi.abs()], &[self]);
+        if self.contains(0.) {
+            out.lo = 0.;
+        }
+        out
+    }
+
+    /// Applies a nondecreasing function to the bounds
+    fn monotonic(self, f: impl Fn(f32) -> f32) -> Self {
+        Self::from_candidates(&[f(self.lo), f(self.hi)], &[self])
+    }
+
+    /// Comparisons never produce NaN; they are false if either side is NaN
+    fn compare(self, other: Self, op: ComponentInfixOp) -> Self {
+        let always = match op {
+            ComponentInfixOp::GreaterThan => self.lo > other.hi,
+            ComponentInfixOp::LessThan => self.hi < other.lo,
+            _ => self.lo == self.hi && other.lo == other.hi && self.lo == other.lo,
+        };
+        let never = match op {
+            ComponentInfixOp::GreaterThan => self.hi <= other.lo,
+            ComponentInfixOp::LessThan => self.lo >= other.hi,
+            _ => self.hi < other.lo || self.lo > other.hi,
+        };
+        let maybe_nan = self.nan || other.nan;
+        match (always && !maybe_nan, never) {
+            (true, _) => Self::point(1.),
+            (_, true) => Self::point(0.),
+            _ => Self::new(0., 1.),
+        }
+    }
+}
+
+impl std::fmt::Display for Interval {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.lo <= self.hi {
+            write!(f, "[{}, {}]", self.lo, self.hi)?;
+        } else {
+            write!(f, "[]")?;
+        }
+        if self.inf {
+            write!(f, " or ±inf")?;
+        }
+        if self.nan {
+            write!(f, " or NaN")?;
+        }
+        Ok(())
+    }
+}
+
+/// Propagate the bounds of each parameter through the graph, which should typecheck. Bounds with
+/// the wrong number of lanes for their parameter are ignored.
+pub fn analyze(graph: &Graph, bounds: &ParameterBounds) -> RangeAnalysis {
+    let mut ranges: Vec<Range> = Vec::with_capacity(graph.len());
+    let mut warnings = vec![];
+
+    for (node_id, node) in graph.iter() {
+        let range_of = |id: &NodeId| &ranges[id.index()];
+        let lanewise = |a: &NodeId, b: &NodeId, f: &dyn Fn(Interval, Interval) -> Interval| {
+            range_of(a)
+                .iter()
+                .zip(range_of(b))
+                .map(|(a, b)| f(*a, *b))
+                .collect::<Range>()
+        };
+
+        let range: Range = match node {
+            NodeKind::ExternInput(id, dtype) => bounds
+                .get(id)
+                .filter(|range| range.len() == dtype.n_lanes())
+                .cloned()
+                .unwrap_or_else(|| vec![Interval::UNBOUNDED; dtype.n_lanes()]),
+            NodeKind::Constant(value) => value.iter_vector_floats().map(Interval::point).collect(),
+            NodeKind::Make(components, _) => components
+                .iter()
+                .map(|c| range_of(c).first().copied().unwrap_or(Interval::UNBOUNDED))
+                .collect(),
+            NodeKind::ComponentInfixOp(a, op, b) => match op {
+                ComponentInfixOp::Add => lanewise(a, b, &Interval::add),
+                ComponentInfixOp::Subtract => lanewise(a, b, &|a, b| a.add(b.neg())),
+                ComponentInfixOp::Multiply => lanewise(a, b, &Interval::mul),
+                ComponentInfixOp::Divide => lanewise(a, b, &Interval::div),
+                ComponentInfixOp::Power => lanewise(a, b, &Interval::powf),
+                ComponentInfixOp::Logbase => lanewise(a, b, &|a, b| a.ln().div(b.ln())),
+                ComponentInfixOp::GreaterThan
+                | ComponentInfixOp::LessThan
+                | ComponentInfixOp::EqualTo => lanewise(a, b, &|a, b| a.compare(b, *op)),
+            },
+            NodeKind::ComponentFn(func, a) => range_of(a)
+                .iter()
+                .map(|a| match func {
+                    ComponentFn::Cosine => a.cos(),
+                    ComponentFn::Sine => a.sin(),
+                    ComponentFn::Tangent => a.tan(),
+                    ComponentFn::NaturalLog => a.ln(),
+                    ComponentFn::NaturalExp => a.exp(),
+                    ComponentFn::Ceil => a.monotonic(f32::ceil),
+                    ComponentFn::Floor => a.monotonic(f32::floor),
+                    ComponentFn::Abs => a.abs(),
+                })
+                .collect(),
+            NodeKind::GetComponent(vector, index) => {
+                let vector = range_of(vector);
+                let index = range_of(index)
+                    .first()
+                    .copied()
+                    .unwrap_or(Interval::UNBOUNDED);
+                // Indices are clamped, and NaN selects the first lane
+                let lane =
+                    |x: f32| (x.clamp(0., vector.len() as f32) as usize).min(vector.len() - 1);
+                let mut lanes = lane(index.lo)..=lane(index.hi);
+                if index.nan {
+                    lanes = 0..=*lanes.end();
+                }
+                vec![vector[lanes]
+                    .iter()
+                    .copied()
+                    .reduce(Interval::union)
+                    .unwrap_or(Interval::UNBOUNDED)]
+            }
+            NodeKind::Dot(a, b) => vec![lanewise(a, b, &Interval::mul)
+                .into_iter()
+                .reduce(Interval::add)
+                .unwrap_or(Interval::point(0.))],
+        };
+
+        // Only warn about the node which introduces NaN or infinity, not everything after it
+        let inputs: Vec<Range> = node
+            .inputs()
+            .iter()
+            .map(|id| range_of(id).clone())
+            .collect();
+        let inputs_nan = inputs.iter().flatten().any(|i| i.nan);
+        let inputs_inf = inputs.iter().flatten().any(|i| i.inf);
+        let kind = if range.iter().any(|i| i.nan) && !inputs_nan {
+            Some(RangeWarningKind::MayBeNan)
+        } else if range.iter().any(|i| i.inf) && !inputs_inf {
+            Some(RangeWarningKind::MayBeInfinite)
+        } else {
+            None
+        };
+        if let Some(kind) = kind {
+            warnings.push(RangeWarning {
+                node: node_id,
+                kind,
+                inputs,
+            });
+        }
+
+        ranges.push(range);
+    }
+
+    RangeAnalysis { ranges, warnings }
+}
+
+impl std::fmt::Display for RangeWarningKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::MayBeNan => "may produce NaN",
+            Self::MayBeInfinite => "may produce infinity",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::fmt::Display for RangeWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} for inputs in ", self.kind)?;
+        for (idx, input) in self.inputs.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ", ")?;
+            }
+            let lanes: Vec<String> = input.iter().map(|i| i.to_string()).collect();
+            write!(f, "({})", lanes.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVALS: [(f32, f32); 8] = [
+        (-1., 1.),
+        (0., 1.),
+        (-3., 0.),
+        (-2., -0.5),
+        (0.5, 3.),
+        (2., 2.),
+        (-1., -1.),
+        (-0.5, 0.5),
+    ];
+
+    /// The bounds, and some values inside, including either side of zero
+    fn samples(interval: Interval) -> Vec<f32> {
+        let (lo, hi) = (interval.lo, interval.hi);
+        let mut samples = vec![lo, hi, (lo + hi) / 2., lo + (hi - lo) / 3.];
+        samples.extend(
+            [0., -0., 1e-3, -1e-3]
+                .into_iter()
+                .filter(|x| interval.contains(*x)),
+        );
+        samples
+    }
+
+    fn assert_contains(out: Interval, x: f32, what: &str) {
+        let contained = if x.is_nan() {
+            out.nan
+        } else if x.is_infinite() {
+            out.inf
+        } else {
+            out.contains(x)
+        };
+        assert!(contained, "{what} = {x} is not in {out}");
+    }
+
+    fn assert_unary_op_contains(
+        name: &str,
+        interval_op: fn(Interval) -> Interval,
+        op: fn(f32) -> f32,
+    ) {
+        for (lo, hi) in INTERVALS {
+            let a = Interval::new(lo, hi);
+            for x in samples(a) {
+                assert_contains(interval_op(a), op(x), &format!("{name}({x})"));
+            }
+        }
+    }
+
+    fn assert_binary_op_contains(
+        name: &str,
+        interval_op: fn(Interval, Interval) -> Interval,
+        op: fn(f32, f32) -> f32,
+    ) {
+        for (a_lo, a_hi) in INTERVALS {
+            for (b_lo, b_hi) in INTERVALS {
+                let (a, b) = (Interval::new(a_lo, a_hi), Interval::new(b_lo, b_hi));
+                for x in samples(a) {
+                    for y in samples(b) {
+                        let what =
+                            format!("[{a_lo}, {a_hi}] {name} [{b_lo}, {b_hi}]: {x} {name} {y}");
+                        assert_contains(interval_op(a, b), op(x, y), &what);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unary_ops_contain_their_results() {
+        assert_unary_op_contains("ln", Interval::ln, f32::ln);
+        assert_unary_op_contains("exp", Interval::exp, f32::exp);
+        assert_unary_op_contains("sin", Interval::sin, f32::sin);
+        assert_unary_op_contains("cos", Interval::cos, f32::cos);
+        assert_unary_op_contains("tan", Interval::tan, f32::tan);
+        assert_unary_op_contains("abs", Interval::abs, f32::abs);
+    }
+
+    #[test]
+    fn binary_ops_contain_their_results() {
+        assert_binary_op_contains("+", Interval::add, |a, b| a + b);
+        assert_binary_op_contains("*", Interval::mul, |a, b| a * b);
+        assert_binary_op_contains("/", Interval::div, |a, b| a / b);
+        assert_binary_op_contains("^", Interval::powf, f32::powf);
+    }
+
+    #[test]
+    fn powers_widen_across_the_pole() {
+        let out = Interval::new(-1., 1.).powf(Interval::point(-1.));
+        assert_eq!((out.lo, out.hi), (f32::NEG_INFINITY, f32::INFINITY));
+        assert!(out.inf && !out.nan);
+
+        let out = Interval::new(-1., 1.).powf(Interval::point(-2.));
+        assert_eq!((out.lo, out.hi), (1., f32::INFINITY));
+
+        let out = Interval::new(0., 2.).powf(Interval::point(-1.));
+        assert_eq!((out.lo, out.hi), (0.5, f32::INFINITY));
+    }
+
+    #[test]
+    fn flags_propagate() {
+        let zero_to_one = Interval::new(0., 1.);
+        let out = zero_to_one.div(zero_to_one);
+        assert!(out.nan && out.inf);
+
+        let out = Interval::new(-1., 1.).ln();
+        assert!(out.nan && out.inf);
+
+        let out = Interval::new(0., 100.).exp();
+        assert!(out.inf && !out.nan);
+
+        assert!(!Interval::new(1., 2.).add(Interval::new(3., 4.)).inf);
+        assert!(Interval::point(f32::NAN).add(Interval::point(1.)).nan);
+    }
+}
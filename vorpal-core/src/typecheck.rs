@@ -0,0 +1,327 @@
+use crate::graph::{Graph, NodeId, NodeKind};
+use crate::*;
+
+/// A problem found by the type checker, attributed to a single node
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    /// The node at fault
+    pub node: NodeId,
+    pub kind: DiagnosticKind,
+    pub expected: TypeTerm,
+    pub found: TypeTerm,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    /// The operands of a componentwise operation or dot product have different datatypes
+    OperandMismatch,
+    /// A vector was made from the wrong number of components
+    ComponentCount,
+    /// A vector component or component index was not a scalar
+    ExpectedScalar,
+    /// An input is not in the function's parameter list
+    UnknownInput,
+    /// An input's datatype differs from the datatype in the parameter list
+    InputMismatch,
+}
+
+/// What a node expected, or what it found instead
+#[derive(Clone, Debug, PartialEq)]
+pub enum TypeTerm {
+    DataType(DataType),
+    ComponentCount(usize),
+    Input(ExternInputId),
+    /// Any input in the parameter list
+    AnyParameter,
+}
+
+/// Check that the graph is well-typed, returning the datatype of the root node
+pub fn typecheck(graph: &Graph, params: &ParameterList) -> Result<DataType, Vec<Diagnostic>> {
+    let dtypes = infer_dtypes(graph, params)?;
+    Ok(dtypes[graph.root().index()])
+}
+
+/// Check that the graph is well-typed, returning the datatype of every node
+pub fn infer_dtypes(
+    graph: &Graph,
+    params: &ParameterList,
+) -> Result<Vec<DataType>, Vec<Diagnostic>> {
+    let mut dtypes: Vec<DataType> = Vec::with_capacity(graph.len());
+    let mut diagnostics = vec![];
+
+    for (node_id, node) in graph.iter() {
+        let dtype_of = |id: &NodeId| dtypes[id.index()];
+        let mut report = |kind, expected, found| {
+            diagnostics.push(Diagnostic {
+                node: node_id,
+                kind,
+                expected,
+                found,
+            })
+        };
+
+        // Each node reports its own problems, and then carries on with its best guess of its
+        // output datatype so that later nodes may also be checked
+        let dtype = match node {
+            NodeKind::ExternInput(id, dtype) => {
+                match params.inputs().iter().find(|(param_id, _)| param_id == id) {
+                    None => report(
+                        DiagnosticKind::UnknownInput,
+                        TypeTerm::AnyParameter,
+                        TypeTerm::Input(id.clone()),
+                    ),
+                    Some((_, param_dtype)) if param_dtype != dtype => report(
+                        DiagnosticKind::InputMismatch,
+                        TypeTerm::DataType(*param_dtype),
+                        TypeTerm::DataType(*dtype),
+                    ),
+                    Some(_) => (),
+                }
+                *dtype
+            }
+            NodeKind::Constant(value) => value.dtype(),
+            NodeKind::Make(components, dtype) => {
+                if components.len() != dtype.n_lanes() {
+                    report(
+                        DiagnosticKind::ComponentCount,
+                        TypeTerm::ComponentCount(dtype.n_lanes()),
+                        TypeTerm::ComponentCount(components.len()),
+                    );
+                }
+                for component in components {
+                    if dtype_of(component) != DataType::Scalar {
+                        report(
+                            DiagnosticKind::ExpectedScalar,
+                            TypeTerm::DataType(DataType::Scalar),
+                            TypeTerm::DataType(dtype_of(component)),
+                        );
+                    }
+                }
+                *dtype
+            }
+            NodeKind::ComponentInfixOp(a, _, b) | NodeKind::Dot(a, b) => {
+                if dtype_of(a) != dtype_of(b) {
+                    report(
+                        DiagnosticKind::OperandMismatch,
+                        TypeTerm::DataType(dtype_of(a)),
+                        TypeTerm::DataType(dtype_of(b)),
+                    );
+                }
+                match node {
+                    NodeKind::Dot(_, _) => DataType::Scalar,
+                    _ => dtype_of(a),
+                }
+            }
+            NodeKind::ComponentFn(_, a) => dtype_of(a),
+            NodeKind::GetComponent(_, index) => {
+                if dtype_of(index) != DataType::Scalar {
+                    report(
+                        DiagnosticKind::ExpectedScalar,
+                        TypeTerm::DataType(DataType::Scalar),
+                        TypeTerm::DataType(dtype_of(index)),
+                    );
+                }
+                DataType::Scalar
+            }
+        };
+
+        dtypes.push(dtype);
+    }
+
+    if diagnostics.is_empty() {
+        Ok(dtypes)
+    } else {
+        Err(diagnostics)
+    }
+}
+
+impl std::fmt::Display for DiagnosticKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::OperandMismatch => "operand datatypes differ",
+            Self::ComponentCount => "wrong number of components",
+            Self::ExpectedScalar => "expected a scalar",
+            Self::UnknownInput => "input is not a parameter",
+            Self::InputMismatch => "input datatype differs from parameter",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::fmt::Display for TypeTerm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DataType(dtype) => write!(f, "{}", dtype),
+            Self::ComponentCount(n) => write!(f, "{} components", n),
+            Self::Input(id) => write!(f, "input \"{}\"", id),
+            Self::AnyParameter => write!(f, "a parameter"),
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Node {}: {}; expected {}, found {}",
+            self.node, self.kind, self.expected, self.found
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::GraphBuilder;
+
+    fn id(name: &str) -> ExternInputId {
+        ExternInputId::new(name.into())
+    }
+
+    fn params(inputs: &[(&str, DataType)]) -> ParameterList {
+        ParameterList(
+            inputs
+                .iter()
+                .map(|(name, dtype)| (id(name), *dtype))
+                .collect(),
+        )
+    }
+
+    fn scalar(builder: &mut GraphBuilder, value: f32) -> NodeId {
+        builder.push(NodeKind::Constant(Value::Scalar(value)))
+    }
+
+    /// The only diagnostic of the graph
+    fn diagnostic(builder: GraphBuilder, root: NodeId, params: &ParameterList) -> Diagnostic {
+        let mut diagnostics = infer_dtypes(&builder.finish(root), params).unwrap_err();
+        assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+        diagnostics.remove(0)
+    }
+
+    #[test]
+    fn operand_mismatch() {
+        let mut builder = GraphBuilder::new();
+        let a = scalar(&mut builder, 1.);
+        let b = builder.push(NodeKind::Constant(Value::Vec2([1., 2.])));
+        let add = builder.push(NodeKind::ComponentInfixOp(a, ComponentInfixOp::Add, b));
+
+        assert_eq!(
+            diagnostic(builder, add, &params(&[])),
+            Diagnostic {
+                node: add,
+                kind: DiagnosticKind::OperandMismatch,
+                expected: TypeTerm::DataType(DataType::Scalar),
+                found: TypeTerm::DataType(DataType::Vec2),
+            }
+        );
+    }
+
+    #[test]
+    fn component_count() {
+        let mut builder = GraphBuilder::new();
+        let x = scalar(&mut builder, 1.);
+        let make = builder.push(NodeKind::Make(vec![x, x], DataType::Vec3));
+
+        assert_eq!(
+            diagnostic(builder, make, &params(&[])),
+            Diagnostic {
+                node: make,
+                kind: DiagnosticKind::ComponentCount,
+                expected: TypeTerm::ComponentCount(3),
+                found: TypeTerm::ComponentCount(2),
+            }
+        );
+    }
+
+    #[test]
+    fn expected_scalar() {
+        let mut builder = GraphBuilder::new();
+        let v = builder.push(NodeKind::Constant(Value::Vec3([1., 2., 3.])));
+        let index = builder.push(NodeKind::Constant(Value::Vec2([0., 1.])));
+        let get = builder.push(NodeKind::GetComponent(v, index));
+
+        assert_eq!(
+            diagnostic(builder, get, &params(&[])),
+            Diagnostic {
+                node: get,
+                kind: DiagnosticKind::ExpectedScalar,
+                expected: TypeTerm::DataType(DataType::Scalar),
+                found: TypeTerm::DataType(DataType::Vec2),
+            }
+        );
+    }
+
+    #[test]
+    fn unknown_input() {
+        let mut builder = GraphBuilder::new();
+        let input = builder.push(NodeKind::ExternInput(id("y"), DataType::Scalar));
+
+        assert_eq!(
+            diagnostic(builder, input, &params(&[("x", DataType::Scalar)])),
+            Diagnostic {
+                node: input,
+                kind: DiagnosticKind::UnknownInput,
+                expected: TypeTerm::AnyParameter,
+                found: TypeTerm::Input(id("y")),
+            }
+        );
+    }
+
+    #[test]
+    fn input_mismatch() {
+        let mut builder = GraphBuilder::new();
+        let input = builder.push(NodeKind::ExternInput(id("x"), DataType::Vec4));
+
+        assert_eq!(
+            diagnostic(builder, input, &params(&[("x", DataType::Vec2)])),
+            Diagnostic {
+                node: input,
+                kind: DiagnosticKind::InputMismatch,
+                expected: TypeTerm::DataType(DataType::Vec2),
+                found: TypeTerm::DataType(DataType::Vec4),
+            }
+        );
+    }
+
+    #[test]
+    fn checking_continues_after_an_error() {
+        let mut builder = GraphBuilder::new();
+        let unknown = builder.push(NodeKind::ExternInput(id("missing"), DataType::Vec2));
+        let x = scalar(&mut builder, 1.);
+        // Still a Vec2, so the mismatch with the scalar is found too
+        let sin = builder.push(NodeKind::ComponentFn(ComponentFn::Sine, unknown));
+        let add = builder.push(NodeKind::ComponentInfixOp(sin, ComponentInfixOp::Add, x));
+        let make = builder.push(NodeKind::Make(vec![add, x], DataType::Vec2));
+        let graph = builder.finish(make);
+
+        let diagnostic = |node, kind, expected, found| Diagnostic {
+            node,
+            kind,
+            expected,
+            found,
+        };
+        assert_eq!(
+            infer_dtypes(&graph, &params(&[])),
+            Err(vec![
+                diagnostic(
+                    unknown,
+                    DiagnosticKind::UnknownInput,
+                    TypeTerm::AnyParameter,
+                    TypeTerm::Input(id("missing")),
+                ),
+                diagnostic(
+                    add,
+                    DiagnosticKind::OperandMismatch,
+                    TypeTerm::DataType(DataType::Vec2),
+                    TypeTerm::DataType(DataType::Scalar),
+                ),
+                diagnostic(
+                    make,
+                    DiagnosticKind::ExpectedScalar,
+                    TypeTerm::DataType(DataType::Scalar),
+                    TypeTerm::DataType(DataType::Vec2),
+                ),
+            ])
+        );
+    }
+}
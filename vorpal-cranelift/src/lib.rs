@@ -0,0 +1,444 @@
+use anyhow::{ensure, Result};
+use cranelift_codegen::ir::{
+    condcodes::FloatCC, types, AbiParam, FuncRef, InstBuilder, MemFlags, Value as IrValue,
+};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+use std::collections::HashMap;
+use vorpal_core::graph::{Graph, NodeId, NodeKind};
+use vorpal_core::*;
+
+/// Signature of the generated machine code; (parameter lanes, output lanes)
+type KernelPtr = unsafe extern "C" fn(*const f32, *mut f32);
+
+/// A node graph which has been compiled to native machine code
+pub struct CompiledFn {
+    /// Owns the executable memory `func` points into
+    _module: JITModule,
+    func: KernelPtr,
+    params: ParameterList,
+    n_param_lanes: usize,
+    output_dtype: DataType,
+}
+
+impl CompiledFn {
+    /// Inputs to the function will be arranged in the given order
+    pub fn new(graph: &Graph, params: &ParameterList) -> Result<Self> {
+        let mut builder = JITBuilder::new(default_libcall_names())?;
+        for (name, ptr) in builtin_symbols() {
+            builder.symbol(name, ptr);
+        }
+        let mut module = JITModule::new(builder);
+
+        let ptr_type = module.target_config().pointer_type();
+        let mut ctx = module.make_context();
+        ctx.func.signature.params.push(AbiParam::new(ptr_type));
+        ctx.func.signature.params.push(AbiParam::new(ptr_type));
+
+        let output_dtype = build_function(&mut module, &mut ctx, graph, params)?;
+
+        let func_id =
+            module.declare_function("vorpal_kernel", Linkage::Export, &ctx.func.signature)?;
+        module.define_function(func_id, &mut ctx)?;
+        module.clear_context(&mut ctx);
+        module.finalize_definitions()?;
+
+        let code = module.get_finalized_function(func_id);
+        // Safety: The function was declared with exactly this signature above
+        let func = unsafe { std::mem::transmute::<*const u8, KernelPtr>(code) };
+
+        Ok(Self {
+            _module: module,
+            func,
+            n_param_lanes: params
+                .inputs()
+                .iter()
+                .map(|(_, dtype)| dtype.n_lanes())
+                .sum(),
+            params: params.clone(),
+            output_dtype,
+        })
+    }
+
+    /// Output datatype of the root node
+    pub fn output_dtype(&self) -> DataType {
+        self.output_dtype
+    }
+
+    /// Get the parameter list passed to us at creation
+    pub fn params(&self) -> &ParameterList {
+        &self.params
+    }
+
+    /// Call the function with the lanes of each parameter laid out one after the other, in the
+    /// order of the parameter list
+    pub fn call_flat(&self, params: &[f32]) -> Result<Value> {
+        ensure!(
+            params.len() == self.n_param_lanes,
+            "Expected {} parameter lanes, got {}",
+            self.n_param_lanes,
+            params.len()
+        );
+        Ok(self.call_checked(params))
+    }
+
+    /// Call the function with parameters looked up by name
+    pub fn call(&self, ctx: &ExternParameters) -> Result<Value> {
+        let mut flat = Vec::with_capacity(self.n_param_lanes);
+        for (id, dtype) in self.params.inputs() {
+            let value = ctx
+                .inputs()
+                .get(id)
+                .ok_or_else(|| EvalError::BadInputId(id.clone()))?;
+            ensure!(value.dtype() == *dtype, EvalError::TypeMismatch);
+            flat.extend(value.iter_vector_floats());
+        }
+        Ok(self.call_checked(&flat))
+    }
+
+    /// Run the machine code, with a parameter buffer of `n_param_lanes` floats
+    fn call_checked(&self, params: &[f32]) -> Value {
+        let mut out = [0_f32; 4];
+        // Safety: The parameter buffer has been checked to be the right size, and the output
+        // buffer is large enough for any datatype
+        unsafe { (self.func)(params.as_ptr(), out.as_mut_ptr()) };
+
+        match self.output_dtype {
+            DataType::Scalar => Value::Scalar(out[0]),
+            DataType::Vec2 => Value::Vec2([out[0], out[1]]),
+            DataType::Vec3 => Value::Vec3([out[0], out[1], out[2]]),
+            DataType::Vec4 => Value::Vec4(out),
+        }
+    }
+}
+
+/// Per-function code generation state
+struct Codegen<'a> {
+    builder: FunctionBuilder<'a>,
+    /// Lanes of each node which has already been computed
+    values: Vec<Vec<IrValue>>,
+    /// Parameter name to offset in floats
+    param_offsets: HashMap<ExternInputId, usize>,
+    params_ptr: IrValue,
+    builtins: HashMap<&'static str, FuncRef>,
+}
+
+/// Fill in the body of the function in `ctx`, returning the output datatype
+fn build_function(
+    module: &mut JITModule,
+    ctx: &mut Context,
+    graph: &Graph,
+    params: &ParameterList,
+) -> Result<DataType> {
+    let output_dtype = typecheck::typecheck(graph, params).map_err(EvalError::TypeCheck)?;
+
+    let mut builtins = HashMap::new();
+    for (name, n_args) in builtin_signatures() {
+        let mut sig = module.make_signature();
+        sig.params
+            .extend((0..n_args).map(|_| AbiParam::new(types::F32)));
+        sig.returns.push(AbiParam::new(types::F32));
+        let func_id = module.declare_function(name, Linkage::Import, &sig)?;
+        builtins.insert(name, module.declare_func_in_func(func_id, &mut ctx.func));
+    }
+
+    let mut param_offsets = HashMap::new();
+    let mut offset = 0;
+    for (id, dtype) in params.inputs() {
+        param_offsets.insert(id.clone(), offset);
+        offset += dtype.n_lanes();
+    }
+
+    let mut fn_builder_ctx = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut ctx.func, &mut fn_builder_ctx);
+    let block = builder.create_block();
+    builder.append_block_params_for_function_params(block);
+    builder.switch_to_block(block);
+    builder.seal_block(block);
+    let params_ptr = builder.block_params(block)[0];
+    let out_ptr = builder.block_params(block)[1];
+
+    let mut codegen = Codegen {
+        builder,
+        values: Vec::with_capacity(graph.len()),
+        param_offsets,
+        params_ptr,
+        builtins,
+    };
+
+    for (_, node) in graph.iter() {
+        let lanes = codegen.compile_node(node);
+        codegen.values.push(lanes);
+    }
+
+    // Write to output pointer
+    for (idx, lane) in codegen.values[graph.root().index()]
+        .clone()
+        .into_iter()
+        .enumerate()
+    {
+        let offset = (idx * 4) as i32; // f32 is 4 bytes
+        codegen
+            .builder
+            .ins()
+            .store(MemFlags::trusted(), lane, out_ptr, offset);
+    }
+    codegen.builder.ins().return_(&[]);
+    codegen.builder.finalize();
+
+    Ok(output_dtype)
+}
+
+impl Codegen<'_> {
+    /// Get the lanes of a node which has already been computed
+    fn value(&self, id: &NodeId) -> Vec<IrValue> {
+        self.values[id.index()].clone()
+    }
+
+    // Nodes are visited in topological order, so that inputs are computed before outputs. The
+    // graph has already been typechecked at this point.
+    fn compile_node(&mut self, node: &NodeKind) -> Vec<IrValue> {
+        match node {
+            NodeKind::ExternInput(id, dtype) => {
+                let offset = self.param_offsets[id];
+                (0..dtype.n_lanes())
+                    .map(|lane| {
+                        let offset = ((offset + lane) * 4) as i32;
+                        self.builder.ins().load(
+                            types::F32,
+                            MemFlags::trusted(),
+                            self.params_ptr,
+                            offset,
+                        )
+                    })
+                    .collect()
+            }
+            NodeKind::Constant(value) => value
+                .iter_vector_floats()
+                .map(|float| self.builder.ins().f32const(float))
+                .collect(),
+            NodeKind::Make(sub_nodes, _) => sub_nodes
+                .iter()
+                .map(|sub_node| self.value(sub_node)[0])
+                .collect(),
+            NodeKind::ComponentInfixOp(a, infix, b) => self
+                .value(a)
+                .into_iter()
+                .zip(self.value(b))
+                .map(|(a, b)| self.infix_op(a, *infix, b))
+                .collect(),
+            NodeKind::ComponentFn(func, a) => self
+                .value(a)
+                .into_iter()
+                .map(|a| self.component_fn(*func, a))
+                .collect(),
+            NodeKind::GetComponent(vector, index) => {
+                let vector = self.value(vector);
+                let index = self.value(index);
+
+                // Select the highest lane whose index is not greater than the floored index
+                let index = self.builder.ins().floor(index[0]);
+                let mut out = vector[0];
+                for (i, lane) in vector.iter().enumerate().skip(1) {
+                    let lane_idx = self.builder.ins().f32const(i as f32);
+                    let cond =
+                        self.builder
+                            .ins()
+                            .fcmp(FloatCC::GreaterThanOrEqual, index, lane_idx);
+                    out = self.builder.ins().select(cond, *lane, out);
+                }
+                vec![out]
+            }
+            NodeKind::Dot(a, b) => {
+                let mut sum = None;
+                for (a, b) in self.value(a).into_iter().zip(self.value(b)) {
+                    let product = self.builder.ins().fmul(a, b);
+                    sum = Some(match sum {
+                        Some(sum) => self.builder.ins().fadd(sum, product),
+                        None => product,
+                    });
+                }
+                vec![sum.unwrap()]
+            }
+        }
+    }
+
+    fn infix_op(&mut self, a: IrValue, infix: ComponentInfixOp, b: IrValue) -> IrValue {
+        let compare = |codegen: &mut Self, cc: FloatCC| {
+            let cond = codegen.builder.ins().fcmp(cc, a, b);
+            let one = codegen.builder.ins().f32const(1.0);
+            let zero = codegen.builder.ins().f32const(0.0);
+            codegen.builder.ins().select(cond, one, zero)
+        };
+
+        match infix {
+            ComponentInfixOp::Add => self.builder.ins().fadd(a, b),
+            ComponentInfixOp::Subtract => self.builder.ins().fsub(a, b),
+            ComponentInfixOp::Multiply => self.builder.ins().fmul(a, b),
+            ComponentInfixOp::Divide => self.builder.ins().fdiv(a, b),
+            ComponentInfixOp::Power => self.call_builtin("vorpal_power", &[a, b]),
+            ComponentInfixOp::Logbase => self.call_builtin("vorpal_logbase", &[a, b]),
+            ComponentInfixOp::GreaterThan => compare(self, FloatCC::GreaterThan),
+            ComponentInfixOp::LessThan => compare(self, FloatCC::LessThan),
+            ComponentInfixOp::EqualTo => compare(self, FloatCC::Equal),
+        }
+    }
+
+    fn component_fn(&mut self, func: ComponentFn, a: IrValue) -> IrValue {
+        match func {
+            ComponentFn::Ceil => self.builder.ins().ceil(a),
+            ComponentFn::Floor => self.builder.ins().floor(a),
+            ComponentFn::Abs => self.builder.ins().fabs(a),
+            ComponentFn::Sine => self.call_builtin("vorpal_sine", &[a]),
+            ComponentFn::Cosine => self.call_builtin("vorpal_cosine", &[a]),
+            ComponentFn::Tangent => self.call_builtin("vorpal_tangent", &[a]),
+            ComponentFn::NaturalLog => self.call_builtin("vorpal_natural_log", &[a]),
+            ComponentFn::NaturalExp => self.call_builtin("vorpal_natural_exp", &[a]),
+        }
+    }
+
+    fn call_builtin(&mut self, name: &str, args: &[IrValue]) -> IrValue {
+        let func_ref = self.builtins[name];
+        let call = self.builder.ins().call(func_ref, args);
+        self.builder.inst_results(call)[0]
+    }
+}
+
+/// Name and number of arguments of each builtin function
+fn builtin_signatures() -> [(&'static str, usize); 7] {
+    [
+        ("vorpal_power", 2),
+        ("vorpal_logbase", 2),
+        ("vorpal_sine", 1),
+        ("vorpal_cosine", 1),
+        ("vorpal_tangent", 1),
+        ("vorpal_natural_log", 1),
+        ("vorpal_natural_exp", 1),
+    ]
+}
+
+/// Name and address of each builtin function
+fn builtin_symbols() -> [(&'static str, *const u8); 7] {
+    [
+        ("vorpal_power", power as *const u8),
+        ("vorpal_logbase", logbase as *const u8),
+        ("vorpal_sine", sine as *const u8),
+        ("vorpal_cosine", cosine as *const u8),
+        ("vorpal_tangent", tangent as *const u8),
+        ("vorpal_natural_log", natural_log as *const u8),
+        ("vorpal_natural_exp", natural_exp as *const u8),
+    ]
+}
+
+extern "C" fn power(base: f32, exponent: f32) -> f32 {
+    ComponentInfixOp::Power.native(base, exponent)
+}
+
+extern "C" fn logbase(value: f32, base: f32) -> f32 {
+    ComponentInfixOp::Logbase.native(value, base)
+}
+
+extern "C" fn sine(value: f32) -> f32 {
+    ComponentFn::Sine.native(value)
+}
+
+extern "C" fn cosine(value: f32) -> f32 {
+    ComponentFn::Cosine.native(value)
+}
+
+extern "C" fn tangent(value: f32) -> f32 {
+    ComponentFn::Tangent.native(value)
+}
+
+extern "C" fn natural_log(value: f32) -> f32 {
+    ComponentFn::NaturalLog.native(value)
+}
+
+extern "C" fn natural_exp(value: f32) -> f32 {
+    ComponentFn::NaturalExp.native(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use vorpal_test_support::{
+        assert_matches_native, component_and_dot_cases, component_fn_cases, infix_op_cases, input,
+        params, Case,
+    };
+
+    /// Compile each case and check it gives the same lanes as the interpreter
+    fn assert_cases_match_native(cases: &[Case]) {
+        for case in cases {
+            let (node, ctx) = case;
+            let graph = Graph::from_node(node);
+            let compiled = CompiledFn::new(&graph, &ctx.build_parameter_list()).unwrap();
+            let jit = compiled.call(ctx).unwrap();
+            let jit: Vec<f32> = jit.iter_vector_floats().collect();
+            assert_matches_native(case, &jit);
+        }
+    }
+
+    #[test]
+    fn component_fns_match_native() {
+        assert_cases_match_native(&component_fn_cases());
+    }
+
+    #[test]
+    fn infix_ops_match_native() {
+        assert_cases_match_native(&infix_op_cases());
+    }
+
+    #[test]
+    fn components_and_dot_match_native() {
+        assert_cases_match_native(&component_and_dot_cases());
+    }
+
+    #[test]
+    fn make_lanes_match_native() {
+        let v = Value::Vec4([1., 2., 3., 4.]);
+        let mut cases = vec![];
+        for dtype in DataType::all() {
+            // Every lane comes from a different component, so swapped lanes show up
+            let components = (0..dtype.n_lanes())
+                .map(|lane| {
+                    let index = Rc::new(Node::Constant(Value::Scalar(3. - lane as f32)));
+                    Rc::new(Node::GetComponent(input("v", DataType::Vec4), index))
+                })
+                .collect();
+            let make = Rc::new(Node::Make(components, dtype));
+            let node = Rc::new(Node::ComponentInfixOp(
+                make,
+                ComponentInfixOp::Multiply,
+                Rc::new(Node::Constant(match dtype {
+                    DataType::Scalar => Value::Scalar(2.),
+                    DataType::Vec2 => Value::Vec2([2., 3.]),
+                    DataType::Vec3 => Value::Vec3([2., 3., 4.]),
+                    DataType::Vec4 => Value::Vec4([2., 3., 4., 5.]),
+                })),
+            ));
+            cases.push((node, params(&[("v", v), ("s", Value::Scalar(-5.))])));
+        }
+        assert_cases_match_native(&cases);
+    }
+
+    #[test]
+    fn wrong_inputs_are_errors() {
+        let node = Rc::new(Node::ComponentFn(
+            ComponentFn::Abs,
+            input("x", DataType::Vec2),
+        ));
+        let graph = Graph::from_node(&node);
+        let ctx = params(&[("x", Value::Vec2([1., -1.]))]);
+        let compiled = CompiledFn::new(&graph, &ctx.build_parameter_list()).unwrap();
+        assert!(compiled.call_flat(&[1.]).is_err());
+        assert_eq!(
+            compiled.call_flat(&[1., -1.]).unwrap(),
+            Value::Vec2([1., 1.])
+        );
+        assert!(compiled.call(&params(&[("x", Value::Scalar(1.))])).is_err());
+        assert!(compiled.call(&params(&[])).is_err());
+    }
+}
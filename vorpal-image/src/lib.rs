@@ -1,26 +1,40 @@
 use std::cell::RefCell;
 
+mod host;
+
+/// Float type of the kernel's parameters and output, which must match the precision of the project
+#[cfg(not(feature = "f64"))]
+pub type Float = f32;
+#[cfg(feature = "f64")]
+pub type Float = f64;
+
+/// Tells the host how to call this plugin and which functions it imports
+#[link_section = "vorpal_manifest"]
+#[used]
+static MANIFEST: [u8; include_bytes!("../manifest.json").len()] =
+    *include_bytes!("../manifest.json");
+
 #[link(wasm_import_module = "kernel")]
 extern "C" {
     fn kernel(
-        ptr: *mut f32,
-        cursor_x: f32,
-        cursor_y: f32,
-        position_x: f32,
-        position_y: f32,
-        width: f32,
-        height: f32,
-        time: f32,
+        ptr: *mut Float,
+        cursor_x: Float,
+        cursor_y: Float,
+        position_x: Float,
+        position_y: Float,
+        width: Float,
+        height: Float,
+        time: Float,
     );
 }
 
 pub fn call_kernel(
-    resolution: [f32; 2],
-    position: [f32; 2],
-    time: f32,
-    cursor_pos: [f32; 2],
-) -> [f32; 4] {
-    let mut out_data = [0_f32; 4];
+    resolution: [Float; 2],
+    position: [Float; 2],
+    time: Float,
+    cursor_pos: [Float; 2],
+) -> [Float; 4] {
+    let mut out_data = [0.0; 4];
     let [width, height] = resolution;
     let [x, y] = position;
     let [cursor_x, cursor_y] = cursor_pos;
@@ -44,9 +58,9 @@ pub fn call_kernel(
 pub extern "C" fn make_image(
     width: u32,
     height: u32,
-    time: f32,
-    cursor_x: f32,
-    cursor_y: f32,
+    time: Float,
+    cursor_x: Float,
+    cursor_y: Float,
 ) -> *const f32 {
     thread_local! {
         static BUFFER: RefCell<Option<Plugin>> = RefCell::new(None);
@@ -54,7 +68,17 @@ pub extern "C" fn make_image(
 
     BUFFER.with(|buffer| {
         let mut maybe_plugin = buffer.borrow_mut();
-        let plugin = maybe_plugin.get_or_insert_with(|| Plugin::new(width, height));
+        let resized = maybe_plugin
+            .as_ref()
+            .is_none_or(|plugin| (plugin.out_width, plugin.out_height) != (width, height));
+        if resized {
+            host::log(&format!(
+                "vorpal-image: drawing {width}x{height} from frame {}",
+                host::frame_index()
+            ));
+            *maybe_plugin = Some(Plugin::new(width, height));
+        }
+        let plugin = maybe_plugin.as_mut().unwrap();
 
         plugin.get_image(time, cursor_x, cursor_y).as_ptr()
     })
@@ -75,20 +99,24 @@ impl Plugin {
         }
     }
 
-    pub fn get_image(&mut self, time: f32, cursor_x: f32, cursor_y: f32) -> &[f32] {
+    pub fn get_image(&mut self, time: Float, cursor_x: Float, cursor_y: Float) -> &[f32] {
         for y in 0..self.out_height {
             for x in 0..self.out_width {
-                let [sx, sy] = [x, y].map(|v| v as f32);
+                let [sx, sy] = [x, y].map(|v| v as Float);
 
                 let rgba = call_kernel(
-                    [self.out_width as f32, self.out_height as f32],
+                    [self.out_width as Float, self.out_height as Float],
                     [sx, sy],
                     time,
                     [cursor_x, cursor_y],
                 );
 
+                // The image is always displayed in single precision
                 let idx = 4 * (y * self.out_width + x) as usize;
-                self.out_rgba[idx..idx + 4].copy_from_slice(&rgba);
+                #[allow(clippy::unnecessary_cast)]
+                for (out, value) in self.out_rgba[idx..idx + 4].iter_mut().zip(rgba) {
+                    *out = value as f32;
+                }
             }
         }
 
@@ -0,0 +1,381 @@
+//! Content-addressed cache of compiled wasm modules, so that unchanged functions are not
+//! recompiled when another function is edited or the app is restarted.
+//!
+//! Loading a serialized module runs whatever machine code it contains, so modules are only ever
+//! loaded from a directory which belongs to the current user and no one else can write to. This
+//! needs unsafe code, so it is kept in its own crate and the app itself can forbid it.
+#![deny(unsafe_code)]
+use anyhow::{ensure, Result};
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use vorpal_core::{graph::Graph, ParameterList, Precision};
+use wasm_bridge::{Engine, Module};
+
+/// Compiled modules, kept in memory and on disk under the hash of whatever they were compiled from
+pub struct ModuleCache {
+    /// Where serialized modules are stored, if anywhere
+    dir: Option<PathBuf>,
+    loaded: HashMap<ModuleKey, Module>,
+    /// Keys requested since the last call to `forget_unused`
+    used: HashSet<ModuleKey>,
+}
+
+/// BLAKE3 hash of whatever a module was compiled from. Modules are looked up by key alone, so
+/// the hash must be cryptographic; otherwise a crafted plugin could share the key of another
+/// module, and have its compiled code run instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ModuleKey(pub [u8; 32]);
+
+/// Feeds anything implementing `Hash` into BLAKE3
+struct KeyHasher(blake3::Hasher);
+
+impl ModuleCache {
+    /// Store modules in `dir`, which is created readable only by the current user. Without a
+    /// directory, or if it belongs to someone else, modules are only cached in memory.
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        let dir = dir.filter(|dir| match prepare_dir(dir) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Not caching modules in {}: {:#}", dir.display(), e);
+                false
+            }
+        });
+
+        Self {
+            dir,
+            loaded: HashMap::new(),
+            used: HashSet::new(),
+        }
+    }
+
+    /// The platform's per-user cache directory, if there is one
+    pub fn default_dir() -> Option<PathBuf> {
+        let var = |name| std::env::var_os(name).map(PathBuf::from);
+        let cache_dir = if cfg!(windows) {
+            var("LOCALAPPDATA")
+        } else if cfg!(target_os = "macos") {
+            var("HOME").map(|home| home.join("Library").join("Caches"))
+        } else {
+            var("XDG_CACHE_HOME")
+                .filter(|dir| dir.is_absolute())
+                .or_else(|| var("HOME").map(|home| home.join(".cache")))
+        };
+        Some(cache_dir?.join("vorpal").join("modules"))
+    }
+
+    /// Load the module with the given key from memory or disk, or compile and store it
+    pub fn get_or_compile(
+        &mut self,
+        engine: &Engine,
+        key: ModuleKey,
+        compile: impl FnOnce() -> Result<Module>,
+    ) -> Result<Module> {
+        self.used.insert(key);
+        if let Some(module) = self.loaded.get(&key) {
+            return Ok(module.clone());
+        }
+
+        let module = match self.load(engine, key) {
+            Some(module) => module,
+            None => {
+                let module = compile()?;
+                self.store(key, &module);
+                module
+            }
+        };
+
+        self.loaded.insert(key, module.clone());
+        Ok(module)
+    }
+
+    /// Forget every module in memory which was not requested since the last call. They remain on
+    /// disk.
+    pub fn forget_unused(&mut self) {
+        let used = std::mem::take(&mut self.used);
+        self.loaded.retain(|key, _| used.contains(key));
+    }
+
+    fn path(&self, key: ModuleKey) -> Option<PathBuf> {
+        Some(self.dir.as_ref()?.join(format!("{key}.cwasm")))
+    }
+
+    fn load(&self, engine: &Engine, key: ModuleKey) -> Option<Module> {
+        let path = self.path(key).filter(|path| path.exists())?;
+        if let Err(e) = check_owner(&path, false) {
+            eprintln!("Ignoring cached module {}: {:#}", path.display(), e);
+            return None;
+        }
+
+        // Safety: Only the current user can write to the cache directory, where files are only
+        // ever written by `store`, from modules wasmtime compiled. Wasmtime rejects modules from
+        // other versions or configurations.
+        #[allow(unsafe_code)]
+        let module = unsafe { Module::deserialize_file(engine, &path) };
+
+        module
+            .map_err(|e| eprintln!("Ignoring cached module {}: {:#}", path.display(), e))
+            .ok()
+    }
+
+    fn store(&self, key: ModuleKey, module: &Module) {
+        let Some(path) = self.path(key) else {
+            return;
+        };
+
+        // Written to a temporary file first, so that a partially written module is never loaded
+        let tmp_path = path.with_extension("tmp");
+        let result = module.serialize().and_then(|bytes| {
+            std::fs::write(&tmp_path, bytes)?;
+            std::fs::rename(&tmp_path, &path)?;
+            Ok(())
+        });
+
+        if let Err(e) = result {
+            eprintln!("Failed to cache module {}: {:#}", path.display(), e);
+        }
+    }
+}
+
+/// Create the cache directory with permissions for the current user only, or check that an
+/// existing one has them
+#[cfg(unix)]
+fn prepare_dir(dir: &Path) -> Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)?;
+    check_owner(dir, true)?;
+    std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    Ok(())
+}
+
+/// Per-user directories are already private on other platforms
+#[cfg(not(unix))]
+fn prepare_dir(dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    Ok(())
+}
+
+/// Check that `path` is a directory or regular file, not a link, and belongs to the current user
+#[cfg(unix)]
+fn check_owner(path: &Path, is_dir: bool) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = std::fs::symlink_metadata(path)?;
+    let kind = if is_dir { "directory" } else { "file" };
+    let is_kind = if is_dir {
+        metadata.is_dir()
+    } else {
+        metadata.is_file()
+    };
+    ensure!(is_kind, "Not a {kind}");
+
+    // Safety: getuid has no preconditions and cannot fail
+    #[allow(unsafe_code)]
+    let uid = unsafe { libc::getuid() };
+    ensure!(metadata.uid() == uid, "The {kind} belongs to another user");
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_owner(_path: &Path, _is_dir: bool) -> Result<()> {
+    Ok(())
+}
+
+/// Key of the module compiled from a function
+pub fn function_key(
+    graph: &Graph,
+    params: &ParameterList,
+    func_name: &str,
+    precision: Precision,
+) -> ModuleKey {
+    let mut hasher = KeyHasher::new("function");
+    // Code generation may change between versions
+    vorpal_wasm::VERSION.hash(&mut hasher);
+    graph.hash(&mut hasher);
+    params.hash(&mut hasher);
+    func_name.hash(&mut hasher);
+    precision.hash(&mut hasher);
+    hasher.key()
+}
+
+/// Key of the module compiled from a wasm binary
+pub fn bytes_key(bytes: &[u8]) -> ModuleKey {
+    let mut hasher = KeyHasher::new("bytes");
+    hasher.0.update(bytes);
+    hasher.key()
+}
+
+impl std::fmt::Display for ModuleKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl KeyHasher {
+    /// Keys of different kinds of sources never collide
+    fn new(kind: &str) -> Self {
+        let mut hasher = Self(blake3::Hasher::new());
+        kind.hash(&mut hasher);
+        hasher
+    }
+
+    fn key(&self) -> ModuleKey {
+        ModuleKey(*self.0.finalize().as_bytes())
+    }
+}
+
+impl Hasher for KeyHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    /// Only for `Hasher`; use `key` for the whole hash
+    fn finish(&self) -> u64 {
+        let bytes = self.key().0;
+        u64::from_le_bytes(bytes[..8].try_into().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::format_err;
+    use vorpal_core::graph::{GraphBuilder, NodeKind};
+    use vorpal_core::Value;
+
+    /// An empty directory, removed when dropped
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir()
+                .join(format!("vorpal-module-cache-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+
+        fn files(&self) -> Vec<String> {
+            let mut files: Vec<String> = std::fs::read_dir(&self.0)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            files.sort();
+            files
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn compile(engine: &Engine) -> Result<Module> {
+        Module::new(
+            engine,
+            r#"(module (func (export "f") (result i32) i32.const 7))"#,
+        )
+    }
+
+    fn not_compiled() -> Result<Module> {
+        Err(format_err!("Compiled instead of loading from the cache"))
+    }
+
+    #[test]
+    fn modules_round_trip_through_the_directory() {
+        let dir = TestDir::new("round-trip");
+        let engine = Engine::default();
+        let key = bytes_key(b"round trip");
+
+        let mut cache = ModuleCache::new(Some(dir.0.clone()));
+        cache
+            .get_or_compile(&engine, key, || compile(&engine))
+            .unwrap();
+        // Kept in memory, even once it is unused
+        cache.get_or_compile(&engine, key, not_compiled).unwrap();
+
+        let mut cache = ModuleCache::new(Some(dir.0.clone()));
+        let module = cache.get_or_compile(&engine, key, not_compiled).unwrap();
+        let exports: Vec<&str> = module.exports().map(|export| export.name()).collect();
+        assert_eq!(exports, ["f"]);
+
+        // Other keys are compiled
+        let other = bytes_key(b"other");
+        assert!(cache.get_or_compile(&engine, other, not_compiled).is_err());
+    }
+
+    #[test]
+    fn modules_are_stored_through_a_temporary_file() {
+        let dir = TestDir::new("store");
+        let engine = Engine::default();
+        let key = bytes_key(b"store");
+
+        // Left over from an interrupted store, and never loaded
+        let cache = ModuleCache::new(Some(dir.0.clone()));
+        let path = cache.path(key).unwrap();
+        std::fs::write(path.with_extension("tmp"), b"partial").unwrap();
+
+        let mut cache = cache;
+        cache
+            .get_or_compile(&engine, key, || compile(&engine))
+            .unwrap();
+        assert_eq!(dir.files(), [format!("{key}.cwasm")]);
+    }
+
+    #[test]
+    fn keys_depend_on_the_kind_of_source() {
+        assert_eq!(bytes_key(b"a"), bytes_key(b"a"));
+        assert_ne!(bytes_key(b"a"), bytes_key(b"b"));
+
+        let mut builder = GraphBuilder::new();
+        let root = builder.push(NodeKind::Constant(Value::Scalar(1.)));
+        let graph = builder.finish(root);
+        let params = ParameterList(vec![]);
+        let key = function_key(&graph, &params, "f", Precision::Single);
+        assert_eq!(key, function_key(&graph, &params, "f", Precision::Single));
+        assert_ne!(key, function_key(&graph, &params, "g", Precision::Single));
+        assert_ne!(key, function_key(&graph, &params, "f", Precision::Double));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn foreign_files_are_rejected() {
+        let dir = TestDir::new("owner");
+        let engine = Engine::default();
+        let key = bytes_key(b"owner");
+
+        let mut cache = ModuleCache::new(Some(dir.0.clone()));
+        cache
+            .get_or_compile(&engine, key, || compile(&engine))
+            .unwrap();
+        let path = cache.path(key).unwrap();
+        assert!(check_owner(&path, false).is_ok());
+
+        // A link to a module is not loaded, even one which belongs to the current user
+        let target = path.with_extension("target");
+        std::fs::rename(&path, &target).unwrap();
+        std::os::unix::fs::symlink(&target, &path).unwrap();
+        assert!(check_owner(&path, false).is_err());
+        let mut cache = ModuleCache::new(Some(dir.0.clone()));
+        assert!(cache.get_or_compile(&engine, key, not_compiled).is_err());
+
+        // Only root can give a file to another user
+        std::fs::remove_file(&path).unwrap();
+        std::fs::rename(&target, &path).unwrap();
+        let Ok(metadata) = std::fs::metadata(&path) else {
+            return;
+        };
+        use std::os::unix::fs::MetadataExt;
+        if std::os::unix::fs::chown(&path, Some(metadata.uid() + 1), None).is_err() {
+            return;
+        }
+        assert!(check_owner(&path, false).is_err());
+        let mut cache = ModuleCache::new(Some(dir.0.clone()));
+        assert!(cache.get_or_compile(&engine, key, not_compiled).is_err());
+    }
+}
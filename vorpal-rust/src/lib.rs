@@ -0,0 +1,330 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt::Write;
+use vorpal_core::graph::{Graph, NodeId, NodeKind};
+use vorpal_core::*;
+
+/// Generate the source of a plain Rust function which evaluates the graph. The parameters are the
+/// lanes of each input, in the order of the parameter list and named just as in
+/// `CodeAnalysis::func_name_rust`. The output lanes are returned as an array. The function is
+/// named `sanitize_identifier(func_name)`.
+pub fn compile_to_rust(graph: &Graph, params: &ParameterList, func_name: &str) -> Result<String> {
+    compile_to_rust_with_precision(graph, params, func_name, Precision::Single)
+}
+
+/// The same as `compile_to_rust`, but computing in the float type of `precision`. Constants are
+/// widened exactly, as in the other backends.
+pub fn compile_to_rust_with_precision(
+    graph: &Graph,
+    params: &ParameterList,
+    func_name: &str,
+    precision: Precision,
+) -> Result<String> {
+    let float = precision.type_name();
+    let dtypes = typecheck::infer_dtypes(graph, params).map_err(EvalError::TypeCheck)?;
+    let output_dtype = dtypes[graph.root().index()];
+    let func_name = sanitize_identifier(func_name);
+    let names: HashMap<&ExternInputId, String> = params
+        .inputs()
+        .iter()
+        .map(|(id, _)| id)
+        .zip(params.identifiers())
+        .collect();
+
+    let mut text = String::new();
+
+    // Not every parameter needs to be used by the graph
+    writeln!(
+        &mut text,
+        "#[allow(unused_variables, clippy::too_many_arguments, clippy::let_and_return)]"
+    )?;
+    writeln!(&mut text, "pub fn {func_name}(")?;
+    for lane in parameter_names(params) {
+        writeln!(&mut text, "    {lane}: {float},")?;
+    }
+    writeln!(&mut text, ") -> [{float}; {}] {{", output_dtype.n_lanes())?;
+
+    // Every node is computed once, in topological order, as an array of its lanes
+    for (node_id, node) in graph.iter() {
+        let lanes = compile_node(node, &dtypes, &names, precision).join(", ");
+        writeln!(&mut text, "    let {} = [{}];", local(node_id), lanes)?;
+    }
+
+    writeln!(&mut text, "    {}", local(graph.root()))?;
+    writeln!(&mut text, "}}")?;
+
+    Ok(text)
+}
+
+/// Names of the parameters of the generated function, one for each lane of each input
+pub fn parameter_names(params: &ParameterList) -> Vec<String> {
+    params
+        .inputs()
+        .iter()
+        .zip(params.identifiers())
+        .flat_map(|((_, input_dtype), name)| lane_params(&name, *input_dtype))
+        .collect()
+}
+
+/// Expression for each lane of the node's output
+fn compile_node(
+    node: &NodeKind,
+    dtypes: &[DataType],
+    names: &HashMap<&ExternInputId, String>,
+    precision: Precision,
+) -> Vec<String> {
+    let lanes_of = |id: &NodeId| -> Vec<String> {
+        (0..dtypes[id.index()].n_lanes())
+            .map(|lane| format!("{}[{lane}]", local(*id)))
+            .collect()
+    };
+
+    match node {
+        NodeKind::ExternInput(name, dtype) => lane_params(&names[name], *dtype),
+        NodeKind::Constant(value) => value
+            .iter_vector_floats()
+            .map(|value| float_literal(value, precision))
+            .collect(),
+        NodeKind::Make(components, _) => components
+            .iter()
+            .map(|component| format!("{}[0]", local(*component)))
+            .collect(),
+        NodeKind::ComponentInfixOp(a, op, b) => lanes_of(a)
+            .into_iter()
+            .zip(lanes_of(b))
+            .map(|(a, b)| infix_expr(*op, &a, &b, precision))
+            .collect(),
+        NodeKind::ComponentFn(func, a) => lanes_of(a)
+            .into_iter()
+            .map(|a| format!("{a}.{}()", fn_method(*func)))
+            .collect(),
+        NodeKind::GetComponent(vector, index) => {
+            // Matches the native backend; out of range indices are clamped
+            let n_lanes = dtypes[vector.index()].n_lanes();
+            vec![format!(
+                "{vector}[({index}[0].clamp(0.0, {n_lanes}.0) as usize).min({})]",
+                n_lanes - 1,
+                vector = local(*vector),
+                index = local(*index),
+            )]
+        }
+        NodeKind::Dot(a, b) => {
+            let products: Vec<String> = lanes_of(a)
+                .into_iter()
+                .zip(lanes_of(b))
+                .map(|(a, b)| format!("{a} * {b}"))
+                .collect();
+            vec![products.join(" + ")]
+        }
+    }
+}
+
+/// Parameter name of each lane of an input
+fn lane_params(name: &str, dtype: DataType) -> Vec<String> {
+    if dtype.n_lanes() == 1 {
+        vec![name.to_string()]
+    } else {
+        dtype
+            .lane_names()
+            .map(|lane| format!("{name}_{lane}"))
+            .collect()
+    }
+}
+
+fn local(id: NodeId) -> String {
+    format!("node_{}", id.index())
+}
+
+fn infix_expr(op: ComponentInfixOp, a: &str, b: &str, precision: Precision) -> String {
+    let float = precision.type_name();
+    match op {
+        ComponentInfixOp::Add => format!("{a} + {b}"),
+        ComponentInfixOp::Subtract => format!("{a} - {b}"),
+        ComponentInfixOp::Multiply => format!("{a} * {b}"),
+        ComponentInfixOp::Divide => format!("{a} / {b}"),
+        ComponentInfixOp::Power => format!("{a}.powf({b})"),
+        ComponentInfixOp::Logbase => format!("{a}.log({b})"),
+        ComponentInfixOp::GreaterThan => format!("{float}::from({a} > {b})"),
+        ComponentInfixOp::LessThan => format!("{float}::from({a} < {b})"),
+        ComponentInfixOp::EqualTo => format!("{float}::from({a} == {b})"),
+    }
+}
+
+/// Name of the float method matching `ComponentFn::native`
+fn fn_method(func: ComponentFn) -> &'static str {
+    match func {
+        ComponentFn::Cosine => "cos",
+        ComponentFn::Sine => "sin",
+        ComponentFn::Tangent => "tan",
+        ComponentFn::NaturalLog => "ln",
+        ComponentFn::NaturalExp => "exp",
+        ComponentFn::Ceil => "ceil",
+        ComponentFn::Floor => "floor",
+        ComponentFn::Abs => "abs",
+    }
+}
+
+/// A literal in the float type of `precision` which round-trips exactly, including values which
+/// have no literal syntax
+fn float_literal(value: f32, precision: Precision) -> String {
+    let float = precision.type_name();
+    if value.is_nan() {
+        format!("{float}::NAN")
+    } else if value == f32::INFINITY {
+        format!("{float}::INFINITY")
+    } else if value == f32::NEG_INFINITY {
+        format!("{float}::NEG_INFINITY")
+    } else {
+        match precision {
+            Precision::Single => format!("{value:?}_f32"),
+            Precision::Double => format!("{:?}_f64", f64::from(value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use vorpal_core::native_backend::Program;
+    use vorpal_test_support::{
+        assert_matches_native, awkward_names_case, component_and_dot_cases, component_fn_cases,
+        infix_op_cases, Case,
+    };
+
+    /// Compile every case into one program in the given precision, built as a crate with cargo,
+    /// and return the bits of the lanes of each call
+    fn run_cases(
+        test_name: &str,
+        func_name: &str,
+        cases: &[Case],
+        precision: Precision,
+    ) -> Vec<Vec<u64>> {
+        let float = precision.type_name();
+        let mut program = String::new();
+        let mut main = String::new();
+        for (idx, (node, ctx)) in cases.iter().enumerate() {
+            let graph = Graph::from_node(node);
+            let params = ctx.build_parameter_list();
+            let func_name = format!("{func_name}{idx}");
+            let source =
+                compile_to_rust_with_precision(&graph, &params, &func_name, precision).unwrap();
+            writeln!(&mut program, "mod case_{idx} {{\n{source}}}").unwrap();
+
+            let args = params
+                .inputs()
+                .iter()
+                .flat_map(|(id, _)| ctx.inputs()[id].iter_vector_floats())
+                .map(|arg| match precision {
+                    Precision::Single => format!("f32::from_bits({:#x})", arg.to_bits()),
+                    Precision::Double => format!("f64::from_bits({:#x})", f64::from(arg).to_bits()),
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            writeln!(
+                &mut main,
+                "    println!(\"{{:?}}\", case_{idx}::{}({args}).map({float}::to_bits));",
+                sanitize_identifier(&func_name)
+            )
+            .unwrap();
+        }
+        writeln!(&mut program, "fn main() {{\n{main}}}").unwrap();
+
+        let dir =
+            std::env::temp_dir().join(format!("vorpal-rust-{test_name}-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        // The empty workspace keeps the crate out of any workspace around the temp dir
+        let manifest = r#"[package]
+name = "generated"
+version = "0.0.0"
+edition = "2021"
+
+[workspace]
+"#;
+        std::fs::write(dir.join("Cargo.toml"), manifest).unwrap();
+        std::fs::write(dir.join("src").join("main.rs"), &program).unwrap();
+        let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+        let output = Command::new(cargo)
+            .current_dir(&dir)
+            .args(["run", "--quiet", "--offline"])
+            .output()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(
+            output.status.success(),
+            "Generated source failed to build:\n{}\n{program}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(stdout.lines().count(), cases.len());
+        stdout
+            .lines()
+            .map(|line| {
+                line.trim_matches(['[', ']'].as_slice())
+                    .split(", ")
+                    .map(|bits| bits.parse().unwrap())
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Check that each call gives the same lanes as the interpreter
+    fn assert_cases_match_native(test_name: &str, func_name: &str, cases: &[Case]) {
+        let outputs = run_cases(test_name, func_name, cases, Precision::Single);
+        for (case, bits) in cases.iter().zip(outputs) {
+            let generated: Vec<f32> = bits
+                .iter()
+                .map(|bits| f32::from_bits(*bits as u32))
+                .collect();
+            assert_matches_native(case, &generated);
+        }
+    }
+
+    #[test]
+    fn component_fns_match_native() {
+        assert_cases_match_native("component_fns", "f", &component_fn_cases());
+    }
+
+    #[test]
+    fn infix_ops_match_native() {
+        assert_cases_match_native("infix_ops", "f", &infix_op_cases());
+    }
+
+    #[test]
+    fn components_and_dot_match_native() {
+        assert_cases_match_native("components", "f", &component_and_dot_cases());
+    }
+
+    #[test]
+    fn awkward_names_compile() {
+        assert_cases_match_native("names", "fn ", &[awkward_names_case()]);
+    }
+
+    #[test]
+    fn double_precision_matches_native() {
+        let mut cases = infix_op_cases();
+        cases.extend(component_fn_cases());
+        let outputs = run_cases("double", "f", &cases, Precision::Double);
+        for ((node, ctx), bits) in cases.iter().zip(outputs) {
+            let native = Program::new(&Graph::from_node(node), &ctx.build_parameter_list())
+                .unwrap()
+                .evaluate_batch::<f64>(ctx, &HashMap::new())
+                .unwrap();
+            assert_eq!(bits.len(), native.data().len(), "{:?}", node);
+            for (generated, native) in bits
+                .iter()
+                .map(|bits| f64::from_bits(*bits))
+                .zip(native.data())
+            {
+                assert!(
+                    generated == *native || (generated.is_nan() && native.is_nan()),
+                    "{:?}: {} != {}",
+                    node,
+                    generated,
+                    native
+                );
+            }
+        }
+    }
+}
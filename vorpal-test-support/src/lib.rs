@@ -0,0 +1,134 @@
+//! Cases shared by the tests of every backend, which check that generated code gives the same
+//! results as `vorpal_core::native_backend`
+use std::rc::Rc;
+use vorpal_core::native_backend::evaluate_node;
+use vorpal_core::{
+    graph::Graph, ComponentFn, ComponentInfixOp, DataType, ExternInputId, ExternParameters, Node,
+    Value,
+};
+
+/// A node to evaluate, and the inputs to evaluate it with
+pub type Case = (Rc<Node>, ExternParameters);
+
+/// Inputs of the component functions and infix operators, including zero, negatives and
+/// fractions
+pub const SAMPLES: [f32; 8] = [-2.5, -1., -0.3, 0., 0.5, 1., 2., 7.25];
+
+pub fn input(name: &str, dtype: DataType) -> Rc<Node> {
+    Rc::new(Node::ExternInput(ExternInputId::new(name.into()), dtype))
+}
+
+pub fn params(values: &[(&str, Value)]) -> ExternParameters {
+    ExternParameters::new(
+        values
+            .iter()
+            .map(|(name, value)| (ExternInputId::new(name.to_string()), *value))
+            .collect(),
+    )
+}
+
+/// Check that the lanes a backend generated are those of the interpreter, with NaNs equal
+#[track_caller]
+pub fn assert_matches_native(case: &Case, generated: &[f32]) {
+    let (node, ctx) = case;
+    let native = evaluate_node(&Graph::from_node(node), ctx).unwrap();
+    let native: Vec<f32> = native.iter_vector_floats().collect();
+    assert_eq!(generated.len(), native.len(), "{:?}", node);
+    for (generated, native) in generated.iter().zip(native) {
+        assert!(
+            *generated == native || (generated.is_nan() && native.is_nan()),
+            "{:?}: {} != {}",
+            node,
+            generated,
+            native
+        );
+    }
+}
+
+/// Every component function of every sample
+pub fn component_fn_cases() -> Vec<Case> {
+    let mut cases = vec![];
+    for func in ComponentFn::all() {
+        let node = Rc::new(Node::ComponentFn(func, input("x", DataType::Vec4)));
+        for x in SAMPLES.chunks(4) {
+            let x = Value::Vec4([x[0], x[1], x[2], x[3]]);
+            cases.push((node.clone(), params(&[("x", x)])));
+        }
+    }
+    cases
+}
+
+/// Every infix operator of every pair of samples
+pub fn infix_op_cases() -> Vec<Case> {
+    let mut cases = vec![];
+    for op in ComponentInfixOp::all() {
+        let node = Rc::new(Node::ComponentInfixOp(
+            input("a", DataType::Vec4),
+            op,
+            input("b", DataType::Vec4),
+        ));
+        // Every pair of samples, four at a time
+        let pairs: Vec<(f32, f32)> = SAMPLES
+            .iter()
+            .flat_map(|a| SAMPLES.iter().map(move |b| (*a, *b)))
+            .collect();
+        for pairs in pairs.chunks(4) {
+            let a = Value::Vec4(std::array::from_fn(|lane| pairs[lane].0));
+            let b = Value::Vec4(std::array::from_fn(|lane| pairs[lane].1));
+            cases.push((node.clone(), params(&[("a", a), ("b", b)])));
+        }
+    }
+    cases
+}
+
+/// Component indices in and out of range, and a dot product of a vector made from components
+pub fn component_and_dot_cases() -> Vec<Case> {
+    let v = Value::Vec4([1.5, -2., 0.25, 9.]);
+    let mut cases = vec![];
+    for index in [-1., 0., 1.7, 3., 4., 100., f32::NAN] {
+        let index = Rc::new(Node::Constant(Value::Scalar(index)));
+        let node = Rc::new(Node::GetComponent(input("v", DataType::Vec4), index));
+        cases.push((node, params(&[("v", v)])));
+    }
+    let components = [3., 1., 0.]
+        .map(|index| {
+            let index = Rc::new(Node::Constant(Value::Scalar(index)));
+            Rc::new(Node::GetComponent(input("v", DataType::Vec4), index))
+        })
+        .to_vec();
+    let make = Rc::new(Node::Make(components, DataType::Vec3));
+    let node = Rc::new(Node::Dot(make, input("w", DataType::Vec3)));
+    let w = Value::Vec3([4., 3., -8.]);
+    cases.push((node, params(&[("v", v), ("w", w)])));
+    cases
+}
+
+/// Inputs named with keywords of the generated languages, names of functions they call, and
+/// names which are not identifiers or which collide once sanitized
+pub fn awkward_names_case() -> Case {
+    let names = [
+        "type", "fn", "int", "powf", "1x", "", "a b", "a_b", "node 0", "out ptr",
+    ];
+    let product = names
+        .iter()
+        .map(|name| input(name, DataType::Scalar))
+        .reduce(|a, b| Rc::new(Node::ComponentInfixOp(a, ComponentInfixOp::Power, b)))
+        .unwrap();
+    // Lanes of a vector input are named `{name}_x` and so on
+    let node = Rc::new(Node::ComponentInfixOp(
+        product,
+        ComponentInfixOp::Multiply,
+        Rc::new(Node::Dot(
+            input("a", DataType::Vec2),
+            input("a x", DataType::Vec2),
+        )),
+    ));
+    let mut values: Vec<(&str, Value)> = names
+        .iter()
+        .enumerate()
+        .map(|(idx, name)| (*name, Value::Scalar(idx as f32 * 0.25)))
+        .collect();
+    values.push(("a", Value::Vec2([2., 3.])));
+    values.push(("a x", Value::Vec2([5., 7.])));
+    (node, params(&values))
+}
@@ -1,22 +1,43 @@
 use std::{
     collections::HashMap,
     path::{Path, PathBuf},
-    time::Instant,
+    rc::Rc,
+    time::{Duration, Instant},
 };
 
 use anyhow::format_err;
 
 use eframe::{
-    egui::{self, ComboBox, Label, Layout, RichText, ScrollArea, TextEdit, Ui},
+    egui::{self, ComboBox, DragValue, Label, Layout, RichText, ScrollArea, TextEdit, Ui},
     epaint::Color32,
 };
 use ndarray::*;
-use vorpal_core::{ndarray, DataType, ExternInputId, ExternParameters, ParameterList, Value, Vec2, highlevel};
+use vorpal_core::{
+    graph::{self, Graph},
+    native_backend, ndarray,
+    range_analysis::{self, Interval, ParameterBounds},
+    typecheck, DataType, EvalError, ExternInputId, ExternParameters, Float, ParameterList,
+    Precision, Value, Vec2,
+};
 
-use vorpal_ui::wasmtime_integration::{NodeGraphs, VorpalWasmtime};
+use vorpal_ui::audio::{audio_fn_inputs, AudioSamples, AudioSettings};
+use vorpal_ui::curves::{plot_fn_inputs, PlotSettings};
+use vorpal_ui::manifest::ProblemKind;
+use vorpal_ui::mesh::{mesh_fn_inputs, Mesh, MeshSettings};
+use vorpal_ui::particles::{particle_fn_inputs, ParticleSettings, Particles};
+use vorpal_ui::wasi::{WasiConfig, PROJECT_GUEST_DIR};
+use vorpal_ui::wasmtime_integration::{
+    FunctionError, NodeGraphs, Snapshot, TimeLimitExceeded, VorpalWasmtime,
+};
+use vorpal_ui::worker::{
+    AudioRequest, EvalWorker, FrameRequest, RenderedAudio, SnapshotPurpose, WorkerEvent,
+};
+use vorpal_wasm::CodeAnalysis;
 use vorpal_widgets::{
     image_view::{array_to_imagedata, ImageViewWidget},
+    mesh_view::{MeshData, MeshViewWidget},
     node_editor::NodeGraphWidget,
+    plot,
 };
 
 type FuncName = String;
@@ -29,9 +50,45 @@ pub struct SaveState {
     functions: Vec<(FuncName, NodeGraphWidget)>,
     selected_function: usize,
     show_wat: bool,
+    /// Comment the WebAssembly text with the editor node each block came from
+    annotate_wat: bool,
     show_rust_decl: bool,
+    /// Show messages logged by the plugin
+    show_console: bool,
     pause: bool,
     focused: bool,
+    /// Float type every function is compiled to and evaluated in
+    precision: Precision,
+    /// How long the plugin may take to draw a frame before it is interrupted
+    time_limit_ms: u64,
+    /// Link the plugin with WASI
+    wasi: bool,
+    /// Directory the plugin can read through WASI. Only set by choosing it, or by confirming the
+    /// one in a loaded .vor file.
+    project_dir: Option<PathBuf>,
+    /// What the functions are evaluated as
+    mode: HostMode,
+    audio: AudioSettings,
+    mesh: MeshSettings,
+    particles: ParticleSettings,
+    plot: PlotSettings,
+}
+
+/// What the project's functions are evaluated as
+#[cfg_attr(feature = "persistence", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum HostMode {
+    /// Draw an image with the plugin, or with the selected function if there is none
+    #[default]
+    Image,
+    /// Render the selected function as audio, one sample at a time
+    Audio,
+    /// Place the vertices of a mesh with the selected function
+    Mesh,
+    /// Move particles with the acceleration given by the selected function, and draw them
+    Particles,
+    /// Plot the selected function and the overlaid ones over a range of x
+    Plot,
 }
 
 pub struct VorpalApp {
@@ -44,22 +101,90 @@ pub struct VorpalApp {
     time: Instant,
 
     autosave_timer: Instant,
-    engine: Option<VorpalWasmtime>,
+    engine: Option<EvalWorker>,
     single_step: bool,
 
     /// Cursor pos relative to the image size (in units of image's pixels)
     cursor_pos: Option<Vec2>,
     add_dtype: DataType,
     add_param: String,
+    /// Plugin state which "Reset" goes back to
+    checkpoint: Option<Snapshot>,
+    /// Why the plugin was stopped, if it was
+    plugin_error: Option<String>,
+    /// Messages logged by the plugin, oldest first
+    console: Vec<String>,
+    /// Project directory of a loaded .vor file, which the user has not yet allowed the plugin
+    /// to read
+    pending_project_dir: Option<PathBuf>,
+    analysis: Option<CachedAnalysis>,
+    audio: AudioPreview,
+    mesh: MeshPreview,
+    particles: Particles,
+    plot: PlotPreview,
 }
 
-const AUTOSAVE_INTERVAL_SECS: f32 = 30.0;
+/// Analysis of the selected function, and what it was made from
+struct CachedAnalysis {
+    graph: Graph,
+    params: ParameterList,
+    precision: Precision,
+    /// The error, if the graph does not typecheck
+    analysis: Result<Rc<CodeAnalysis>, String>,
+}
+
+/// Audio rendered from the selected function
+#[derive(Default)]
+struct AudioPreview {
+    /// Created once the audio mode is first used
+    worker: Option<EvalWorker>,
+    /// What was last sent to be rendered, which is rendered again once it changes
+    requested: Option<(NodeGraphs, AudioSettings, Precision)>,
+    /// Function being rendered, while the worker is busy
+    rendering: Option<usize>,
+    samples: AudioSamples,
+    /// Decibels of each frequency, from 0 Hz to half the sample rate
+    spectrum: Vec<f32>,
+}
+
+/// Mesh built from the selected function
+#[derive(Default)]
+struct MeshPreview {
+    /// What `mesh` was built from, which is built again once it changes
+    rendered: Option<(NodeGraphs, MeshSettings, Precision)>,
+    mesh: Mesh,
+    view: MeshViewWidget,
+}
+
+/// Curves of the plotted functions
+#[derive(Default)]
+struct PlotPreview {
+    /// What `curves` were sampled from, which are sampled again once it changes
+    rendered: Option<(NodeGraphs, PlotSettings, Precision)>,
+    /// Name of each function, and its value at each x
+    curves: Vec<(FuncName, Vec<f32>)>,
+}
 
+const AUTOSAVE_INTERVAL_SECS: f32 = 30.0;
+/// Older console messages are dropped
+const MAX_CONSOLE_LINES: usize = 1000;
+/// Largest image a plugin may ask for, in each dimension
+const MAX_RESOLUTION: u32 = 4096;
+/// Number of frequencies in the spectrum of the audio preview
+const SPECTRUM_BINS: usize = 1024;
+/// Height of each plot of the audio preview
+const AUDIO_PLOT_HEIGHT: f32 = 120.;
+/// Longest time the particles are stepped by in one frame, in seconds
+const MAX_PARTICLE_STEP: f32 = 0.1;
+/// Most values of x a function can be sampled at
+const MAX_PLOT_SAMPLES: u32 = 100_000;
+
+/// Parameters of the image function, in the order vorpal-image passes them
 fn image_fn_inputs() -> ParameterList {
     let params = [
         (
-            ExternInputId::new(vorpal_ui::TIME_KEY.to_string()),
-            DataType::Scalar,
+            ExternInputId::new(vorpal_ui::CURSOR_KEY.to_string()),
+            DataType::Vec2,
         ),
         (
             ExternInputId::new(vorpal_ui::POS_KEY.to_string()),
@@ -70,8 +195,8 @@ fn image_fn_inputs() -> ParameterList {
             DataType::Vec2,
         ),
         (
-            ExternInputId::new(vorpal_ui::CURSOR_KEY.to_string()),
-            DataType::Vec2,
+            ExternInputId::new(vorpal_ui::TIME_KEY.to_string()),
+            DataType::Scalar,
         ),
     ]
     .into_iter()
@@ -82,15 +207,26 @@ fn image_fn_inputs() -> ParameterList {
 
 impl Default for SaveState {
     fn default() -> Self {
-        let nodes = new_widget();
+        let nodes = new_widget(HostMode::Image);
         Self {
             user_wasm_path: Some("target/wasm32-unknown-unknown/release/vorpal_image.wasm".into()),
             functions: [("kernel".to_string(), nodes)].into_iter().collect(),
             selected_function: 0,
             show_wat: false,
+            annotate_wat: true,
             pause: false,
             focused: false,
             show_rust_decl: true,
+            show_console: false,
+            precision: Precision::default(),
+            time_limit_ms: 1000,
+            wasi: false,
+            project_dir: None,
+            mode: HostMode::Image,
+            audio: AudioSettings::default(),
+            mesh: MeshSettings::default(),
+            particles: ParticleSettings::default(),
+            plot: PlotSettings::default(),
         }
     }
 }
@@ -109,6 +245,15 @@ impl Default for VorpalApp {
             cursor_pos: None,
             add_dtype: DataType::Scalar,
             add_param: "my_new_param".into(),
+            checkpoint: None,
+            plugin_error: None,
+            console: vec![],
+            pending_project_dir: None,
+            analysis: None,
+            audio: AudioPreview::default(),
+            mesh: MeshPreview::default(),
+            particles: Particles::default(),
+            plot: PlotPreview::default(),
         }
     }
 }
@@ -145,12 +290,54 @@ impl eframe::App for VorpalApp {
     /// Called each time the UI needs repainting, which may be many times per second.
     /// Put your widgets into a `SidePanel`, `TopPanel`, `CentralPanel`, `Window` or `Area`.
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
-        if !self.saved.pause || self.single_step {
+        // Show the latest frame from the worker, which may arrive while paused
+        while let Some(event) = self.engine.as_mut().and_then(EvalWorker::poll) {
+            match event {
+                WorkerEvent::Frame(result) => self.show_frame(ctx, result),
+                WorkerEvent::Snapshot(result, purpose) => self.use_snapshot(result, purpose),
+                WorkerEvent::Restored(Ok(())) => {}
+                WorkerEvent::Restored(Err(e)) => {
+                    eprintln!("Error restoring checkpoint: {:#}", e);
+                    // Start the plugin over instead
+                    self.engine = None;
+                }
+            }
+        }
+
+        if let Some(engine) = self.engine.as_mut() {
+            extend_console(&mut self.console, engine.take_log());
+
+            if let Some((width, height)) = engine.take_requested_resolution() {
+                let [width, height] = [width, height].map(|x| x.clamp(1, MAX_RESOLUTION) as usize);
+                self.image_data = NdArray::zeros(vec![width, height, 4]);
+            }
+        }
+
+        if self.saved.mode == HostMode::Audio {
+            self.autosave(frame);
+            self.update_audio(ctx);
+        } else if self.saved.mode == HostMode::Mesh {
+            self.autosave(frame);
+            self.update_mesh();
+        } else if self.saved.mode == HostMode::Plot {
+            self.autosave(frame);
+            self.update_plot();
+        } else if self.saved.mode == HostMode::Particles {
+            self.autosave(frame);
+            if !self.saved.pause || self.single_step {
+                self.update_particles(ctx);
+                self.single_step = false;
+            }
+        } else if !self.saved.pause || self.single_step {
             // Load wasm file if unloaded
             if self.engine.is_none() {
                 if let Some(path) = &self.saved.user_wasm_path {
                     match VorpalWasmtime::new(path.clone()) {
-                        Ok(engine) => self.engine = Some(engine),
+                        Ok(engine) => {
+                            let ctx = ctx.clone();
+                            let worker = EvalWorker::new(engine, move || ctx.request_repaint());
+                            self.engine = Some(worker);
+                        }
                         Err(e) => {
                             eprintln!("Failed to load wasmtime {:?}", e);
                             self.saved.user_wasm_path = None;
@@ -159,21 +346,13 @@ impl eframe::App for VorpalApp {
                 }
             }
 
-            // Autosave
-            if self.autosave_timer.elapsed().as_secs_f32() > AUTOSAVE_INTERVAL_SECS {
-                self.autosave_timer = Instant::now();
-
-                if let Some(storage) = frame.storage_mut() {
-                    self.save(storage);
-                    storage.flush();
-                    eprintln!("Autosave successful");
-                }
-            }
+            self.autosave(frame);
 
             ctx.request_repaint();
 
             let width = self.image_data.shape()[0];
             let height = self.image_data.shape()[1];
+            let time = self.time.elapsed().as_secs_f64();
 
             let extern_parameters = [
                 (
@@ -186,7 +365,7 @@ impl eframe::App for VorpalApp {
                 ),
                 (
                     ExternInputId::new(vorpal_ui::TIME_KEY.to_string()),
-                    Value::Scalar(self.time.elapsed().as_secs_f32()),
+                    Value::Scalar(time as f32),
                 ),
             ];
             let extern_parameters = ExternParameters::new(extern_parameters.into_iter().collect());
@@ -200,26 +379,61 @@ impl eframe::App for VorpalApp {
                 .map(|(name, widget)| {
                     (
                         name.clone(),
-                        highlevel::convert_node(widget.extract_output_node()),
+                        widget.extract_output_graph(),
                         widget.params().clone(),
                     )
                 })
                 .collect();
 
+            // Index of the function at fault, and the errors to pin on its nodes
+            let mut errors: Option<(usize, Vec<NodeError>)> = None;
+
             if let Some(engine) = self.engine.as_mut() {
-                match engine.eval_image(&nodes, &extern_parameters) {
-                    Ok(image_data) => {
-                        self.image_data.data_mut().copy_from_slice(&image_data);
-                    }
-                    Err(e) => {
-                        eprintln!("Error failed to eval {:#}", e);
+                // Errors are shown once the frame arrives
+                engine.request(FrameRequest {
+                    nodes: nodes.clone(),
+                    params: extern_parameters,
+                    time,
+                    precision: self.saved.precision,
+                    time_limit: Duration::from_millis(self.saved.time_limit_ms),
+                    wasi: self.saved.wasi.then(|| WasiConfig {
+                        project_dir: self.saved.project_dir.clone(),
+                    }),
+                });
+            } else if let Some((_, graph, params)) = nodes.get(self.saved.selected_function) {
+                // No user code loaded; preview the selected function with the native backend
+                let precision = self.saved.precision;
+                match eval_image_native(
+                    graph,
+                    params,
+                    &extern_parameters,
+                    width,
+                    height,
+                    time,
+                    precision,
+                ) {
+                    Ok(image_data) if image_data.len() == self.image_data.len() => {
                         self.image_data
                             .data_mut()
-                            .iter_mut()
-                            .zip([1., 0., 0., 0.].into_iter().cycle())
-                            .for_each(|(o, i)| *o = i);
+                            .copy_from_slice(image_data.data());
+                    }
+                    Ok(_) => {
+                        let msg = "Native preview requires a Vec4 output".to_string();
+                        paint_error(&mut self.image_data);
+                        errors = Some((self.saved.selected_function, vec![(None, msg)]));
+                    }
+                    Err(e) => {
+                        paint_error(&mut self.image_data);
+                        errors = Some((self.saved.selected_function, node_errors(&e.into())));
                     }
                 }
+                self.set_errors(errors);
+            }
+
+            let builtin_bounds = image_fn_bounds(width, height);
+            for ((_, graph, params), (_, widget)) in nodes.iter().zip(&mut self.saved.functions) {
+                let warnings = range_warnings(graph, params, &builtin_bounds, widget.bounds());
+                widget.set_warnings(warnings);
             }
 
             self.image
@@ -235,6 +449,12 @@ impl eframe::App for VorpalApp {
                     if ui.button("Save .wat (compiled wasm module text)").clicked() {
                         self.save_wat_file();
                     }
+                    if ui.button("Save .rs (native Rust source)").clicked() {
+                        self.save_rs_file();
+                    }
+                    if ui.button("Save .c/.h (C source)").clicked() {
+                        self.save_c_files();
+                    }
                     if ui.button("Save .vor (nodes)").clicked() {
                         self.save_vor_file();
                     }
@@ -244,6 +464,24 @@ impl eframe::App for VorpalApp {
                     if ui.button("Load .wasm (user code)").clicked() {
                         self.load_user_wasm_file();
                     }
+                    if ui.button("Choose project folder (plugin data)").clicked() {
+                        self.choose_project_dir();
+                    }
+                    if ui.button("Save .wav (rendered audio)").clicked() {
+                        self.save_wav_file();
+                    }
+                    if ui.button("Save .obj (mesh)").clicked() {
+                        self.save_obj_file();
+                    }
+                    if ui.button("Save .ply (mesh)").clicked() {
+                        self.save_ply_file();
+                    }
+                    if ui.button("Save snapshot (plugin state)").clicked() {
+                        self.save_snapshot_file();
+                    }
+                    if ui.button("Load snapshot (plugin state)").clicked() {
+                        self.load_snapshot_file();
+                    }
                     if ui.button("Load defaults").clicked() {
                         *self = Self::default();
                     }
@@ -254,10 +492,66 @@ impl eframe::App for VorpalApp {
                     self.single_step = true;
                 }
                 ui.checkbox(&mut self.saved.pause, "Pause");
-                if ui.button("Reset").clicked() {
-                    self.engine = None;
+                let reset_text = if self.checkpoint.is_some() {
+                    "Go back to the checkpoint"
+                } else {
+                    "Start over"
+                };
+                if ui.button("Reset").on_hover_text(reset_text).clicked() {
+                    self.reset();
+                }
+                if ui.button("Checkpoint").clicked() {
+                    self.take_checkpoint();
+                }
+                if self.checkpoint.is_some() && ui.button("Clear checkpoint").clicked() {
+                    self.checkpoint = None;
                 }
+                ui.add(
+                    DragValue::new(&mut self.saved.time_limit_ms)
+                        .clamp_range(1..=60_000)
+                        .suffix(" ms"),
+                )
+                .on_hover_text("Time limit for drawing a frame");
                 ui.checkbox(&mut self.saved.focused, "Focused");
+                ui.checkbox(&mut self.saved.show_console, "Console");
+                let wasi_text = match &self.saved.project_dir {
+                    Some(dir) => format!(
+                        "Let the plugin print and read files in {} as {}",
+                        dir.display(),
+                        PROJECT_GUEST_DIR
+                    ),
+                    None => "Let the plugin print. Choose a project folder to give it files."
+                        .to_string(),
+                };
+                ui.checkbox(&mut self.saved.wasi, "WASI")
+                    .on_hover_text(wasi_text);
+                if let Some(dir) = &self.pending_project_dir {
+                    ui.label(format!("Let the plugin read {}?", dir.display()));
+                    if ui.button("Allow").clicked() {
+                        self.saved.project_dir = self.pending_project_dir.take();
+                    }
+                    if ui.button("Deny").clicked() {
+                        self.pending_project_dir = None;
+                    }
+                }
+                ComboBox::from_label("Mode")
+                    .selected_text(self.saved.mode.to_string())
+                    .show_ui(ui, |ui| {
+                        for mode in HostMode::all() {
+                            ui.selectable_value(&mut self.saved.mode, mode, mode.to_string());
+                        }
+                    });
+                ComboBox::from_label("Precision")
+                    .selected_text(self.saved.precision.to_string())
+                    .show_ui(ui, |ui| {
+                        for precision in Precision::all() {
+                            ui.selectable_value(
+                                &mut self.saved.precision,
+                                precision,
+                                precision.to_string(),
+                            );
+                        }
+                    });
                 //});
 
                 let filename_text = match self.saved.user_wasm_path.as_ref() {
@@ -274,6 +568,20 @@ impl eframe::App for VorpalApp {
                     egui::Layout::right_to_left(eframe::emath::Align::Max),
                     |ui| {
                         ui.label(format!("Running {filename_text}"));
+                        if let Some(engine) = &self.engine {
+                            let stats = engine.stats();
+                            ui.label(format!(
+                                "{:.0} FPS, {} ms latency",
+                                stats.fps,
+                                stats.latency.as_millis()
+                            ));
+                        }
+                        if let Some(error) = &self.plugin_error {
+                            ui.label(
+                                RichText::new(format!("⚠ {error}"))
+                                    .color(ui.visuals().warn_fg_color),
+                            );
+                        }
                     },
                 );
                 //ui.menu_button(filename_text, |_| ());
@@ -285,6 +593,8 @@ impl eframe::App for VorpalApp {
                 self.saved.selected_fn_widget().show(ui);
             });
             egui::SidePanel::right("options").show(ctx, |ui| {
+                self.error_list(ui);
+
                 ui.strong("Functions");
 
                 // Function name editor
@@ -314,13 +624,15 @@ impl eframe::App for VorpalApp {
 
                     self.saved
                         .functions
-                        .push(("unnamed".into(), new_widget()));
+                        .push(("unnamed".into(), new_widget(self.saved.mode)));
                 }
 
                 if let Some(idx) = remove {
                     self.saved.functions.remove(idx);
                 }
 
+                self.plugin_functions(ui);
+
                 ui.separator();
 
                 ui.strong("Selected function parameters");
@@ -357,19 +669,33 @@ impl eframe::App for VorpalApp {
                     dtype_selector(99999, ui, &mut self.add_dtype)
                 });
 
+                ui.separator();
+
+                ui.strong("Selected function parameter bounds");
+
+                let widget = self.saved.selected_fn_widget();
+                let params = widget.params().clone();
+                let bounds = widget.bounds_mut();
+                for (idx, id) in ordered.iter().enumerate() {
+                    let dtype = params
+                        .inputs()
+                        .iter()
+                        .find_map(|(p_id, dtype)| (p_id == id).then_some(*dtype));
+                    if let Some(dtype) = dtype {
+                        ui.push_id((idx, "bounds"), |ui| bounds_editor(ui, id, dtype, bounds));
+                    }
+                }
+
+                let analysis = self.selected_analysis();
+
                 // Get function name
                 let func_name = &self.saved.functions[self.saved.selected_function].0;
 
                 // Get rust function body as a string
-                let maybe_fn_body: Option<String> = self.engine.as_ref().and_then(|engine| {
-                    engine
-                        .cache
-                        .as_ref()?
-                        .analyses
-                        .get(self.saved.selected_function)?
-                        .func_name_rust(&func_name)
-                        .ok()
-                });
+                let maybe_fn_body: Option<String> = analysis
+                    .as_ref()
+                    .ok()
+                    .and_then(|analysis| analysis.func_name_rust(&func_name).ok());
 
                 ui.separator();
 
@@ -399,26 +725,26 @@ impl eframe::App for VorpalApp {
 
                 ui.separator();
 
-                ui.checkbox(&mut self.saved.show_wat, "WebAssembly text:");
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut self.saved.show_wat, "WebAssembly text:");
+                    ui.checkbox(&mut self.saved.annotate_wat, "Annotate with nodes");
+                });
                 if self.saved.show_wat {
+                    let sources = if self.saved.annotate_wat {
+                        self.saved.functions[self.saved.selected_function]
+                            .1
+                            .node_sources()
+                    } else {
+                        vec![]
+                    };
                     egui::ScrollArea::vertical().show(ui, |ui| {
                         // Show wasm code
-                        let mut text = self
-                            .engine
+                        let mut text = analysis
                             .as_ref()
-                            .ok_or(format_err!("No engine"))
-                            .and_then(|engine| {
-                                engine.cache.as_ref().ok_or(format_err!("Nothing cached"))
-                            })
-                            .and_then(|cache| {
-                                cache
-                                    .analyses
-                                    .get(self.saved.selected_function)
-                                    .ok_or(format_err!("Nothing selected"))
-                            })
+                            .map_err(|e| format_err!("{}", e))
                             .and_then(|analysis| {
                                 analysis
-                                    .compile_to_wat(&func_name)
+                                    .compile_to_wat_annotated(&func_name, &sources)
                                     .map_err(|e| format_err!("Compilation failed {:?}", e))
                             })
                             .unwrap_or_else(|err| err.to_string());
@@ -432,7 +758,44 @@ impl eframe::App for VorpalApp {
             });
         }
 
+        if self.saved.show_console {
+            egui::TopBottomPanel::bottom("console")
+                .resizable(true)
+                .show(ctx, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.strong("Console");
+                        if ui.button("Clear").clicked() {
+                            self.console.clear();
+                        }
+                    });
+                    ScrollArea::vertical()
+                        .stick_to_bottom(true)
+                        .auto_shrink([false, true])
+                        .show(ui, |ui| {
+                            for line in &self.console {
+                                ui.monospace(line);
+                            }
+                        });
+                });
+        }
+
         egui::CentralPanel::default().show(ctx, |ui| {
+            if self.saved.mode == HostMode::Audio {
+                self.audio_view(ui);
+                return;
+            }
+            if self.saved.mode == HostMode::Mesh {
+                self.mesh_view(ui);
+                return;
+            }
+            if self.saved.mode == HostMode::Plot {
+                self.plot_view(ui);
+                return;
+            }
+            if self.saved.mode == HostMode::Particles {
+                self.particle_settings(ui);
+            }
+
             /*
             let response = ui
                 .with_layout(
@@ -459,6 +822,179 @@ impl eframe::App for VorpalApp {
 }
 
 impl VorpalApp {
+    /// Functions the plugin requires which the project lacks or defines differently, with buttons
+    /// to create the missing ones
+    fn plugin_functions(&mut self, ui: &mut Ui) {
+        let Some(manifest) = self.engine.as_ref().and_then(|engine| engine.manifest()) else {
+            return;
+        };
+        let found: Vec<_> = self
+            .saved
+            .functions
+            .iter()
+            .map(|(name, widget)| (name.as_str(), widget.params(), None))
+            .collect();
+        let problems = manifest.validate(&found);
+
+        let mut create = None;
+        for problem in problems {
+            ui.horizontal(|ui| {
+                let text = RichText::new(format!("⚠ {problem}")).color(ui.visuals().warn_fg_color);
+                ui.label(text);
+                if problem.kind == ProblemKind::Missing && ui.button("Create").clicked() {
+                    create = Some(problem.expected.clone());
+                }
+            });
+        }
+
+        if let Some(function) = create {
+            self.saved.selected_function = self.saved.functions.len();
+            let widget = NodeGraphWidget::new(function.params, function.output, "Output".into());
+            self.saved.functions.push((function.name, widget));
+        }
+    }
+
+    /// List the errors and warnings of every function; clicking one shows the node at fault
+    fn error_list(&mut self, ui: &mut Ui) {
+        let mut clicked_error = None;
+        let mut clicked_warning = None;
+        for (func_idx, (func_name, widget)) in self.saved.functions.iter().enumerate() {
+            for (error_idx, (_, msg)) in widget.errors().iter().enumerate() {
+                let text = RichText::new(format!("⚠ {func_name}: {msg}"))
+                    .color(ui.visuals().error_fg_color);
+                if ui.link(text).clicked() {
+                    clicked_error = Some((func_idx, error_idx));
+                }
+            }
+            for (warning_idx, (_, msg)) in widget.warnings().iter().enumerate() {
+                let text = RichText::new(format!("⚠ {func_name}: {msg}"))
+                    .color(ui.visuals().warn_fg_color);
+                if ui.link(text).clicked() {
+                    clicked_warning = Some((func_idx, warning_idx));
+                }
+            }
+        }
+
+        if let Some((func_idx, error_idx)) = clicked_error {
+            self.saved.selected_function = func_idx;
+            self.saved.focused = false;
+            self.saved.functions[func_idx].1.focus_error(error_idx);
+        }
+
+        if let Some((func_idx, warning_idx)) = clicked_warning {
+            self.saved.selected_function = func_idx;
+            self.saved.focused = false;
+            self.saved.functions[func_idx].1.focus_warning(warning_idx);
+        }
+
+        if self
+            .saved
+            .functions
+            .iter()
+            .any(|(_, w)| !w.errors().is_empty() || !w.warnings().is_empty())
+        {
+            ui.separator();
+        }
+    }
+
+    /// Show a frame rendered by the worker, or the error it failed with
+    fn show_frame(&mut self, ctx: &egui::Context, result: anyhow::Result<Vec<f32>>) {
+        let mut errors = None;
+        match result {
+            // Frames requested before a resize are dropped
+            Ok(image_data) if image_data.len() != self.image_data.len() => {}
+            Ok(image_data) => {
+                self.image_data.data_mut().copy_from_slice(&image_data);
+                self.plugin_error = None;
+            }
+            Err(e) => {
+                paint_error(&mut self.image_data);
+                match e.downcast_ref::<FunctionError>() {
+                    Some(e) => errors = Some((e.func_idx, node_errors(&e.error))),
+                    // Not caused by any one function, so shown next to the plugin's name
+                    None => self.plugin_error = Some(format!("{:#}", e)),
+                }
+                if e.downcast_ref::<TimeLimitExceeded>().is_some() {
+                    // Running it again next frame would only freeze the plugin again
+                    self.saved.pause = true;
+                }
+            }
+        }
+        self.set_errors(errors);
+        self.image
+            .set_image("my image".into(), ctx, array_to_imagedata(&self.image_data));
+    }
+
+    /// Go back to the checkpoint if there is one, or otherwise start the plugin over
+    fn reset(&mut self) {
+        // Scattered again on the next step
+        self.particles = Particles::default();
+
+        if let (Some(engine), Some(checkpoint)) = (self.engine.as_mut(), self.checkpoint.as_ref()) {
+            // Starts over instead if this fails
+            engine.restore(checkpoint.clone());
+            // Show the restored state even when paused
+            self.single_step = true;
+            return;
+        }
+        self.engine = None;
+    }
+
+    fn take_checkpoint(&mut self) {
+        if let Some(engine) = self.engine.as_mut() {
+            engine.snapshot(SnapshotPurpose::Checkpoint);
+        }
+    }
+
+    pub fn save_snapshot_file(&mut self) {
+        let Some(engine) = self.engine.as_mut() else {
+            return;
+        };
+        if let Some(path) = rfd::FileDialog::new()
+            .set_title("Save snapshot")
+            .set_file_name("snapshot.vsnap")
+            .save_file()
+        {
+            engine.snapshot(SnapshotPurpose::Save(path));
+        }
+    }
+
+    /// Keep or save a snapshot the worker took
+    fn use_snapshot(&mut self, result: anyhow::Result<Snapshot>, purpose: SnapshotPurpose) {
+        let snapshot = match result {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                eprintln!("Error taking snapshot: {:#}", e);
+                return;
+            }
+        };
+        match purpose {
+            SnapshotPurpose::Checkpoint => self.checkpoint = Some(snapshot),
+            SnapshotPurpose::Save(path) => {
+                if let Err(e) = snapshot.save(&path) {
+                    eprintln!("Error saving {}; {:#}", path.display(), e);
+                }
+            }
+        }
+    }
+
+    /// Load a snapshot as the checkpoint, and go back to it
+    pub fn load_snapshot_file(&mut self) {
+        if let Some(path) = rfd::FileDialog::new()
+            .set_title("Load snapshot")
+            .add_filter("vsnap", &["vsnap"])
+            .pick_file()
+        {
+            match Snapshot::load(&path) {
+                Ok(snapshot) => {
+                    self.checkpoint = Some(snapshot);
+                    self.reset();
+                }
+                Err(e) => eprintln!("Error loading {}; {:#}", path.display(), e),
+            }
+        }
+    }
+
     pub fn load_user_wasm_file(&mut self) {
         if let Some(path) = rfd::FileDialog::new()
             .set_title("Load .wasm file")
@@ -471,28 +1007,609 @@ impl VorpalApp {
         }
     }
 
-    pub fn save_wat_file(&self) {
-        if let Some(engine) = self.engine.as_ref() {
-            if let Some(cache) = engine.cache.as_ref() {
-                let (func_name, _) = &self.saved.functions[self.saved.selected_function];
-                if let Ok(wat) =
-                    cache.analyses[self.saved.selected_function].compile_to_wat(&func_name)
+    pub fn save_wat_file(&mut self) {
+        if let Ok(analysis) = self.selected_analysis() {
+            let (func_name, widget) = &self.saved.functions[self.saved.selected_function];
+            let sources = if self.saved.annotate_wat {
+                widget.node_sources()
+            } else {
+                vec![]
+            };
+            if let Ok(wat) = analysis.compile_to_wat_annotated(&func_name, &sources) {
+                if let Some(path) = rfd::FileDialog::new()
+                    .set_title("Save .wat file")
+                    .set_file_name(format!("{}.wat", func_name))
+                    .save_file()
+                {
+                    if let Err(e) = std::fs::write(path, &wat) {
+                        eprintln!("Error saving .wat: {:#}", e)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Analysis of the selected function, for showing the code it compiles to. Only made again
+    /// once the function changes.
+    fn selected_analysis(&mut self) -> Result<Rc<CodeAnalysis>, String> {
+        let precision = self.saved.precision;
+        let widget = self.saved.selected_fn_widget();
+        let graph = widget.extract_output_graph();
+        let params = widget.params();
+        if let Some(cached) = &self.analysis {
+            if cached.graph == graph && cached.params == *params && cached.precision == precision {
+                return cached.analysis.clone();
+            }
+        }
+
+        let analysis = CodeAnalysis::new(&graph, params)
+            .map(|analysis| Rc::new(analysis.with_precision(precision)))
+            .map_err(|e| format!("{:#}", e));
+        self.analysis = Some(CachedAnalysis {
+            graph,
+            params: params.clone(),
+            precision,
+            analysis: analysis.clone(),
+        });
+        analysis
+    }
+
+    /// Pin errors on the nodes of the function at fault, and clear them from the others
+    fn set_errors(&mut self, mut errors: Option<(usize, Vec<NodeError>)>) {
+        for (idx, (_, widget)) in self.saved.functions.iter_mut().enumerate() {
+            match &mut errors {
+                Some((func_idx, errors)) if *func_idx == idx => {
+                    widget.set_errors(std::mem::take(errors))
+                }
+                _ => widget.set_errors(vec![]),
+            }
+        }
+    }
+
+    pub fn save_rs_file(&mut self) {
+        let (func_name, widget) = &mut self.saved.functions[self.saved.selected_function];
+        let graph = widget.extract_output_graph();
+        match vorpal_rust::compile_to_rust(&graph, widget.params(), func_name) {
+            Ok(source) => {
+                if let Some(path) = rfd::FileDialog::new()
+                    .set_title("Save .rs file")
+                    .set_file_name(format!("{}.rs", func_name))
+                    .save_file()
                 {
-                    if let Some(path) = rfd::FileDialog::new()
-                        .set_title("Save .wat file")
-                        .set_file_name(format!("{}.wat", func_name))
-                        .save_file()
-                    {
-                        if let Err(e) = std::fs::write(path, &wat) {
-                            eprintln!("Error saving .wat: {:#}", e)
+                    if let Err(e) = std::fs::write(path, &source) {
+                        eprintln!("Error saving .rs: {:#}", e)
+                    }
+                }
+            }
+            Err(e) => eprintln!("Error generating Rust source: {:#}", e),
+        }
+    }
+
+    /// Save a .c and .h pair for each function into a folder
+    pub fn save_c_files(&mut self) {
+        let Some(folder) = rfd::FileDialog::new()
+            .set_title("Save C sources to folder")
+            .pick_folder()
+        else {
+            return;
+        };
+
+        for (func_name, widget) in &mut self.saved.functions {
+            let graph = widget.extract_output_graph();
+            let res = vorpal_c::compile_to_c(&graph, widget.params(), func_name).and_then(|c| {
+                std::fs::write(folder.join(format!("{}.h", c.name)), c.header)?;
+                std::fs::write(folder.join(format!("{}.c", c.name)), c.source)?;
+                Ok(())
+            });
+            if let Err(e) = res {
+                eprintln!("Error saving C source for {}(): {:#}", func_name, e);
+            }
+        }
+    }
+
+    fn autosave(&mut self, frame: &mut eframe::Frame) {
+        if self.autosave_timer.elapsed().as_secs_f32() > AUTOSAVE_INTERVAL_SECS {
+            self.autosave_timer = Instant::now();
+
+            if let Some(storage) = frame.storage_mut() {
+                self.save(storage);
+                storage.flush();
+                eprintln!("Autosave successful");
+            }
+        }
+    }
+
+    /// Show audio which finished rendering, and render the selected function as audio on the
+    /// worker if it or the settings changed since it was last requested
+    fn update_audio(&mut self, ctx: &egui::Context) {
+        while let Some(event) = self.audio.worker.as_mut().and_then(EvalWorker::poll) {
+            match event {
+                WorkerEvent::Audio(result) => self.show_audio(result),
+                // Only sent once the thread stopped, so start it over
+                WorkerEvent::Frame(Err(e)) => {
+                    eprintln!("Error failed to render audio {:#}", e);
+                    self.audio.worker = None;
+                    self.audio.requested = None;
+                    self.audio.rendering = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(worker) = self.audio.worker.as_mut() {
+            extend_console(&mut self.console, worker.take_log());
+        }
+
+        // Wait for the audio being rendered, then render again if anything changed meanwhile
+        if self.audio.rendering.is_some() {
+            return;
+        }
+
+        let func_idx = self.saved.selected_function;
+        let Some((name, widget)) = self.saved.functions.get_mut(func_idx) else {
+            return;
+        };
+        let nodes: NodeGraphs = vec![(
+            name.clone(),
+            widget.extract_output_graph(),
+            widget.params().clone(),
+        )];
+        let key = (nodes, self.saved.audio, self.saved.precision);
+        if self.audio.requested.as_ref() == Some(&key) {
+            return;
+        }
+
+        if self.audio.worker.is_none() {
+            match VorpalWasmtime::without_plugin() {
+                Ok(engine) => {
+                    let ctx = ctx.clone();
+                    let worker = EvalWorker::new(engine, move || ctx.request_repaint());
+                    self.audio.worker = Some(worker);
+                }
+                Err(e) => {
+                    eprintln!("Failed to load wasmtime {:?}", e);
+                    return;
+                }
+            }
+        }
+
+        let (nodes, settings, precision) = key.clone();
+        let request = AudioRequest {
+            nodes,
+            settings,
+            precision,
+            time_limit: Duration::from_millis(self.saved.time_limit_ms),
+            spectrum_bins: SPECTRUM_BINS,
+        };
+        self.audio.worker.as_mut().unwrap().render_audio(request);
+        self.audio.requested = Some(key);
+        self.audio.rendering = Some(func_idx);
+    }
+
+    /// Show rendered audio, or the errors it failed with
+    fn show_audio(&mut self, result: anyhow::Result<RenderedAudio>) {
+        let Some(func_idx) = self.audio.rendering.take() else {
+            return;
+        };
+
+        let mut errors = None;
+        match result {
+            Ok(rendered) => {
+                self.audio.samples = rendered.samples;
+                self.audio.spectrum = rendered.spectrum;
+            }
+            Err(e) => {
+                eprintln!("Error failed to render audio {:#}", e);
+                self.audio.samples = AudioSamples::default();
+                self.audio.spectrum.clear();
+                let error = e.downcast_ref::<FunctionError>().map_or(&e, |e| &e.error);
+                errors = Some((func_idx, node_errors(error)));
+            }
+        }
+        self.set_errors(errors);
+    }
+
+    /// Settings of the audio, and plots of the waveform and spectrum
+    fn audio_view(&mut self, ui: &mut Ui) {
+        let settings = &mut self.saved.audio;
+        let mut save = false;
+        ui.horizontal(|ui| {
+            ComboBox::from_label("Sample rate")
+                .selected_text(format!("{} Hz", settings.sample_rate))
+                .show_ui(ui, |ui| {
+                    for rate in [22_050, 44_100, 48_000, 96_000] {
+                        ui.selectable_value(&mut settings.sample_rate, rate, format!("{rate} Hz"));
+                    }
+                });
+            ui.add(
+                DragValue::new(&mut settings.duration)
+                    .clamp_range(0.01..=60.)
+                    .speed(0.01)
+                    .suffix(" s"),
+            )
+            .on_hover_text("Duration");
+            ui.label(vorpal_ui::NOTE_KEY);
+            ui.add(DragValue::new(&mut settings.note).clamp_range(0.0..=20_000.));
+            ui.label(vorpal_ui::VELOCITY_KEY);
+            ui.add(egui::Slider::new(&mut settings.velocity, 0.0..=1.0));
+            let mut gate = settings.gate > 0.;
+            ui.checkbox(&mut gate, vorpal_ui::GATE_KEY);
+            settings.gate = f32::from(u8::from(gate));
+            save = ui.button("Save .wav").clicked();
+        });
+        if save {
+            self.save_wav_file();
+        }
+
+        let samples = &self.audio.samples;
+        let duration = samples.n_frames() as f32 / samples.sample_rate.max(1) as f32;
+        ui.label(format!(
+            "{} channel(s), {:.2} s",
+            samples.channels, duration
+        ));
+        for channel in 0..samples.channels {
+            let values: Vec<f32> = samples.channel(channel).collect();
+            let response = plot::line_plot(ui, &values, (-1., 1.), AUDIO_PLOT_HEIGHT);
+            if let Some(idx) = plot::hovered_index(&response, values.len()) {
+                let time = idx as f32 / samples.sample_rate as f32;
+                response.on_hover_text(format!("{:.4} s: {:.4}", time, values[idx]));
+            }
+        }
+
+        ui.label("Spectrum");
+        let spectrum = &self.audio.spectrum;
+        let response = plot::line_plot(ui, spectrum, (-100., 0.), AUDIO_PLOT_HEIGHT);
+        if let Some(bin) = plot::hovered_index(&response, spectrum.len()) {
+            let nyquist = samples.sample_rate as f32 / 2.;
+            let frequency = bin as f32 * nyquist / spectrum.len() as f32;
+            response.on_hover_text(format!("{:.0} Hz: {:.1} dB", frequency, spectrum[bin]));
+        }
+    }
+
+    pub fn save_wav_file(&mut self) {
+        if self.audio.samples.data.is_empty() {
+            eprintln!("No audio has been rendered; switch to the audio mode first");
+            return;
+        }
+
+        if let Some(path) = rfd::FileDialog::new()
+            .set_title("Save .wav file")
+            .set_file_name("audio.wav")
+            .save_file()
+        {
+            if let Err(e) = self.audio.samples.save_wav(&path) {
+                eprintln!("Error saving {}; {:#}", path.display(), e);
+            }
+        }
+    }
+
+    /// Evaluate the selected function over the grid, and the normal and color functions if set,
+    /// if any of them or the settings changed since the mesh was last built
+    fn update_mesh(&mut self) {
+        let func_idx = self.saved.selected_function;
+        if func_idx >= self.saved.functions.len() {
+            return;
+        }
+        let find = |name: &Option<FuncName>| {
+            let name = name.as_ref()?;
+            self.saved
+                .functions
+                .iter()
+                .position(|(other, _)| other == name)
+        };
+        let normal_idx = find(&self.saved.mesh.normal_fn);
+        let color_idx = find(&self.saved.mesh.color_fn);
+
+        // The functions giving the positions, normals and colors
+        let indices = [Some(func_idx), normal_idx, color_idx];
+
+        let nodes: NodeGraphs = indices
+            .into_iter()
+            .flatten()
+            .map(|idx| {
+                let (name, widget) = &mut self.saved.functions[idx];
+                (
+                    name.clone(),
+                    widget.extract_output_graph(),
+                    widget.params().clone(),
+                )
+            })
+            .collect();
+        let key = (nodes, self.saved.mesh.clone(), self.saved.precision);
+        if self.mesh.rendered.as_ref() == Some(&key) {
+            return;
+        }
+
+        let (nodes, settings, precision) = &key;
+        let result = indices
+            .into_iter()
+            .flatten()
+            .zip(nodes)
+            .map(|(idx, (_, graph, params))| {
+                settings
+                    .eval_grid(graph, params, *precision)
+                    .map_err(|e| (idx, e))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|grids| {
+                // The positions, then whichever of the normals and colors are set
+                let mut rest = grids[1..].iter();
+                let normals = normal_idx.and_then(|_| rest.next());
+                let colors = color_idx.and_then(|_| rest.next());
+                Mesh::from_grid(&grids[0], normals, colors).map_err(|e| (func_idx, e))
+            });
+
+        let mut errors = None;
+        match result {
+            Ok(mesh) => self.mesh.mesh = mesh,
+            Err((idx, e)) => {
+                eprintln!("Error failed to build mesh {:#}", e);
+                self.mesh.mesh = Mesh::default();
+                errors = Some((idx, node_errors(&e)));
+            }
+        }
+        self.set_errors(errors);
+        self.mesh.rendered = Some(key);
+    }
+
+    /// Settings of the mesh, and a preview of it
+    fn mesh_view(&mut self, ui: &mut Ui) {
+        let settings = &mut self.saved.mesh;
+        let names: Vec<&FuncName> = self.saved.functions.iter().map(|(name, _)| name).collect();
+        let (mut save_obj, mut save_ply) = (false, false);
+        ui.horizontal(|ui| {
+            ui.label("Resolution");
+            for n in &mut settings.resolution {
+                ui.add(DragValue::new(n).clamp_range(2..=1024));
+            }
+            ui.label(vorpal_ui::TIME_KEY);
+            ui.add(DragValue::new(&mut settings.time).speed(0.01));
+            fn_selector(ui, "Normals", "Computed", &mut settings.normal_fn, &names);
+            fn_selector(ui, "Colors", "None", &mut settings.color_fn, &names);
+            ui.checkbox(&mut self.mesh.view.wireframe, "Wireframe");
+            save_obj = ui.button("Save .obj").clicked();
+            save_ply = ui.button("Save .ply").clicked();
+        });
+        if save_obj {
+            self.save_obj_file();
+        }
+        if save_ply {
+            self.save_ply_file();
+        }
+
+        let mesh = &self.mesh.mesh;
+        ui.label(format!(
+            "{} vertices, {} triangles",
+            mesh.positions.len(),
+            mesh.triangles.len()
+        ));
+        let data = MeshData {
+            positions: &mesh.positions,
+            normals: &mesh.normals,
+            colors: mesh.colors.as_deref(),
+            triangles: &mesh.triangles,
+        };
+        self.mesh.view.show(ui, data);
+    }
+
+    pub fn save_obj_file(&mut self) {
+        self.save_mesh_file("Save .obj file", "mesh.obj", |mesh, path| {
+            mesh.save_obj(path)
+        });
+    }
+
+    pub fn save_ply_file(&mut self) {
+        self.save_mesh_file("Save .ply file", "mesh.ply", |mesh, path| {
+            mesh.save_ply(path)
+        });
+    }
+
+    fn save_mesh_file(
+        &mut self,
+        title: &str,
+        file_name: &str,
+        save: impl Fn(&Mesh, &Path) -> anyhow::Result<()>,
+    ) {
+        if self.mesh.mesh.positions.is_empty() {
+            eprintln!("No mesh has been built; switch to the mesh mode first");
+            return;
+        }
+
+        if let Some(path) = rfd::FileDialog::new()
+            .set_title(title)
+            .set_file_name(file_name)
+            .save_file()
+        {
+            if let Err(e) = save(&self.mesh.mesh, &path) {
+                eprintln!("Error saving {}; {:#}", path.display(), e);
+            }
+        }
+    }
+
+    /// Step the particles with the selected function, and draw them into the image
+    fn update_particles(&mut self, ctx: &egui::Context) {
+        ctx.request_repaint();
+
+        let width = self.image_data.shape()[0];
+        let height = self.image_data.shape()[1];
+        let settings = self.saved.particles;
+        if self.particles.len() != settings.count as usize {
+            self.particles = Particles::scattered(settings.count as usize, width, height);
+        }
+
+        let uniforms = [
+            (
+                ExternInputId::new(vorpal_ui::CURSOR_KEY.into()),
+                Value::Vec2(self.cursor_pos.unwrap_or([-1., -1.]).into()),
+            ),
+            (
+                ExternInputId::new(vorpal_ui::TIME_KEY.to_string()),
+                Value::Scalar(self.time.elapsed().as_secs_f32()),
+            ),
+        ];
+        let uniforms = ExternParameters::new(uniforms.into_iter().collect());
+        // A long frame would fling the particles far off course
+        let dt = ctx.input(|input| input.stable_dt).min(MAX_PARTICLE_STEP);
+
+        let precision = self.saved.precision;
+        let widget = self.saved.selected_fn_widget();
+        let graph = widget.extract_output_graph();
+        let result = self.particles.step(
+            &graph,
+            widget.params(),
+            &uniforms,
+            dt,
+            settings.drag,
+            precision,
+        );
+
+        let mut errors = None;
+        match result {
+            Ok(()) => {
+                self.particles.wrap(width, height);
+                self.particles
+                    .splat(&mut self.image_data, settings.brightness);
+            }
+            Err(e) => {
+                eprintln!("Error failed to step particles {:#}", e);
+                paint_error(&mut self.image_data);
+                errors = Some((self.saved.selected_function, node_errors(&e)));
+            }
+        }
+        self.set_errors(errors);
+        self.image
+            .set_image("my image".into(), ctx, array_to_imagedata(&self.image_data));
+    }
+
+    /// Number of particles, and how they move and are drawn
+    fn particle_settings(&mut self, ui: &mut Ui) {
+        let settings = &mut self.saved.particles;
+        ui.horizontal(|ui| {
+            ui.label("Particles");
+            ui.add(DragValue::new(&mut settings.count).clamp_range(1..=1_000_000));
+            ui.label("Drag");
+            ui.add(egui::Slider::new(&mut settings.drag, 0.0..=1.0));
+            ui.label("Brightness");
+            ui.add(egui::Slider::new(&mut settings.brightness, 0.0..=1.0));
+        });
+    }
+
+    /// Sample the selected function and the overlaid ones, if any of them or the settings changed
+    /// since they were last sampled
+    fn update_plot(&mut self) {
+        let func_idx = self.saved.selected_function;
+        if func_idx >= self.saved.functions.len() {
+            return;
+        }
+        let settings = &self.saved.plot;
+        let overlaid = self
+            .saved
+            .functions
+            .iter()
+            .enumerate()
+            .filter(|(idx, (name, _))| *idx != func_idx && settings.overlay.contains(name));
+        let indices: Vec<usize> = std::iter::once(func_idx)
+            .chain(overlaid.map(|(idx, _)| idx))
+            .collect();
+
+        let nodes: NodeGraphs = indices
+            .iter()
+            .map(|&idx| {
+                let (name, widget) = &mut self.saved.functions[idx];
+                (
+                    name.clone(),
+                    widget.extract_output_graph(),
+                    widget.params().clone(),
+                )
+            })
+            .collect();
+        let key = (nodes, self.saved.plot.clone(), self.saved.precision);
+        if self.plot.rendered.as_ref() == Some(&key) {
+            return;
+        }
+
+        // Curves which can be sampled are still shown when others fail
+        let (nodes, settings, precision) = &key;
+        let mut curves = vec![];
+        let mut errors = None;
+        for (&idx, (name, graph, params)) in indices.iter().zip(nodes) {
+            match settings.sample(graph, params, *precision) {
+                Ok(values) => curves.push((name.clone(), values)),
+                Err(e) => {
+                    eprintln!("Error failed to sample {}(): {:#}", name, e);
+                    errors = errors.or_else(|| Some((idx, node_errors(&e))));
+                }
+            }
+        }
+        self.set_errors(errors);
+        self.plot.curves = curves;
+        self.plot.rendered = Some(key);
+    }
+
+    /// Range of x and the functions to overlay, and the plot of their curves
+    fn plot_view(&mut self, ui: &mut Ui) {
+        let settings = &mut self.saved.plot;
+        let functions = &self.saved.functions;
+        let selected = functions
+            .get(self.saved.selected_function)
+            .map(|(name, _)| name);
+        let others: Vec<&FuncName> = functions
+            .iter()
+            .map(|(name, _)| name)
+            .filter(|name| Some(*name) != selected)
+            .collect();
+        ui.horizontal(|ui| {
+            let [start, end] = &mut settings.x_range;
+            ui.label("x from");
+            ui.add(DragValue::new(start).speed(0.01));
+            ui.label("to");
+            ui.add(DragValue::new(end).speed(0.01));
+            ui.add(
+                DragValue::new(&mut settings.n_samples)
+                    .clamp_range(2..=MAX_PLOT_SAMPLES)
+                    .suffix(" samples"),
+            );
+            ui.label(vorpal_ui::TIME_KEY);
+            ui.add(DragValue::new(&mut settings.time).speed(0.01));
+            ui.menu_button("Overlay", |ui| {
+                if others.is_empty() {
+                    ui.label("There are no other functions");
+                }
+                for name in &others {
+                    let mut overlaid = settings.overlay.contains(name);
+                    if ui.checkbox(&mut overlaid, name.as_str()).changed() {
+                        if overlaid {
+                            settings.overlay.push(name.to_string());
+                        } else {
+                            settings.overlay.retain(|other| other != *name);
                         }
                     }
                 }
+            });
+        });
+
+        let curves = &self.plot.curves;
+        ui.horizontal(|ui| {
+            for (idx, (name, _)) in curves.iter().enumerate() {
+                ui.colored_label(plot::series_color(idx), name.as_str());
+            }
+        });
+
+        let series: Vec<&[f32]> = curves.iter().map(|(_, values)| values.as_slice()).collect();
+        let y_range = plot::value_range(series.iter().copied());
+        let height = ui.available_height();
+        let response = plot::overlay_plot(ui, &series, y_range, height);
+        let n_values = series.first().map_or(0, |values| values.len());
+        if let Some(idx) = plot::hovered_index(&response, n_values) {
+            let x = self.saved.plot.xs().nth(idx).unwrap_or_default();
+            let mut text = format!("x = {:.4}", x);
+            for (name, values) in curves {
+                text += &format!("\n{}: {:.4}", name, values[idx]);
             }
+            response.on_hover_text(text);
         }
     }
 
-    pub fn save_vor_file(&self) {
+    pub fn save_vor_file(&mut self) {
         if let Some(path) = rfd::FileDialog::new()
             .set_title("Save .vor file")
             .set_file_name("project.vor")
@@ -510,10 +1627,22 @@ impl VorpalApp {
             .add_filter("vor", &["vor"])
             .pick_file()
         {
-            self.saved = SaveState::load_vor_file(path).unwrap();
+            self.saved = SaveState::load_vor_file(&path).unwrap();
+            // The file may come from anyone, so its project directory waits for confirmation
+            self.pending_project_dir = self.saved.project_dir.take();
             self.engine = None;
         }
     }
+
+    pub fn choose_project_dir(&mut self) {
+        if let Some(path) = rfd::FileDialog::new()
+            .set_title("Choose the folder the plugin can access")
+            .pick_folder()
+        {
+            self.saved.project_dir = Some(path);
+            self.pending_project_dir = None;
+        }
+    }
 }
 
 impl SaveState {
@@ -529,17 +1658,26 @@ impl SaveState {
     }
 }
 
-fn new_widget() -> NodeGraphWidget {
-    NodeGraphWidget::new(image_fn_inputs(), DataType::Vec4, "RGBA".into())
+/// A function taking the inputs of the given mode
+fn new_widget(mode: HostMode) -> NodeGraphWidget {
+    match mode {
+        HostMode::Image => NodeGraphWidget::new(image_fn_inputs(), DataType::Vec4, "RGBA".into()),
+        HostMode::Audio => {
+            NodeGraphWidget::new(audio_fn_inputs(), DataType::Scalar, "Sample".into())
+        }
+        HostMode::Mesh => NodeGraphWidget::new(mesh_fn_inputs(), DataType::Vec3, "Position".into()),
+        HostMode::Particles => {
+            NodeGraphWidget::new(particle_fn_inputs(), DataType::Vec2, "Acceleration".into())
+        }
+        HostMode::Plot => NodeGraphWidget::new(plot_fn_inputs(), DataType::Scalar, "Y".into()),
+    }
 }
 
 impl SaveState {
     pub fn selected_fn_widget(&mut self) -> &mut NodeGraphWidget {
         if self.functions.is_empty() {
-            self.functions.push((
-                "unnamed".to_string(),
-                new_widget()
-            ));
+            self.functions
+                .push(("unnamed".to_string(), new_widget(self.mode)));
         }
 
         self.selected_function = self.selected_function.min(self.functions.len() - 1);
@@ -548,6 +1686,195 @@ impl SaveState {
     }
 }
 
+/// Add lines to the console, dropping the oldest ones past `MAX_CONSOLE_LINES`
+fn extend_console(console: &mut Vec<String>, lines: Vec<String>) {
+    console.extend(lines);
+    let excess = console.len().saturating_sub(MAX_CONSOLE_LINES);
+    console.drain(..excess);
+}
+
+impl HostMode {
+    fn all() -> [Self; 5] {
+        [
+            Self::Image,
+            Self::Audio,
+            Self::Mesh,
+            Self::Particles,
+            Self::Plot,
+        ]
+    }
+}
+
+impl std::fmt::Display for HostMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Image => write!(f, "Image"),
+            Self::Audio => write!(f, "Audio"),
+            Self::Mesh => write!(f, "Mesh"),
+            Self::Particles => write!(f, "Particles"),
+            Self::Plot => write!(f, "Plot"),
+        }
+    }
+}
+
+/// An error message, and the graph node it came from if known
+type NodeError = (Option<graph::NodeId>, String);
+
+/// Split type errors into one message per node at fault
+fn node_errors(error: &anyhow::Error) -> Vec<NodeError> {
+    match error.downcast_ref::<EvalError>() {
+        Some(EvalError::TypeCheck(diagnostics)) => diagnostics
+            .iter()
+            .map(|diagnostic| {
+                let msg = format!(
+                    "{}; expected {}, found {}",
+                    diagnostic.kind, diagnostic.expected, diagnostic.found
+                );
+                (Some(diagnostic.node), msg)
+            })
+            .collect(),
+        _ => vec![(None, format!("{:#}", error))],
+    }
+}
+
+/// Ranges of the inputs supplied to every image function
+fn image_fn_bounds(width: usize, height: usize) -> ParameterBounds {
+    let (width, height) = (width as f32, height as f32);
+    [
+        (vorpal_ui::TIME_KEY, vec![Interval::new(0., f32::INFINITY)]),
+        (
+            vorpal_ui::POS_KEY,
+            vec![Interval::new(0., width), Interval::new(0., height)],
+        ),
+        (
+            vorpal_ui::RESOLUTION_KEY,
+            vec![Interval::point(width), Interval::point(height)],
+        ),
+        // The cursor is at (-1, -1) when not pressed
+        (
+            vorpal_ui::CURSOR_KEY,
+            vec![Interval::new(-1., width), Interval::new(-1., height)],
+        ),
+    ]
+    .into_iter()
+    .map(|(key, range)| (ExternInputId::new(key.to_string()), range))
+    .collect()
+}
+
+/// Operations which may produce NaN or infinity, given the declared bounds of the parameters.
+/// Graphs which do not typecheck have errors to show instead.
+fn range_warnings(
+    graph: &Graph,
+    params: &ParameterList,
+    builtin_bounds: &ParameterBounds,
+    declared_bounds: &ParameterBounds,
+) -> Vec<NodeError> {
+    if typecheck::infer_dtypes(graph, params).is_err() {
+        return vec![];
+    }
+
+    let mut bounds = builtin_bounds.clone();
+    bounds.extend(
+        declared_bounds
+            .iter()
+            .map(|(id, range)| (id.clone(), range.clone())),
+    );
+
+    range_analysis::analyze(graph, &bounds)
+        .warnings
+        .into_iter()
+        .map(|warning| (Some(warning.node), warning.to_string()))
+        .collect()
+}
+
+/// Edit the declared bounds of a parameter, lane by lane
+fn bounds_editor(ui: &mut Ui, id: &ExternInputId, dtype: DataType, bounds: &mut ParameterBounds) {
+    ui.horizontal(|ui| {
+        ui.label(id.to_string());
+        match bounds.get_mut(id) {
+            Some(range) if range.len() == dtype.n_lanes() => {
+                for (lane, interval) in dtype.lane_names().zip(range.iter_mut()) {
+                    ui.label(lane.to_string());
+                    ui.add(DragValue::new(&mut interval.lo).speed(0.01));
+                    ui.add(DragValue::new(&mut interval.hi).speed(0.01));
+                    interval.hi = interval.hi.max(interval.lo);
+                }
+                if ui.button("Unbound").clicked() {
+                    bounds.remove(id);
+                }
+            }
+            _ => {
+                if ui.button("Bound").clicked() {
+                    bounds.insert(id.clone(), vec![Interval::new(0., 1.); dtype.n_lanes()]);
+                }
+            }
+        }
+    });
+}
+
+/// Fill the image with red, to show that something went wrong
+fn paint_error(image_data: &mut NdArray<f32>) {
+    image_data
+        .data_mut()
+        .iter_mut()
+        .zip([1., 0., 0., 0.].into_iter().cycle())
+        .for_each(|(o, i)| *o = i);
+}
+
+/// Evaluate an image function over every pixel at once using the native interpreter
+fn eval_image_native(
+    graph: &Graph,
+    params: &ParameterList,
+    extern_parameters: &ExternParameters,
+    width: usize,
+    height: usize,
+    time: f64,
+    precision: Precision,
+) -> Result<NdArray<f32>, EvalError> {
+    let program = native_backend::Program::new(graph, params)?;
+    let size = (width, height);
+    match precision {
+        Precision::Single => eval_image_batch::<f32>(&program, extern_parameters, size, time),
+        Precision::Double => eval_image_batch::<f64>(&program, extern_parameters, size, time),
+    }
+}
+
+/// Evaluate an image function in the precision of `F`. The time is passed separately from the
+/// other inputs so that it is not rounded to `f32` first.
+fn eval_image_batch<F: Float>(
+    program: &native_backend::Program,
+    extern_parameters: &ExternParameters,
+    (width, height): (usize, usize),
+    time: f64,
+) -> Result<NdArray<f32>, EvalError> {
+    let mut positions = NdArray::zeros(vec![height, width, 2]);
+    for y in 0..height {
+        for x in 0..width {
+            positions[[y, x, 0]] = F::from_f32(x as f32);
+            positions[[y, x, 1]] = F::from_f32(y as f32);
+        }
+    }
+
+    let mut time_array = NdArray::zeros(vec![1]);
+    time_array.data_mut()[0] = F::from_f64(time);
+
+    let varying = [
+        (ExternInputId::new(vorpal_ui::POS_KEY.into()), positions),
+        (ExternInputId::new(vorpal_ui::TIME_KEY.into()), time_array),
+    ]
+    .into_iter()
+    .collect();
+
+    let out = program.evaluate_batch(extern_parameters, &varying)?;
+
+    // The image is always displayed in single precision
+    let mut image = NdArray::zeros(out.shape().to_vec());
+    for (pixel, value) in image.data_mut().iter_mut().zip(out.data()) {
+        *pixel = value.to_f64() as f32;
+    }
+    Ok(image)
+}
+
 fn dtype_selector(idx: usize, ui: &mut Ui, dtype: &mut DataType) {
     ComboBox::new((idx, "dtype selector"), "")
         .selected_text(dtype.to_string())
@@ -557,3 +1884,21 @@ fn dtype_selector(idx: usize, ui: &mut Ui, dtype: &mut DataType) {
             }
         });
 }
+
+/// Choose one of the functions, or `none_text` for none of them
+fn fn_selector(
+    ui: &mut Ui,
+    label: &str,
+    none_text: &str,
+    selected: &mut Option<FuncName>,
+    names: &[&FuncName],
+) {
+    ComboBox::from_label(label)
+        .selected_text(selected.as_deref().unwrap_or(none_text))
+        .show_ui(ui, |ui| {
+            ui.selectable_value(selected, None, none_text);
+            for name in names {
+                ui.selectable_value(selected, Some(name.to_string()), name.as_str());
+            }
+        });
+}
@@ -0,0 +1,225 @@
+//! Functions which generate audio, one sample at a time
+use anyhow::{bail, ensure, Result};
+use std::path::Path;
+use vorpal_core::{DataType, ExternInputId, ParameterList};
+use vorpal_wasm::BlockInput;
+
+/// What to render, and the values of the note inputs
+#[cfg_attr(feature = "persistence", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioSettings {
+    /// Samples per second
+    pub sample_rate: u32,
+    /// Seconds of audio to render
+    pub duration: f32,
+    /// Frequency of the note being played, in Hz
+    pub note: f32,
+    /// How hard the note was played, from 0 to 1
+    pub velocity: f32,
+    /// 1 while the note is held, 0 once released
+    pub gate: f32,
+}
+
+/// Rendered audio, with the channels of each sample interleaved
+#[derive(Clone, Debug, Default)]
+pub struct AudioSamples {
+    pub sample_rate: u32,
+    /// 1 for mono, 2 for stereo
+    pub channels: usize,
+    pub data: Vec<f32>,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            sample_rate: 44_100,
+            duration: 2.,
+            note: 440.,
+            velocity: 1.,
+            gate: 1.,
+        }
+    }
+}
+
+/// Parameters of new audio functions
+pub fn audio_fn_inputs() -> ParameterList {
+    let params = [
+        crate::TIME_KEY,
+        crate::SAMPLE_INDEX_KEY,
+        crate::NOTE_KEY,
+        crate::VELOCITY_KEY,
+        crate::GATE_KEY,
+    ]
+    .into_iter()
+    .map(|name| (ExternInputId::new(name.into()), DataType::Scalar))
+    .collect();
+
+    ParameterList(params)
+}
+
+/// Number of channels of a function with the given output
+pub fn output_channels(dtype: DataType) -> Result<usize> {
+    match dtype {
+        DataType::Scalar => Ok(1),
+        DataType::Vec2 => Ok(2),
+        _ => bail!("Audio functions must output a Scalar (mono) or Vec2 (stereo), not {dtype}"),
+    }
+}
+
+impl AudioSettings {
+    /// Total number of samples to render
+    pub fn n_samples(&self) -> u32 {
+        (self.duration.max(0.) * self.sample_rate as f32) as u32
+    }
+
+    /// How each parameter is computed when rendering a block, and the values of the constant
+    /// ones in order
+    pub fn block_inputs(&self, params: &ParameterList) -> Result<(Vec<BlockInput>, Vec<f64>)> {
+        let mut inputs = vec![];
+        let mut constants = vec![];
+        for (id, dtype) in &params.0 {
+            let input = match id.to_string().as_str() {
+                crate::TIME_KEY => BlockInput::Time,
+                crate::SAMPLE_INDEX_KEY => BlockInput::SampleIndex,
+                name => {
+                    let value = match name {
+                        crate::NOTE_KEY => self.note,
+                        crate::VELOCITY_KEY => self.velocity,
+                        crate::GATE_KEY => self.gate,
+                        _ => bail!("Audio functions cannot take the parameter {name}"),
+                    };
+                    ensure!(*dtype == DataType::Scalar, "{name} must be a Scalar");
+                    constants.push(value.into());
+                    BlockInput::Constant
+                }
+            };
+            inputs.push(input);
+        }
+        Ok((inputs, constants))
+    }
+}
+
+impl AudioSamples {
+    pub fn n_frames(&self) -> usize {
+        self.data.len() / self.channels.max(1)
+    }
+
+    /// Samples of one channel
+    pub fn channel(&self, channel: usize) -> impl Iterator<Item = f32> + '_ {
+        self.data
+            .iter()
+            .skip(channel)
+            .step_by(self.channels.max(1))
+            .copied()
+    }
+
+    /// Write a 16 bit PCM WAV file. Samples are clamped to -1..=1.
+    pub fn save_wav(&self, path: impl AsRef<Path>) -> Result<()> {
+        let channels = self.channels as u16;
+        let block_align = channels * 2;
+        let data_len = (self.data.len() * 2) as u32;
+
+        let mut bytes = Vec::with_capacity(44 + data_len as usize);
+        bytes.extend(b"RIFF");
+        bytes.extend((36 + data_len).to_le_bytes());
+        bytes.extend(b"WAVE");
+
+        bytes.extend(b"fmt ");
+        bytes.extend(16_u32.to_le_bytes());
+        // PCM
+        bytes.extend(1_u16.to_le_bytes());
+        bytes.extend(channels.to_le_bytes());
+        bytes.extend(self.sample_rate.to_le_bytes());
+        bytes.extend((self.sample_rate * u32::from(block_align)).to_le_bytes());
+        bytes.extend(block_align.to_le_bytes());
+        bytes.extend(16_u16.to_le_bytes());
+
+        bytes.extend(b"data");
+        bytes.extend(data_len.to_le_bytes());
+        for sample in &self.data {
+            let sample = (sample.clamp(-1., 1.) * f32::from(i16::MAX)) as i16;
+            bytes.extend(sample.to_le_bytes());
+        }
+
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    /// Magnitude of each frequency in decibels, from 0 Hz to half the sample rate, averaged over
+    /// windows of `2 * n_bins` samples of the channels' mix
+    pub fn spectrum(&self, n_bins: usize) -> Vec<f32> {
+        let window_len = (2 * n_bins).next_power_of_two();
+        let mono: Vec<f32> = (0..self.n_frames())
+            .map(|frame| {
+                let samples = &self.data[frame * self.channels..][..self.channels];
+                samples.iter().sum::<f32>() / self.channels as f32
+            })
+            .collect();
+
+        let mut power = vec![0_f32; window_len / 2];
+        let mut n_windows = 0;
+        for window in mono.chunks_exact(window_len) {
+            let mut re: Vec<f32> = window
+                .iter()
+                .enumerate()
+                .map(|(idx, x)| x * hann(idx, window_len))
+                .collect();
+            let mut im = vec![0.; window_len];
+            fft(&mut re, &mut im);
+
+            for (bin, power) in power.iter_mut().enumerate() {
+                *power += re[bin] * re[bin] + im[bin] * im[bin];
+            }
+            n_windows += 1;
+        }
+
+        let scale = 1. / (n_windows.max(1) as f32 * window_len as f32);
+        power
+            .into_iter()
+            .map(|power| 10. * (power * scale).max(1e-12).log10())
+            .collect()
+    }
+}
+
+fn hann(idx: usize, len: usize) -> f32 {
+    let phase = idx as f32 / len as f32;
+    0.5 - 0.5 * (std::f32::consts::TAU * phase).cos()
+}
+
+/// In-place radix 2 FFT. The length must be a power of two.
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+
+    // Bit reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -std::f32::consts::TAU / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * cos - im[b] * sin;
+                let t_im = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
@@ -0,0 +1,43 @@
+//! The functions compiled graphs import from the `builtins` module, implemented on the host with
+//! the same code as native evaluation. Building with the `wasm-builtins` feature links the
+//! `vorpal-wasm-builtins` module instead, so that compiled graphs run entirely inside the sandbox.
+use anyhow::Result;
+use vorpal_core::Precision;
+use vorpal_wasm::{builtin_suffix, BUILTINS_MODULE, BUILTIN_FNS, BUILTIN_OPS};
+use wasm_bridge::Linker;
+
+use crate::host::HostState;
+
+/// Define the builtin functions of both precisions in `linker`
+pub fn add_to_linker(linker: &mut Linker<HostState>) -> Result<()> {
+    let single = builtin_suffix(Precision::Single);
+    let double = builtin_suffix(Precision::Double);
+
+    for (name, func) in BUILTIN_FNS {
+        linker.func_wrap(
+            BUILTINS_MODULE,
+            &format!("{name}{single}"),
+            move |x: f32| func.native(x),
+        )?;
+        linker.func_wrap(
+            BUILTINS_MODULE,
+            &format!("{name}{double}"),
+            move |x: f64| func.native(x),
+        )?;
+    }
+
+    for (name, op) in BUILTIN_OPS {
+        linker.func_wrap(
+            BUILTINS_MODULE,
+            &format!("{name}{single}"),
+            move |a: f32, b: f32| op.native(a, b),
+        )?;
+        linker.func_wrap(
+            BUILTINS_MODULE,
+            &format!("{name}{double}"),
+            move |a: f64, b: f64| op.native(a, b),
+        )?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,86 @@
+//! Scalar functions of x, sampled evenly over a range for plotting
+use anyhow::{ensure, Result};
+use vorpal_core::graph::Graph;
+use vorpal_core::native_backend::Program;
+use vorpal_core::ndarray::NdArray;
+use vorpal_core::{DataType, ExternInputId, ExternParameters, Float, ParameterList, Precision};
+
+/// Where to sample the functions, and which to plot along with the selected one
+#[cfg_attr(feature = "persistence", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlotSettings {
+    /// First and last value of x
+    pub x_range: [f32; 2],
+    /// Number of values of x, spaced evenly across the range
+    pub n_samples: u32,
+    /// Value of the time input
+    pub time: f32,
+    /// Names of the other functions to plot
+    pub overlay: Vec<String>,
+}
+
+impl Default for PlotSettings {
+    fn default() -> Self {
+        Self {
+            x_range: [0., 1.],
+            n_samples: 1000,
+            time: 0.,
+            overlay: vec![],
+        }
+    }
+}
+
+/// Parameters of new plotted functions
+pub fn plot_fn_inputs() -> ParameterList {
+    ParameterList(vec![
+        (ExternInputId::new(crate::X_KEY.into()), DataType::Scalar),
+        (ExternInputId::new(crate::TIME_KEY.into()), DataType::Scalar),
+    ])
+}
+
+impl PlotSettings {
+    /// The values of x the functions are sampled at
+    pub fn xs(&self) -> impl Iterator<Item = f64> {
+        let [start, end] = self.x_range.map(f64::from);
+        let n = self.n_samples.max(2);
+        (0..n).map(move |idx| start + (end - start) * idx as f64 / (n - 1) as f64)
+    }
+
+    /// Value of a function at each of `xs()`
+    pub fn sample(
+        &self,
+        graph: &Graph,
+        params: &ParameterList,
+        precision: Precision,
+    ) -> Result<Vec<f32>> {
+        let program = Program::new(graph, params)?;
+        match precision {
+            Precision::Single => self.sample_batch::<f32>(&program),
+            Precision::Double => self.sample_batch::<f64>(&program),
+        }
+    }
+
+    fn sample_batch<F: Float>(&self, program: &Program) -> Result<Vec<f32>> {
+        let xs: Vec<F> = self.xs().map(F::from_f64).collect();
+        let n = xs.len();
+        let mut x_array = NdArray::zeros(vec![n, 1]);
+        x_array.data_mut().copy_from_slice(&xs);
+
+        let mut time = NdArray::zeros(vec![1]);
+        time.data_mut()[0] = F::from_f32(self.time);
+
+        let varying = [
+            (ExternInputId::new(crate::X_KEY.into()), x_array),
+            (ExternInputId::new(crate::TIME_KEY.into()), time),
+        ]
+        .into_iter()
+        .collect();
+
+        let out = program.evaluate_batch(&ExternParameters::default(), &varying)?;
+        ensure!(
+            out.shape() == [n, 1],
+            "Plotted functions must output a Scalar"
+        );
+        Ok(out.data().iter().map(|y| y.to_f64() as f32).collect())
+    }
+}
@@ -0,0 +1,128 @@
+//! Functions which plugins can import from the `vorpal_host` module:
+//!
+//! ```ignore
+//! #[link(wasm_import_module = "vorpal_host")]
+//! extern "C" {
+//!     /// Print a UTF-8 message to the console
+//!     fn log(ptr: *const u8, len: usize);
+//!     /// Value of the parameter with the given name, or NaN if there is none
+//!     fn get_param(name_ptr: *const u8, name_len: usize) -> f32;
+//!     /// Number of frames drawn since the plugin was loaded
+//!     fn frame_index() -> u64;
+//!     /// Seconds since the previous frame
+//!     fn delta_time() -> f32;
+//!     /// Ask for the following frames to be drawn at a different size
+//!     fn request_resolution(width: u32, height: u32);
+//! }
+//! ```
+//!
+//! Parameters are the inputs the host passes to the project's functions. Scalars keep their
+//! name, and vectors are split into one parameter per lane, named `{name}.x`, `{name}.y` and so
+//! on. An image plugin can read `Time (seconds)`, `Resolution (pixels).x`,
+//! `Resolution (pixels).y`, `Cursor position (pixels).x` and `Cursor position (pixels).y`.
+use anyhow::{ensure, Context, Result};
+use std::collections::HashMap;
+use vorpal_core::ExternParameters;
+use wasm_bridge::{Caller, Linker, Memory};
+use wasmtime_wasi::WasiCtx;
+
+use crate::wasi::ConsoleOutput;
+
+/// Name of the import module
+pub const HOST_MODULE: &str = "vorpal_host";
+
+/// Data of each store, which the host functions read and write
+#[derive(Default)]
+pub struct HostState {
+    /// The memory shared by every module
+    pub memory: Option<Memory>,
+    /// Messages logged since they were last taken
+    pub log: Vec<String>,
+    /// Parameters by name, as given by `param_lanes`
+    pub params: HashMap<String, f32>,
+    pub frame_index: u64,
+    pub delta_time: f32,
+    /// Resolution the plugin asked for since it was last taken
+    pub requested_resolution: Option<(u32, u32)>,
+    /// Set if the plugin was linked with WASI
+    pub wasi: Option<WasiCtx>,
+    /// Where WASI's stdout and stderr are written
+    pub console: ConsoleOutput,
+}
+
+/// Define the host functions in `linker`
+pub fn add_to_linker(linker: &mut Linker<HostState>) -> Result<()> {
+    linker.func_wrap(
+        HOST_MODULE,
+        "log",
+        |mut caller: Caller<'_, HostState>, ptr: u32, len: u32| -> Result<()> {
+            let message = read_string(&mut caller, ptr, len)?;
+            caller.data_mut().log.push(message);
+            Ok(())
+        },
+    )?;
+
+    linker.func_wrap(
+        HOST_MODULE,
+        "get_param",
+        |mut caller: Caller<'_, HostState>, ptr: u32, len: u32| -> Result<f32> {
+            let name = read_string(&mut caller, ptr, len)?;
+            Ok(caller.data().params.get(&name).copied().unwrap_or(f32::NAN))
+        },
+    )?;
+
+    linker.func_wrap(
+        HOST_MODULE,
+        "frame_index",
+        |caller: Caller<'_, HostState>| -> u64 { caller.data().frame_index },
+    )?;
+
+    linker.func_wrap(
+        HOST_MODULE,
+        "delta_time",
+        |caller: Caller<'_, HostState>| -> f32 { caller.data().delta_time },
+    )?;
+
+    linker.func_wrap(
+        HOST_MODULE,
+        "request_resolution",
+        |mut caller: Caller<'_, HostState>, width: u32, height: u32| {
+            caller.data_mut().requested_resolution = Some((width, height));
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Every lane of the inputs, under the names `get_param` looks them up by
+pub fn param_lanes(ctx: &ExternParameters) -> HashMap<String, f32> {
+    ctx.inputs()
+        .iter()
+        .flat_map(|(id, value)| {
+            let dtype = value.dtype();
+            let names: Vec<String> = if dtype.n_lanes() == 1 {
+                vec![id.to_string()]
+            } else {
+                dtype
+                    .lane_names()
+                    .map(|lane| format!("{id}.{lane}"))
+                    .collect()
+            };
+            names.into_iter().zip(value.iter_vector_floats())
+        })
+        .collect()
+}
+
+/// Copy a UTF-8 string out of the shared memory, replacing invalid sequences
+fn read_string(caller: &mut Caller<'_, HostState>, ptr: u32, len: u32) -> Result<String> {
+    let memory = caller.data().memory.context("No memory to read from")?;
+    let (ptr, len) = (ptr as usize, len as usize);
+    ensure!(
+        ptr.saturating_add(len) <= memory.data_size(&*caller),
+        "String is out of bounds"
+    );
+
+    let mut bytes = vec![0; len];
+    memory.read(&*caller, ptr, &mut bytes)?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
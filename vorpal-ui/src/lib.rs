@@ -4,13 +4,29 @@
 
 // ----------------------------------------------------------------------------
 // When compiling for web:
+pub mod audio;
+pub mod builtins;
+pub mod curves;
 pub mod file_watcher;
+pub mod host;
+pub mod manifest;
+pub mod mesh;
+pub mod particles;
+pub mod wasi;
 pub mod wasmtime_integration;
+pub mod worker;
 
 pub const TIME_KEY: &str = "Time (seconds)";
 pub const POS_KEY: &str = "Position (pixels)";
 pub const RESOLUTION_KEY: &str = "Resolution (pixels)";
 pub const CURSOR_KEY: &str = "Cursor position (pixels)";
+pub const SAMPLE_INDEX_KEY: &str = "Sample index";
+pub const NOTE_KEY: &str = "Note (Hz)";
+pub const VELOCITY_KEY: &str = "Velocity";
+pub const GATE_KEY: &str = "Gate";
+pub const UV_KEY: &str = "UV";
+pub const PARTICLE_VELOCITY_KEY: &str = "Velocity (pixels per second)";
+pub const X_KEY: &str = "X";
 
 /*
 #[cfg(target_arch = "wasm32")]
@@ -0,0 +1,254 @@
+//! Plugins describe the functions they import and how they are called in a manifest, stored as
+//! JSON in a `vorpal_manifest` custom section of their wasm module. For example:
+//!
+//! ```json
+//! {
+//!     "entry": "make_image",
+//!     "inputs": ["width", "height", "time", "cursor_x", "cursor_y"],
+//!     "output": "rgba_f32",
+//!     "functions": [
+//!         {
+//!             "name": "kernel",
+//!             "params": [["Time (seconds)", "Scalar"], ["Position (pixels)", "Vec2"]],
+//!             "output": "Vec4"
+//!         }
+//!     ]
+//! }
+//! ```
+use anyhow::{bail, ensure, format_err, Context, Result};
+use serde::Deserialize;
+use vorpal_core::{DataType, ExternInputId, ParameterList};
+
+/// Name of the custom section holding the manifest
+pub const MANIFEST_SECTION: &str = "vorpal_manifest";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PluginManifest {
+    /// Exported function called once per frame
+    pub entry: String,
+    /// Arguments of the entry function, in order
+    pub inputs: Vec<HostInput>,
+    /// What the entry function returns a pointer to
+    pub output: OutputFormat,
+    /// Functions the plugin imports, which the project must define
+    pub functions: Vec<FunctionManifest>,
+}
+
+/// A value the host passes to the entry function
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HostInput {
+    /// Image width in pixels, as an `i32`
+    Width,
+    /// Image height in pixels, as an `i32`
+    Height,
+    /// Seconds since the start, as a float of the project's precision
+    Time,
+    /// Cursor position in pixels, as floats of the project's precision
+    CursorX,
+    CursorY,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    /// `width * height` pixels of four `f32`s each, row by row
+    RgbaF32,
+}
+
+/// A function the plugin imports from a module of the same name
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionManifest {
+    pub name: String,
+    pub params: ParameterList,
+    pub output: DataType,
+}
+
+/// The manifest as written, with datatypes by name
+#[derive(Deserialize)]
+struct ManifestFile {
+    entry: String,
+    inputs: Vec<HostInput>,
+    output: OutputFormat,
+    #[serde(default)]
+    functions: Vec<FunctionFile>,
+}
+
+#[derive(Deserialize)]
+struct FunctionFile {
+    name: String,
+    params: Vec<(String, String)>,
+    output: String,
+}
+
+impl PluginManifest {
+    /// Read the manifest of a wasm module, falling back to `PluginManifest::legacy` for modules
+    /// without one
+    pub fn from_wasm(wasm: &[u8]) -> Result<Self> {
+        match custom_section(wasm, MANIFEST_SECTION)? {
+            Some(json) => Self::from_json(json).context("Reading plugin manifest"),
+            None => Ok(Self::legacy()),
+        }
+    }
+
+    pub fn from_json(json: &[u8]) -> Result<Self> {
+        let file: ManifestFile = serde_json::from_slice(json)?;
+
+        let functions = file
+            .functions
+            .into_iter()
+            .map(|function| {
+                let params = function
+                    .params
+                    .into_iter()
+                    .map(|(name, dtype)| Ok((ExternInputId::new(name), parse_dtype(&dtype)?)))
+                    .collect::<Result<_>>()?;
+                Ok(FunctionManifest {
+                    name: function.name,
+                    params: ParameterList(params),
+                    output: parse_dtype(&function.output)?,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            entry: file.entry,
+            inputs: file.inputs,
+            output: file.output,
+            functions,
+        })
+    }
+
+    /// The ABI of plugins which predate manifests. The functions they import are unknown, so they
+    /// are not validated.
+    pub fn legacy() -> Self {
+        Self {
+            entry: "make_image".into(),
+            inputs: vec![
+                HostInput::Width,
+                HostInput::Height,
+                HostInput::Time,
+                HostInput::CursorX,
+                HostInput::CursorY,
+            ],
+            output: OutputFormat::RgbaF32,
+            functions: vec![],
+        }
+    }
+
+    /// Problems with the project's functions, given as their name, parameters and output
+    /// datatype if known
+    pub fn validate(
+        &self,
+        functions: &[(&str, &ParameterList, Option<DataType>)],
+    ) -> Vec<ManifestProblem> {
+        self.functions
+            .iter()
+            .filter_map(|expected| {
+                let found = functions.iter().find(|(name, _, _)| *name == expected.name);
+                let kind = match found {
+                    None => ProblemKind::Missing,
+                    Some((_, params, _)) if **params != expected.params => ProblemKind::WrongParams,
+                    Some((_, _, Some(output))) if *output != expected.output => {
+                        ProblemKind::WrongOutput(*output)
+                    }
+                    Some(_) => return None,
+                };
+                Some(ManifestProblem {
+                    expected: expected.clone(),
+                    kind,
+                })
+            })
+            .collect()
+    }
+}
+
+/// A project's function which does not match the manifest
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManifestProblem {
+    /// The function as the plugin expects it
+    pub expected: FunctionManifest,
+    pub kind: ProblemKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProblemKind {
+    Missing,
+    WrongParams,
+    /// The function outputs the given datatype instead
+    WrongOutput(DataType),
+}
+
+impl std::fmt::Display for ManifestProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = &self.expected.name;
+        match self.kind {
+            ProblemKind::Missing => write!(f, "The plugin requires a function {name}()"),
+            ProblemKind::WrongParams => {
+                write!(f, "The plugin requires {name}() to take parameters ")?;
+                let params: Vec<String> = self
+                    .expected
+                    .params
+                    .0
+                    .iter()
+                    .map(|(id, dtype)| format!("{}: {}", id, dtype))
+                    .collect();
+                write!(f, "({})", params.join(", "))
+            }
+            ProblemKind::WrongOutput(found) => write!(
+                f,
+                "The plugin requires {name}() to output {}, not {}",
+                self.expected.output, found
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ManifestProblem {}
+
+fn parse_dtype(name: &str) -> Result<DataType> {
+    Ok(match name {
+        "Scalar" => DataType::Scalar,
+        "Vec2" => DataType::Vec2,
+        "Vec3" => DataType::Vec3,
+        "Vec4" => DataType::Vec4,
+        _ => bail!("Unknown datatype {name}"),
+    })
+}
+
+/// Payload of the first custom section with the given name in a wasm binary
+fn custom_section<'a>(wasm: &'a [u8], name: &str) -> Result<Option<&'a [u8]>> {
+    ensure!(wasm.starts_with(b"\0asm"), "Not a wasm module");
+    // Skip the magic number and version
+    let mut rest = wasm.get(8..).context("Wasm module is truncated")?;
+
+    while let Some((&id, after_id)) = rest.split_first() {
+        let (size, after_size) = read_leb128(after_id)?;
+        let payload = after_size
+            .get(..size)
+            .context("Wasm section is truncated")?;
+        rest = &after_size[size..];
+
+        if id == 0 {
+            let (name_len, after_len) = read_leb128(payload)?;
+            let section_name = after_len.get(..name_len).context("Bad custom section")?;
+            if section_name == name.as_bytes() {
+                return Ok(Some(&after_len[name_len..]));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Unsigned LEB128 integer, and the bytes after it
+fn read_leb128(bytes: &[u8]) -> Result<(usize, &[u8])> {
+    let mut value = 0_usize;
+    for (idx, byte) in bytes.iter().enumerate().take(5) {
+        value |= usize::from(byte & 0x7f) << (7 * idx);
+        if byte & 0x80 == 0 {
+            return Ok((value, &bytes[idx + 1..]));
+        }
+    }
+    Err(format_err!("Bad LEB128 integer in wasm module"))
+}
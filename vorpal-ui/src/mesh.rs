@@ -0,0 +1,252 @@
+//! Functions which place the vertices of a mesh, given their coordinates on a grid
+use anyhow::{bail, ensure, Result};
+use std::fmt::Write;
+use std::path::Path;
+use vorpal_core::graph::Graph;
+use vorpal_core::native_backend::Program;
+use vorpal_core::ndarray::NdArray;
+use vorpal_core::{DataType, ExternInputId, ExternParameters, Float, ParameterList, Precision};
+
+/// How finely to sample the functions
+#[cfg_attr(feature = "persistence", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct MeshSettings {
+    /// Number of vertices along U and V
+    pub resolution: [u32; 2],
+    /// Value of the time input
+    pub time: f32,
+    /// Function giving the normal of each vertex, which are computed from the triangles if unset
+    pub normal_fn: Option<String>,
+    /// Function giving the color of each vertex, from 0 to 1
+    pub color_fn: Option<String>,
+}
+
+/// An indexed triangle mesh
+#[derive(Clone, Debug, Default)]
+pub struct Mesh {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    /// Color of each vertex, if any
+    pub colors: Option<Vec<[f32; 3]>>,
+    /// Vertices of each triangle, counterclockwise
+    pub triangles: Vec<[u32; 3]>,
+}
+
+impl Default for MeshSettings {
+    fn default() -> Self {
+        Self {
+            resolution: [64, 64],
+            time: 0.,
+            normal_fn: None,
+            color_fn: None,
+        }
+    }
+}
+
+/// Parameters of new mesh functions
+pub fn mesh_fn_inputs() -> ParameterList {
+    ParameterList(vec![
+        (ExternInputId::new(crate::UV_KEY.into()), DataType::Vec2),
+        (ExternInputId::new(crate::TIME_KEY.into()), DataType::Scalar),
+    ])
+}
+
+impl MeshSettings {
+    /// Evaluate a function at every vertex, giving an array of shape `[v, u, lanes]` with U and V
+    /// running from 0 to 1
+    pub fn eval_grid(
+        &self,
+        graph: &Graph,
+        params: &ParameterList,
+        precision: Precision,
+    ) -> Result<NdArray<f32>> {
+        let program = Program::new(graph, params)?;
+        Ok(match precision {
+            Precision::Single => self.eval_grid_batch::<f32>(&program)?,
+            Precision::Double => self.eval_grid_batch::<f64>(&program)?,
+        })
+    }
+
+    fn eval_grid_batch<F: Float>(&self, program: &Program) -> Result<NdArray<f32>> {
+        let [width, height] = self.resolution.map(|n| n.max(2) as usize);
+        let mut uvs = NdArray::zeros(vec![height, width, 2]);
+        for v in 0..height {
+            for u in 0..width {
+                uvs[[v, u, 0]] = F::from_f64(u as f64 / (width - 1) as f64);
+                uvs[[v, u, 1]] = F::from_f64(v as f64 / (height - 1) as f64);
+            }
+        }
+
+        let mut time = NdArray::zeros(vec![1]);
+        time.data_mut()[0] = F::from_f32(self.time);
+
+        let varying = [
+            (ExternInputId::new(crate::UV_KEY.into()), uvs),
+            (ExternInputId::new(crate::TIME_KEY.into()), time),
+        ]
+        .into_iter()
+        .collect();
+
+        let out = program.evaluate_batch(&ExternParameters::default(), &varying)?;
+        let mut grid = NdArray::zeros(out.shape().to_vec());
+        for (cell, value) in grid.data_mut().iter_mut().zip(out.data()) {
+            *cell = value.to_f64() as f32;
+        }
+        Ok(grid)
+    }
+}
+
+impl Mesh {
+    /// Connect the vertices of a `[v, u, lanes]` grid of positions with two triangles per cell.
+    /// Normals and colors are grids of the same size, and have 3 or more lanes.
+    pub fn from_grid(
+        positions: &NdArray<f32>,
+        normals: Option<&NdArray<f32>>,
+        colors: Option<&NdArray<f32>>,
+    ) -> Result<Self> {
+        let &[height, width, lanes] = positions.shape() else {
+            bail!("Positions must be a grid");
+        };
+        ensure!(lanes == 3, "Positions must be Vec3s");
+        for grid in normals.iter().chain(&colors) {
+            let shape = grid.shape();
+            ensure!(
+                shape[..2] == [height, width] && shape[2] >= 3,
+                "Normals and colors must be Vec3s or Vec4s"
+            );
+        }
+
+        let vec3s = |grid: &NdArray<f32>| -> Vec<[f32; 3]> {
+            let lanes = grid.shape()[2];
+            grid.data()
+                .chunks_exact(lanes)
+                .map(|v| [v[0], v[1], v[2]])
+                .collect()
+        };
+
+        let mut triangles = vec![];
+        for v in 0..height.saturating_sub(1) {
+            for u in 0..width.saturating_sub(1) {
+                let idx = |u: usize, v: usize| (v * width + u) as u32;
+                let (a, b) = (idx(u, v), idx(u + 1, v));
+                let (c, d) = (idx(u, v + 1), idx(u + 1, v + 1));
+                triangles.push([a, b, d]);
+                triangles.push([a, d, c]);
+            }
+        }
+
+        let mut mesh = Self {
+            positions: vec3s(positions),
+            normals: vec![],
+            colors: colors.map(vec3s),
+            triangles,
+        };
+        mesh.normals = match normals {
+            Some(normals) => vec3s(normals).into_iter().map(normalize).collect(),
+            None => mesh.vertex_normals(),
+        };
+        Ok(mesh)
+    }
+
+    /// Normals of each vertex, averaged over the triangles around it weighted by their area
+    pub fn vertex_normals(&self) -> Vec<[f32; 3]> {
+        let mut normals = vec![[0.; 3]; self.positions.len()];
+        for triangle in &self.triangles {
+            let normal = self.face_normal(*triangle);
+            for idx in triangle {
+                let sum = &mut normals[*idx as usize];
+                (0..3).for_each(|axis| sum[axis] += normal[axis]);
+            }
+        }
+        normals.into_iter().map(normalize).collect()
+    }
+
+    /// Normal of a triangle, with a length of twice its area
+    pub fn face_normal(&self, [a, b, c]: [u32; 3]) -> [f32; 3] {
+        let [a, b, c] = [a, b, c].map(|idx| self.positions[idx as usize]);
+        cross(sub(b, a), sub(c, a))
+    }
+
+    /// Write a Wavefront OBJ file, with vertex colors after the positions if there are any
+    pub fn save_obj(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut text = String::new();
+        writeln!(text, "# Generated by Vorpal")?;
+        for (idx, [x, y, z]) in self.positions.iter().enumerate() {
+            write!(text, "v {x} {y} {z}")?;
+            if let Some(colors) = &self.colors {
+                let [r, g, b] = colors[idx].map(|c| c.clamp(0., 1.));
+                write!(text, " {r} {g} {b}")?;
+            }
+            writeln!(text)?;
+        }
+        for [x, y, z] in &self.normals {
+            writeln!(text, "vn {x} {y} {z}")?;
+        }
+        for triangle in &self.triangles {
+            // OBJ indices start at 1
+            let [a, b, c] = triangle.map(|idx| idx + 1);
+            writeln!(text, "f {a}//{a} {b}//{b} {c}//{c}")?;
+        }
+
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+
+    /// Write an ASCII PLY file
+    pub fn save_ply(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut text = String::new();
+        writeln!(text, "ply")?;
+        writeln!(text, "format ascii 1.0")?;
+        writeln!(text, "comment Generated by Vorpal")?;
+        writeln!(text, "element vertex {}", self.positions.len())?;
+        for property in ["x", "y", "z", "nx", "ny", "nz"] {
+            writeln!(text, "property float {property}")?;
+        }
+        if self.colors.is_some() {
+            for property in ["red", "green", "blue"] {
+                writeln!(text, "property uchar {property}")?;
+            }
+        }
+        writeln!(text, "element face {}", self.triangles.len())?;
+        writeln!(text, "property list uchar uint vertex_indices")?;
+        writeln!(text, "end_header")?;
+
+        for (idx, ([x, y, z], [nx, ny, nz])) in self.positions.iter().zip(&self.normals).enumerate()
+        {
+            write!(text, "{x} {y} {z} {nx} {ny} {nz}")?;
+            if let Some(colors) = &self.colors {
+                let [r, g, b] = colors[idx].map(|c| (c.clamp(0., 1.) * 255.).round() as u8);
+                write!(text, " {r} {g} {b}")?;
+            }
+            writeln!(text)?;
+        }
+        for [a, b, c] in &self.triangles {
+            writeln!(text, "3 {a} {b} {c}")?;
+        }
+
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    std::array::from_fn(|axis| a[axis] - b[axis])
+}
+
+fn cross([ax, ay, az]: [f32; 3], [bx, by, bz]: [f32; 3]) -> [f32; 3] {
+    [ay * bz - az * by, az * bx - ax * bz, ax * by - ay * bx]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    (0..3).map(|axis| a[axis] * b[axis]).sum()
+}
+
+/// Scale to a length of one, leaving zero vectors as they are
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let len = dot(v, v).sqrt();
+    if len > 0. {
+        v.map(|x| x / len)
+    } else {
+        v
+    }
+}
@@ -0,0 +1,191 @@
+//! Particles moved by a function giving their acceleration
+use anyhow::{ensure, Result};
+use vorpal_core::graph::Graph;
+use vorpal_core::native_backend::Program;
+use vorpal_core::ndarray::NdArray;
+use vorpal_core::{DataType, ExternInputId, ExternParameters, Float, ParameterList, Precision};
+
+/// How many particles there are, and how they are stepped and drawn
+#[cfg_attr(feature = "persistence", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ParticleSettings {
+    pub count: u32,
+    /// Fraction of the velocity lost each second, from 0 to 1
+    pub drag: f32,
+    /// Amount of light each particle adds to the image
+    pub brightness: f32,
+}
+
+/// Position and velocity of each particle, in pixels of the image
+#[derive(Clone, Debug, Default)]
+pub struct Particles {
+    pub positions: Vec<[f32; 2]>,
+    pub velocities: Vec<[f32; 2]>,
+}
+
+impl Default for ParticleSettings {
+    fn default() -> Self {
+        Self {
+            count: 10_000,
+            drag: 0.5,
+            brightness: 0.5,
+        }
+    }
+}
+
+/// Parameters of new particle functions
+pub fn particle_fn_inputs() -> ParameterList {
+    ParameterList(vec![
+        (ExternInputId::new(crate::POS_KEY.into()), DataType::Vec2),
+        (
+            ExternInputId::new(crate::PARTICLE_VELOCITY_KEY.into()),
+            DataType::Vec2,
+        ),
+        (ExternInputId::new(crate::TIME_KEY.into()), DataType::Scalar),
+        (ExternInputId::new(crate::CURSOR_KEY.into()), DataType::Vec2),
+    ])
+}
+
+impl Particles {
+    /// Particles at rest, spread evenly over a `width` by `height` area
+    pub fn scattered(count: usize, width: usize, height: usize) -> Self {
+        // Additive recurrence with the plastic number, which covers the plane without clumps
+        let plastic = 1.324_717_957_244_746_f64;
+        let step = [1. / plastic, 1. / (plastic * plastic)];
+        let positions = (0..count)
+            .map(|idx| {
+                let [x, y] = step.map(|step| (0.5 + step * idx as f64).fract() as f32);
+                [x * width as f32, y * height as f32]
+            })
+            .collect();
+
+        Self {
+            positions,
+            velocities: vec![[0.; 2]; count],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    /// Advance the particles by `dt` seconds, with the acceleration given by a function of their
+    /// position and velocity
+    pub fn step(
+        &mut self,
+        graph: &Graph,
+        params: &ParameterList,
+        uniforms: &ExternParameters,
+        dt: f32,
+        drag: f32,
+        precision: Precision,
+    ) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        let program = Program::new(graph, params)?;
+        let accelerations = match precision {
+            Precision::Single => self.accelerations::<f32>(&program, uniforms)?,
+            Precision::Double => self.accelerations::<f64>(&program, uniforms)?,
+        };
+
+        // Semi-implicit Euler, which keeps orbits from spiralling outwards
+        let damping = (1. - drag.clamp(0., 1.)).powf(dt);
+        let particles = self.positions.iter_mut().zip(&mut self.velocities);
+        for ((position, velocity), acceleration) in particles.zip(accelerations) {
+            for axis in 0..2 {
+                velocity[axis] = (velocity[axis] + acceleration[axis] * dt) * damping;
+                position[axis] += velocity[axis] * dt;
+            }
+        }
+        Ok(())
+    }
+
+    /// Bring the particles which left a `width` by `height` area back in from the opposite side
+    pub fn wrap(&mut self, width: usize, height: usize) {
+        for position in &mut self.positions {
+            position[0] = position[0].rem_euclid(width as f32);
+            position[1] = position[1].rem_euclid(height as f32);
+        }
+    }
+
+    fn accelerations<F: Float>(
+        &self,
+        program: &Program,
+        uniforms: &ExternParameters,
+    ) -> Result<Vec<[f32; 2]>> {
+        let to_array = |values: &[[f32; 2]]| {
+            let mut array = NdArray::zeros(vec![values.len(), 2]);
+            for (lanes, value) in array.data_mut().chunks_exact_mut(2).zip(values) {
+                lanes[0] = F::from_f32(value[0]);
+                lanes[1] = F::from_f32(value[1]);
+            }
+            array
+        };
+
+        let varying = [
+            (
+                ExternInputId::new(crate::POS_KEY.into()),
+                to_array(&self.positions),
+            ),
+            (
+                ExternInputId::new(crate::PARTICLE_VELOCITY_KEY.into()),
+                to_array(&self.velocities),
+            ),
+        ]
+        .into_iter()
+        .collect();
+
+        let out = program.evaluate_batch(uniforms, &varying)?;
+        ensure!(
+            out.shape() == [self.len(), 2],
+            "Particle functions must output a Vec2 acceleration"
+        );
+        Ok(out
+            .data()
+            .chunks_exact(2)
+            .map(|lanes| [lanes[0].to_f64() as f32, lanes[1].to_f64() as f32])
+            .collect())
+    }
+
+    /// Draw the particles as points of light on black, into an image of shape
+    /// `[width, height, 4]`. Each particle is shared between the four pixels nearest to it.
+    pub fn splat(&self, image: &mut NdArray<f32>, brightness: f32) {
+        let (width, height) = (image.shape()[0], image.shape()[1]);
+        let data = image.data_mut();
+        data.iter_mut()
+            .zip([0., 0., 0., 1.].into_iter().cycle())
+            .for_each(|(o, i)| *o = i);
+
+        for &[x, y] in &self.positions {
+            // Pixel centers are at half integers
+            let (x, y) = (x - 0.5, y - 0.5);
+            if !(x.is_finite() && y.is_finite()) {
+                continue;
+            }
+            let (x0, y0) = (x.floor(), y.floor());
+            let (fx, fy) = (x - x0, y - y0);
+            let corners = [
+                (0, 0, (1. - fx) * (1. - fy)),
+                (1, 0, fx * (1. - fy)),
+                (0, 1, (1. - fx) * fy),
+                (1, 1, fx * fy),
+            ];
+            for (dx, dy, weight) in corners {
+                let (px, py) = (x0 as i64 + dx, y0 as i64 + dy);
+                if px < 0 || py < 0 || px >= width as i64 || py >= height as i64 {
+                    continue;
+                }
+                let idx = (py as usize * width + px as usize) * 4;
+                for channel in &mut data[idx..idx + 3] {
+                    *channel += weight * brightness;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! Optional WASI support, for plugins compiled for `wasm32-wasi` or which print to stdout.
+//!
+//! WASI functions find memory through the module's `memory` export, so plugins which import
+//! their memory must also export it, e.g. with `rustflags = ["-C", "link-args=--export-memory"]`.
+use anyhow::{Context, Result};
+use std::any::Any;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use wasi_common::dir::{OpenResult, ReaddirCursor, ReaddirEntity, WasiDir};
+use wasi_common::file::{FdFlags, Filestat, OFlags};
+use wasi_common::pipe::WritePipe;
+use wasi_common::{Error, ErrorExt, SystemTimeSpec};
+use wasm_bridge::Linker;
+use wasmtime_wasi::sync::{ambient_authority, dir, Dir, WasiCtxBuilder};
+use wasmtime_wasi::WasiCtx;
+
+use crate::host::HostState;
+
+/// Path at which the plugin sees the project directory
+pub const PROJECT_GUEST_DIR: &str = "/project";
+
+/// What a plugin may access through WASI
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WasiConfig {
+    /// Directory the plugin may read, but not modify, as `PROJECT_GUEST_DIR`
+    pub project_dir: Option<PathBuf>,
+}
+
+/// Longest line kept whole. Longer lines, including output which never ends a line, are split
+/// into lines of this many bytes.
+const MAX_LINE_LEN: usize = 4096;
+
+/// Output written to stdout or stderr, until it is taken line by line
+#[derive(Clone, Default)]
+pub struct ConsoleOutput(Arc<Mutex<Console>>);
+
+/// Contents of a `ConsoleOutput`
+#[derive(Default)]
+struct Console {
+    /// Lines finished since they were last taken
+    lines: Vec<String>,
+    /// The line being written
+    pending: Vec<u8>,
+}
+
+impl WasiConfig {
+    /// A context with no arguments, environment or inherited stdio, whose stdout and stderr go
+    /// to `output`
+    pub fn build(&self, output: &ConsoleOutput) -> Result<WasiCtx> {
+        let mut builder = WasiCtxBuilder::new();
+        builder
+            .stdout(Box::new(WritePipe::new(output.clone())))
+            .stderr(Box::new(WritePipe::new(output.clone())));
+
+        let ctx = builder.build();
+        if let Some(project_dir) = &self.project_dir {
+            let dir = Dir::open_ambient_dir(project_dir, ambient_authority())
+                .with_context(|| format!("Opening {} for the plugin", project_dir.display()))?;
+            let dir = ReadOnlyDir(Box::new(dir::Dir::from_cap_std(dir)));
+            ctx.push_preopened_dir(Box::new(dir), PROJECT_GUEST_DIR)?;
+        }
+
+        Ok(ctx)
+    }
+}
+
+/// A directory whose files and subdirectories may be opened for reading only. Everything which
+/// would create, modify or remove an entry fails with `EPERM`.
+struct ReadOnlyDir(Box<dyn WasiDir>);
+
+#[wiggle::async_trait]
+impl WasiDir for ReadOnlyDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn open_file(
+        &self,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Result<OpenResult, Error> {
+        if write || oflags.intersects(OFlags::CREATE | OFlags::TRUNCATE) {
+            return Err(Error::perm());
+        }
+
+        match self
+            .0
+            .open_file(symlink_follow, path, oflags, read, false, fdflags)
+            .await?
+        {
+            OpenResult::Dir(dir) => Ok(OpenResult::Dir(Box::new(ReadOnlyDir(dir)))),
+            file => Ok(file),
+        }
+    }
+
+    async fn create_dir(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+
+    async fn readdir(
+        &self,
+        cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>, Error> {
+        self.0.readdir(cursor).await
+    }
+
+    async fn symlink(&self, _old_path: &str, _new_path: &str) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+
+    async fn remove_dir(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+
+    async fn unlink_file(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+
+    async fn read_link(&self, path: &str) -> Result<PathBuf, Error> {
+        self.0.read_link(path).await
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        self.0.get_filestat().await
+    }
+
+    async fn get_path_filestat(
+        &self,
+        path: &str,
+        follow_symlinks: bool,
+    ) -> Result<Filestat, Error> {
+        self.0.get_path_filestat(path, follow_symlinks).await
+    }
+
+    async fn rename(
+        &self,
+        _path: &str,
+        _dest_dir: &dyn WasiDir,
+        _dest_path: &str,
+    ) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+
+    async fn hard_link(
+        &self,
+        _path: &str,
+        _target_dir: &dyn WasiDir,
+        _target_path: &str,
+    ) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+
+    async fn set_times(
+        &self,
+        _path: &str,
+        _atime: Option<SystemTimeSpec>,
+        _mtime: Option<SystemTimeSpec>,
+        _follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+}
+
+/// Define the `wasi_snapshot_preview1` functions in `linker`. They use the context in
+/// `HostState::wasi`, which must be set before they are called.
+pub fn add_to_linker(linker: &mut Linker<HostState>) -> Result<()> {
+    wasmtime_wasi::add_to_linker(linker, |state: &mut HostState| {
+        state
+            .wasi
+            .as_mut()
+            .expect("WASI functions linked without a context")
+    })
+}
+
+impl ConsoleOutput {
+    /// Complete lines written since the last call. A partial line is kept until it is finished.
+    pub fn take_lines(&self) -> Vec<String> {
+        std::mem::take(&mut self.0.lock().unwrap().lines)
+    }
+}
+
+impl Console {
+    fn end_line(&mut self) {
+        let mut line = std::mem::take(&mut self.pending);
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        self.lines.push(String::from_utf8_lossy(&line).into_owned());
+    }
+}
+
+impl Write for ConsoleOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut console = self.0.lock().unwrap();
+        for &byte in buf {
+            if byte == b'\n' {
+                console.end_line();
+            } else {
+                console.pending.push(byte);
+                if console.pending.len() == MAX_LINE_LEN {
+                    console.end_line();
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
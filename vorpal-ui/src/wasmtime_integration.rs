@@ -1,11 +1,21 @@
-use anyhow::{Context, Result};
-use std::{collections::HashMap, path::PathBuf, rc::Rc};
-use vorpal_core::*;
+use anyhow::{ensure, format_err, Context, Result};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use std::{collections::HashMap, path::Path, path::PathBuf};
+use vorpal_core::{graph::Graph, *};
+use vorpal_module_cache::{self as module_cache, ModuleCache, ModuleKey};
 use vorpal_wasm::CodeAnalysis;
 use wasm_bridge::*;
 
+use crate::audio::{self, AudioSamples, AudioSettings};
+#[cfg(not(feature = "wasm-builtins"))]
+use crate::builtins;
 use crate::file_watcher::FileWatcher;
+use crate::host::{self, HostState};
+use crate::manifest::{HostInput, OutputFormat, PluginManifest};
+use crate::wasi::{self, WasiConfig};
 
+#[cfg(feature = "wasm-builtins")]
 const BUILTINS_WASM: &[u8] =
     include_bytes!("../../target/wasm32-unknown-unknown/release/vorpal_wasm_builtins.wasm");
 
@@ -24,31 +34,145 @@ pub fn evaluate_node(node: &Node, ctx: &ExternContext) -> Result<Value> {
 pub struct VorpalWasmtime {
     wasm_engine: wasm_bridge::Engine,
     pub cache: Option<CachedCompilation>,
-    watcher: FileWatcher,
+    /// Watches the image module, if there is one
+    watcher: Option<FileWatcher>,
     cached_image_wasm: Vec<u8>,
+    /// Manifest of the image module in `cached_image_wasm`
+    manifest: PluginManifest,
+    /// Whether the manifest changed since `take_manifest_change` was last called
+    manifest_changed: bool,
+    modules: ModuleCache,
+    /// How long a single call into the image module may run for
+    time_limit: Duration,
+    /// Messages the plugin logged since they were last taken
+    log: Vec<String>,
+    /// Resolution the plugin last asked for, until it is taken
+    requested_resolution: Option<(u32, u32)>,
+    /// Time of the previous frame, for `delta_time`
+    last_time: Option<f64>,
+    /// Link the plugin with WASI, if set
+    wasi: Option<WasiConfig>,
+}
+
+/// Number of samples `eval_audio` renders per call into wasm
+const AUDIO_BLOCK_LEN: u32 = 4096;
+
+/// Interval between increments of the engine's epoch, which is the granularity of time limits.
+/// Coarser than the sleep granularity of any platform, so that ticks are rarely late.
+const EPOCH_PERIOD: Duration = Duration::from_millis(10);
+
+/// The image module ran for longer than the time limit, and was interrupted
+#[derive(Debug)]
+pub struct TimeLimitExceeded {
+    pub limit: Duration,
 }
 
 pub struct CachedCompilation {
     pub nodes: NodeGraphs,
+    pub precision: Precision,
     pub instance: Instance,
-    pub store: Store<()>,
+    pub store: Store<HostState>,
     pub mem: Memory,
     pub analyses: Vec<CodeAnalysis>,
+    /// Defines the memory and builtins, for instantiating new versions of the functions
+    linker: Linker<HostState>,
+    /// Table through which the image module calls each function
+    slots: Vec<Table>,
+    /// Hash of the image module, which snapshots must match
+    image_hash: ModuleKey,
+}
+
+/// State of the image module at some point in time, which can be restored into the same module
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    /// Hash of the image module the snapshot was taken from
+    pub image_hash: ModuleKey,
+    pub memory: Vec<u8>,
+    /// Values of the image module's exported mutable globals
+    pub globals: Vec<(String, GlobalValue)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GlobalValue {
+    I32(i32),
+    I64(i64),
+    /// Bits of an `f32`
+    F32(u32),
+    /// Bits of an `f64`
+    F64(u64),
 }
 
 pub type FuncName = String;
-pub type NodeGraphs = Vec<(FuncName, Rc<Node>, ParameterList)>;
+pub type NodeGraphs = Vec<(FuncName, Graph, ParameterList)>;
+
+/// A function's node graph failed to compile, or trapped while running
+#[derive(Debug)]
+pub struct FunctionError {
+    /// Index of the function within the `NodeGraphs`
+    pub func_idx: usize,
+    pub func_name: FuncName,
+    pub error: anyhow::Error,
+}
 
 impl VorpalWasmtime {
     pub fn new(wasm_path: PathBuf) -> Result<Self> {
+        Self::with_watcher(Some(FileWatcher::new(wasm_path)?))
+    }
+
+    /// An engine with no image module, which can only evaluate functions on their own, e.g. with
+    /// `eval_audio`
+    pub fn without_plugin() -> Result<Self> {
+        Self::with_watcher(None)
+    }
+
+    fn with_watcher(watcher: Option<FileWatcher>) -> Result<Self> {
+        let wasm_engine = shared_engine()?;
+
         Ok(Self {
-            wasm_engine: wasm_bridge::Engine::new(&Default::default())?,
-            watcher: FileWatcher::new(wasm_path)?,
+            time_limit: Duration::from_secs(1),
+            wasm_engine,
+            watcher,
             cache: None,
             cached_image_wasm: vec![],
+            manifest: PluginManifest::legacy(),
+            manifest_changed: false,
+            log: vec![],
+            requested_resolution: None,
+            last_time: None,
+            wasi: None,
+            modules: ModuleCache::new(ModuleCache::default_dir()),
         })
     }
 
+    /// The manifest of the image module, if it was (re)loaded since the last call
+    pub fn take_manifest_change(&mut self) -> Option<PluginManifest> {
+        std::mem::take(&mut self.manifest_changed).then(|| self.manifest.clone())
+    }
+
+    /// Messages the plugin logged since the last call
+    pub fn take_log(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.log)
+    }
+
+    /// Resolution the plugin asked for since the last call, if any
+    pub fn take_requested_resolution(&mut self) -> Option<(u32, u32)> {
+        self.requested_resolution.take()
+    }
+
+    /// Interrupt calls into the image module which take longer than `limit`
+    pub fn set_time_limit(&mut self, limit: Duration) {
+        self.time_limit = limit;
+    }
+
+    /// Give the plugin WASI functions with the given access, or none. The plugin is reloaded
+    /// when this changes.
+    pub fn set_wasi(&mut self, config: Option<WasiConfig>) {
+        if self.wasi != config {
+            self.wasi = config;
+            self.cache = None;
+        }
+    }
+
     /*
     pub fn eval(&mut self, node: &Node, ctx: &ExternContext) -> Result<Value> {
         // Generate input list in random order
@@ -64,7 +188,16 @@ impl VorpalWasmtime {
     }
     */
 
-    pub fn eval_image(&mut self, nodes: &NodeGraphs, ctx: &ExternParameters) -> Result<Vec<f32>> {
+    /// Compile the functions in the given precision, and evaluate the image. The time is passed
+    /// separately from the other inputs so that it keeps its precision in double precision mode;
+    /// the image module must have been built with the matching ABI.
+    pub fn eval_image(
+        &mut self,
+        nodes: &NodeGraphs,
+        ctx: &ExternParameters,
+        time: f64,
+        precision: Precision,
+    ) -> Result<Vec<f32>> {
         let res_key = &ExternInputId::new(crate::RESOLUTION_KEY.into());
         let time_key = &ExternInputId::new(crate::TIME_KEY.into());
         let pos_key = &ExternInputId::new(crate::POS_KEY.into());
@@ -81,9 +214,6 @@ impl VorpalWasmtime {
         let Value::Vec2([width, height]) = ctx.inputs()[&res_key] else {
             panic!("Wrong vector type")
         };
-        let Value::Scalar(time) = ctx.inputs()[&time_key] else {
-            panic!("Wrong vector type")
-        };
         let Value::Vec2([cursor_x, cursor_y]) = ctx.inputs()[&cursor_key] else {
             panic!("Wrong vector type")
         };
@@ -91,125 +221,528 @@ impl VorpalWasmtime {
         let width = width as u32;
         let height = height as u32;
 
-        let mut old_cache = None;
+        let mut compile_data = match self.cache.take() {
+            // The image module's memory is kept as long as it and its imports stay the same
+            Some(mut cache) if !self.plugin_changed() && cache.precision == precision => {
+                match self.relink(&mut cache, nodes) {
+                    Ok(true) => cache,
+                    Ok(false) => self.link(nodes, precision)?,
+                    Err(e) => {
+                        // Keep running the previous version of the function
+                        self.cache = Some(cache);
+                        return Err(e);
+                    }
+                }
+            }
+            _ => self.link(nodes, precision)?,
+        };
+
+        // Arguments of the entry function, as the manifest declares them
+        let float = |x: f64| match precision {
+            Precision::Single => Val::F32((x as f32).to_bits()),
+            Precision::Double => Val::F64(x.to_bits()),
+        };
+        let args: Vec<Val> = self
+            .manifest
+            .inputs
+            .iter()
+            .map(|input| match input {
+                HostInput::Width => Val::I32(width as i32),
+                HostInput::Height => Val::I32(height as i32),
+                HostInput::Time => float(time),
+                HostInput::CursorX => float(cursor_x.into()),
+                HostInput::CursorY => float(cursor_y.into()),
+            })
+            .collect();
+
+        let entry = compile_data
+            .instance
+            .get_func(&mut compile_data.store, &self.manifest.entry)
+            .ok_or_else(|| format_err!("Plugin does not export {}()", self.manifest.entry))?;
+
+        let state = compile_data.store.data_mut();
+        state.params = host::param_lanes(ctx);
+        state.delta_time = self.last_time.map_or(0., |last| (time - last) as f32);
+        self.last_time = Some(time);
+
+        compile_data.store.set_epoch_deadline(self.deadline_ticks());
+        let mut results = [Val::I32(0)];
+        let result = entry.call(&mut compile_data.store, &args, &mut results);
+
+        // Kept even if the call failed, since the log may say why
+        let state = compile_data.store.data_mut();
+        state.frame_index += 1;
+        self.log.append(&mut state.log);
+        self.log.extend(state.console.take_lines());
+        if let Some(resolution) = state.requested_resolution.take() {
+            self.requested_resolution = Some(resolution);
+        }
+
+        if let Err(e) = result {
+            // An interrupted module may have stopped halfway through updating its state, so it
+            // starts over. After any other trap its memory is as the plugin left it, and kept.
+            if !matches!(e.downcast_ref::<Trap>(), Some(Trap::Interrupt)) {
+                self.cache = Some(compile_data);
+            }
+            return Err(self.call_error(e, nodes));
+        }
 
-        let nodes_changed = self
-            .cache
-            .as_ref()
-            .map(|cache_nodes| &cache_nodes.nodes != nodes)
-            .unwrap_or(false);
-        if !self.watcher.changed() && nodes_changed {
-            old_cache = self.cache.take();
+        let n_floats = match self.manifest.output {
+            OutputFormat::RgbaF32 => width * height * 4,
+        };
+        let mut out_image = vec![0_f32; n_floats as usize];
+        let read = results[0]
+            .i32()
+            .context("Plugin entry must return a pointer")
+            .and_then(|ptr| {
+                compile_data.mem.read(
+                    &mut compile_data.store,
+                    ptr as u32 as usize,
+                    bytemuck::cast_slice_mut(&mut out_image),
+                )?;
+                Ok(())
+            });
+
+        self.cache = Some(compile_data);
+        read?;
+
+        Ok(out_image)
+    }
+
+    /// Render the function at `func_idx` as audio, calling it once per sample. Its parameters
+    /// must be among those of `audio::audio_fn_inputs`.
+    pub fn eval_audio(
+        &mut self,
+        nodes: &NodeGraphs,
+        func_idx: usize,
+        settings: &AudioSettings,
+        precision: Precision,
+    ) -> Result<AudioSamples> {
+        let (func_name, graph, params) = &nodes[func_idx];
+        let function_error = |error| FunctionError {
+            func_idx,
+            func_name: func_name.clone(),
+            error,
+        };
+
+        let (kernel_module, anal) = self
+            .compile(graph, params, func_name, precision)
+            .map_err(function_error)?;
+        let channels = audio::output_channels(anal.final_output_dtype()).map_err(function_error)?;
+        let (inputs, constants) = settings.block_inputs(params).map_err(function_error)?;
+        let block_module = self.wat_module(&anal.compile_block_to_wat(func_name, &inputs)?)?;
+
+        let (mut store, mut linker, mem) = self.new_store()?;
+        let kernel = linker.instantiate(&mut store, &kernel_module)?;
+        linker.instance(&mut store, func_name, kernel)?;
+        let block = linker.instantiate(&mut store, &block_module)?;
+        let render_block = block
+            .get_func(&mut store, "render_block")
+            .ok_or_else(|| format_err!("Block module does not export render_block()"))?;
+        self.modules.forget_unused();
+
+        let float = |x: f64| match precision {
+            Precision::Single => Val::F32((x as f32).to_bits()),
+            Precision::Double => Val::F64(x.to_bits()),
+        };
+        let n_samples = settings.n_samples();
+        let mut data = Vec::with_capacity(n_samples as usize * channels);
+        let mut bytes = vec![];
+        for first in (0..n_samples).step_by(AUDIO_BLOCK_LEN as usize) {
+            let count = AUDIO_BLOCK_LEN.min(n_samples - first);
+
+            // Outputs are written from the start of memory
+            let mut args = vec![
+                Val::I32(first as i32),
+                Val::I32(count as i32),
+                float(settings.sample_rate.into()),
+            ];
+            args.extend(constants.iter().map(|&x| float(x)));
+            args.push(Val::I32(0));
+
+            store.set_epoch_deadline(self.deadline_ticks());
+            render_block
+                .call(&mut store, &args, &mut [])
+                .map_err(|e| self.call_error(e, nodes))?;
+
+            bytes.resize(count as usize * channels * precision.size_of(), 0);
+            mem.read(&mut store, 0, &mut bytes)?;
+            match precision {
+                Precision::Single => data.extend(
+                    bytes
+                        .chunks_exact(4)
+                        .map(|b| f32::from_le_bytes(b.try_into().unwrap())),
+                ),
+                Precision::Double => data.extend(
+                    bytes
+                        .chunks_exact(8)
+                        .map(|b| f64::from_le_bytes(b.try_into().unwrap()) as f32),
+                ),
+            }
+        }
+
+        let state = store.data_mut();
+        self.log.append(&mut state.log);
+        self.log.extend(state.console.take_lines());
+
+        Ok(AudioSamples {
+            sample_rate: settings.sample_rate,
+            channels,
+            data,
+        })
+    }
+
+    /// Compile the functions and instantiate the image module in a new store. Each function is
+    /// called through a dispatch module, so that it can later be replaced by `relink`.
+    fn link(&mut self, nodes: &NodeGraphs, precision: Precision) -> Result<CachedCompilation> {
+        // Loaded first, for its manifest
+        let image_module = self.image_module()?;
+        let (mut store, mut linker, mem) = self.new_store()?;
+
+        // Compile code
+        let mut analyses = vec![];
+        let mut slots = vec![];
+        for (func_idx, (func_name, node, params)) in nodes.iter().enumerate() {
+            let (kernel_module, anal) =
+                self.compile(node, params, func_name, precision)
+                    .map_err(|error| FunctionError {
+                        func_idx,
+                        func_name: func_name.clone(),
+                        error,
+                    })?;
+            let kernel = linker.instantiate(&mut store, &kernel_module)?;
+
+            let dispatch_module = self.wat_module(&anal.compile_dispatch_to_wat(func_name))?;
+            let dispatch = linker.instantiate(&mut store, &dispatch_module)?;
+            let slot = dispatch
+                .get_table(&mut store, "slot")
+                .ok_or_else(|| format_err!("Dispatch module has no slot"))?;
+            self.install(&mut store, &anal, func_name, slot, kernel)?;
+
+            // Callers import the function from the dispatch module, never the kernel itself
+            linker.instance(&mut store, func_name, dispatch)?;
+            analyses.push(anal);
+            slots.push(slot);
+        }
+
+        // Fail before instantiating, which would only report the first missing import
+        let found: Vec<_> = nodes
+            .iter()
+            .zip(&analyses)
+            .map(|((name, _, params), anal)| {
+                (name.as_str(), params, Some(anal.final_output_dtype()))
+            })
+            .collect();
+        if let Some(problem) = self.manifest.validate(&found).into_iter().next() {
+            let name = problem.expected.name.clone();
+            let error: anyhow::Error =
+                match nodes.iter().position(|(func_name, ..)| *func_name == name) {
+                    Some(func_idx) => FunctionError {
+                        func_idx,
+                        func_name: name,
+                        error: problem.into(),
+                    }
+                    .into(),
+                    None => problem.into(),
+                };
+            return Err(error);
+        }
+
+        let instance = linker.instantiate(&mut store, &image_module)?;
+        // WASI reactors set themselves up before any other call
+        if let Some(initialize) = instance.get_func(&mut store, "_initialize") {
+            initialize.call(&mut store, &[], &mut [])?;
         }
+        let image_hash = module_cache::bytes_key(&self.cached_image_wasm);
+
+        // Modules of edited or removed functions are no longer needed in memory
+        self.modules.forget_unused();
+
+        Ok(CachedCompilation {
+            nodes: nodes.clone(),
+            precision,
+            instance,
+            store,
+            mem,
+            analyses,
+            linker,
+            slots,
+            image_hash,
+        })
+    }
 
-        let mut compile_data: CachedCompilation = self
+    /// Capture the memory and mutable globals of the running image module
+    pub fn snapshot(&mut self) -> Result<Snapshot> {
+        let cache = self
             .cache
-            .take()
-            .filter(|cache| &cache.nodes == nodes)
-            .filter(|_| !self.watcher.changed())
-            .map(|cache| Ok(cache))
-            .unwrap_or_else(|| -> anyhow::Result<CachedCompilation> {
-                let mut store = Store::new(&self.wasm_engine, ());
-
-                // Start linking modules
-                let mut linker = Linker::new(&mut self.wasm_engine);
-
-                // Create a memory which all modules know to import
-                let memory_ty = MemoryType::new(100, None);
-                let mem = Memory::new(&mut store, memory_ty)?;
-                // Gleaned from compiling Rust to WAST and adding the
-                // `rustflags = ["-C", "link-args=--import-memory"]`
-                // to .cargo/config.toml
-                linker.define(&store, "env", "memory", mem)?;
-
-                // Add special modules
-                linker.module(&mut store, "builtins", &self.builtins_module()?)?;
-
-                // Compile code
-                let mut analyses = vec![];
-                for (func_name, node, params) in nodes {
-                    let (kernel_module, anal) = self
-                        .compile(&node, &params, &func_name)
-                        .with_context(|| format!("Compiling {func_name}()"))?;
-                    linker.module(&mut store, &func_name, &kernel_module)?;
-                    analyses.push(anal);
-                }
+            .as_mut()
+            .ok_or_else(|| format_err!("Nothing is running"))?;
+
+        let globals = exported_globals(cache)
+            .into_iter()
+            .map(|(name, global)| {
+                let value = match global.get(&mut cache.store) {
+                    Val::I32(v) => GlobalValue::I32(v),
+                    Val::I64(v) => GlobalValue::I64(v),
+                    Val::F32(v) => GlobalValue::F32(v),
+                    Val::F64(v) => GlobalValue::F64(v),
+                    other => return Err(format_err!("Cannot snapshot global {name}: {other:?}")),
+                };
+                Ok((name, value))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Snapshot {
+            image_hash: cache.image_hash,
+            memory: cache.mem.data(&cache.store).to_vec(),
+            globals,
+        })
+    }
+
+    /// Restore a snapshot taken from the same image module. The functions stay as they are.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<()> {
+        let cache = self
+            .cache
+            .as_mut()
+            .ok_or_else(|| format_err!("Nothing is running"))?;
+        ensure!(
+            snapshot.image_hash == cache.image_hash,
+            "Snapshot was taken from a different image module"
+        );
+
+        // Everything is checked before anything is written, so that a snapshot which does not
+        // fit leaves the module as it was
+        let mut globals = exported_globals(cache);
+        ensure!(
+            globals.len() == snapshot.globals.len(),
+            "Snapshot has {} globals, but the module has {}",
+            snapshot.globals.len(),
+            globals.len()
+        );
+        let mut writes = Vec::with_capacity(globals.len());
+        for (name, value) in &snapshot.globals {
+            let idx = globals
+                .iter()
+                .position(|(global_name, _)| global_name == name)
+                .ok_or_else(|| format_err!("Module has no global {name}"))?;
+            let (_, global) = globals.swap_remove(idx);
+            let value = match *value {
+                GlobalValue::I32(v) => Val::I32(v),
+                GlobalValue::I64(v) => Val::I64(v),
+                GlobalValue::F32(v) => Val::F32(v),
+                GlobalValue::F64(v) => Val::F64(v),
+            };
+            let ty = global.ty(&cache.store);
+            ensure!(
+                *ty.content() == value.ty(),
+                "Global {name} is {:?}, but the snapshot has {:?}",
+                ty.content(),
+                value.ty()
+            );
+            writes.push((global, value));
+        }
 
-                let instance = linker.instantiate(&mut store, &self.image_module()?)?;
+        // Memory can only grow; anything past the end of the snapshot is cleared
+        let page_size = 64 * 1024;
+        let current_len = cache.mem.data_size(&cache.store);
+        if snapshot.memory.len() > current_len {
+            let pages = (snapshot.memory.len() - current_len).div_ceil(page_size);
+            cache.mem.grow(&mut cache.store, pages as u64)?;
+        }
 
-                Ok(CachedCompilation {
-                    nodes: nodes.clone(),
-                    instance,
-                    store,
-                    mem,
-                    analyses,
-                })
-            })?;
-
-        // If we've had a node graph change but no wasm change, we can actually rely on the memoty
-        // layout being the same! So, we copy the entire memory out of the old instance
-        if let Some(old_cache) = old_cache {
-            eprintln!("Restoring old cached memory. This could be dangerous!");
-            // Allocate needed pages
-            let required_pages =
-                old_cache.mem.size(&old_cache.store) - compile_data.mem.size(&compile_data.store);
-            compile_data
-                .mem
-                .grow(&mut compile_data.store, required_pages)?;
-
-            // Copy all of the memory
-            compile_data
-                .mem
-                .data_mut(&mut compile_data.store)
-                .copy_from_slice(old_cache.mem.data(&old_cache.store));
+        for (global, value) in writes {
+            // Cannot fail, since the global is mutable and of the same type
+            global.set(&mut cache.store, value)?;
         }
 
-        let func = compile_data
-            .instance
-            .get_typed_func::<(u32, u32, f32, f32, f32), u32>(
-                &mut compile_data.store,
-                "make_image",
-            )?;
+        let data = cache.mem.data_mut(&mut cache.store);
+        let (restored, rest) = data.split_at_mut(snapshot.memory.len());
+        restored.copy_from_slice(&snapshot.memory);
+        rest.fill(0);
 
-        let ptr = func.call(
-            &mut compile_data.store,
-            (width, height, time, cursor_x, cursor_y),
-        )?;
+        Ok(())
+    }
 
-        let mut out_image = vec![0_f32; (width * height * 4) as usize];
-        compile_data.mem.read(
-            &mut compile_data.store,
-            ptr as usize,
-            bytemuck::cast_slice_mut(&mut out_image),
+    /// A store with a memory, and a linker defining it and everything else modules may import
+    fn new_store(&mut self) -> Result<(Store<HostState>, Linker<HostState>, Memory)> {
+        let mut store = Store::new(&self.wasm_engine, HostState::default());
+        store.set_epoch_deadline(self.deadline_ticks());
+
+        // Start linking modules
+        let mut linker = Linker::new(&mut self.wasm_engine);
+
+        // Create a memory which all modules know to import
+        let memory_ty = MemoryType::new(100, None);
+        let mem = Memory::new(&mut store, memory_ty)?;
+        // Gleaned from compiling Rust to WAST and adding the
+        // `rustflags = ["-C", "link-args=--import-memory"]`
+        // to .cargo/config.toml
+        linker.define(&store, "env", "memory", mem)?;
+        store.data_mut().memory = Some(mem);
+
+        // Add special modules
+        #[cfg(feature = "wasm-builtins")]
+        linker.module(
+            &mut store,
+            vorpal_wasm::BUILTINS_MODULE,
+            &self.builtins_module()?,
         )?;
+        #[cfg(not(feature = "wasm-builtins"))]
+        builtins::add_to_linker(&mut linker)?;
+        host::add_to_linker(&mut linker)?;
+        if let Some(config) = &self.wasi {
+            let ctx = config.build(&store.data().console)?;
+            store.data_mut().wasi = Some(ctx);
+            wasi::add_to_linker(&mut linker)?;
+        }
 
-        self.cache = Some(compile_data);
+        Ok((store, linker, mem))
+    }
 
-        Ok(out_image)
+    /// Swap in new versions of the functions which changed, keeping the image module and its
+    /// memory. Returns `false` if that is not possible because functions were added, removed,
+    /// renamed or given different parameters.
+    fn relink(&mut self, cache: &mut CachedCompilation, nodes: &NodeGraphs) -> Result<bool> {
+        let same_signatures = cache.nodes.len() == nodes.len()
+            && cache.nodes.iter().zip(nodes).all(
+                |((old_name, _, old_params), (new_name, _, new_params))| {
+                    old_name == new_name && old_params == new_params
+                },
+            );
+        if !same_signatures {
+            return Ok(false);
+        }
+
+        for (func_idx, (func_name, node, params)) in nodes.iter().enumerate() {
+            if cache.nodes[func_idx].1 == *node {
+                continue;
+            }
+
+            let (kernel_module, anal) = self
+                .compile(node, params, func_name, cache.precision)
+                .and_then(|(kernel_module, anal)| {
+                    // Only the output may have changed
+                    let found = [(func_name.as_str(), params, Some(anal.final_output_dtype()))];
+                    let problem = self
+                        .manifest
+                        .validate(&found)
+                        .into_iter()
+                        .find(|problem| problem.expected.name == *func_name);
+                    match problem {
+                        Some(problem) => Err(problem.into()),
+                        None => Ok((kernel_module, anal)),
+                    }
+                })
+                .map_err(|error| FunctionError {
+                    func_idx,
+                    func_name: func_name.clone(),
+                    error,
+                })?;
+            // The old instance stays alive until the store is dropped, which is only a few
+            // kilobytes per edit
+            let kernel = cache.linker.instantiate(&mut cache.store, &kernel_module)?;
+            self.install(
+                &mut cache.store,
+                &anal,
+                func_name,
+                cache.slots[func_idx],
+                kernel,
+            )?;
+
+            cache.nodes[func_idx] = nodes[func_idx].clone();
+            cache.analyses[func_idx] = anal;
+        }
+
+        Ok(true)
+    }
+
+    /// Store the function exported by `kernel` in its dispatch module's slot
+    fn install(
+        &mut self,
+        store: &mut Store<HostState>,
+        analysis: &CodeAnalysis,
+        func_name: &str,
+        slot: Table,
+        kernel: Instance,
+    ) -> Result<()> {
+        let func = kernel
+            .get_func(&mut *store, func_name)
+            .ok_or_else(|| format_err!("Function {} not exported", func_name))?;
+        let install_module = self.wat_module(&analysis.compile_install_to_wat())?;
+        Instance::new(&mut *store, &install_module, &[slot.into(), func.into()])?;
+        Ok(())
     }
 
-    fn builtins_module(&self) -> Result<Module> {
-        Ok(Module::new(&self.wasm_engine, BUILTINS_WASM)?)
+    /// Explain why a call into wasm failed. Traps raised while one of the `nodes` was running are
+    /// attributed to it.
+    fn call_error(&self, error: anyhow::Error, nodes: &NodeGraphs) -> anyhow::Error {
+        match error.downcast_ref::<Trap>() {
+            Some(Trap::Interrupt) => TimeLimitExceeded {
+                limit: self.time_limit,
+            }
+            .into(),
+            _ => match error.downcast_ref::<wasmtime_wasi::I32Exit>() {
+                Some(exit) => format_err!("Plugin exited with code {}", exit.0),
+                None => trapping_function(error, nodes),
+            },
+        }
+    }
+
+    /// Whether the image module changed since it was loaded
+    fn plugin_changed(&self) -> bool {
+        self.watcher.as_ref().is_some_and(FileWatcher::changed)
+    }
+
+    /// Number of epochs a call may run for. The epoch is shared, so the first tick may come at
+    /// any moment; one extra tick makes sure calls get at least the whole limit.
+    fn deadline_ticks(&self) -> u64 {
+        (self.time_limit.as_nanos().div_ceil(EPOCH_PERIOD.as_nanos()) + 1) as u64
+    }
+
+    /// Compile a small module generated by the backend
+    fn wat_module(&mut self, wat: &str) -> Result<Module> {
+        let key = module_cache::bytes_key(wat.as_bytes());
+        self.modules.get_or_compile(&self.wasm_engine, key, || {
+            Module::new(&self.wasm_engine, wat)
+        })
+    }
+
+    #[cfg(feature = "wasm-builtins")]
+    fn builtins_module(&mut self) -> Result<Module> {
+        let key = module_cache::bytes_key(BUILTINS_WASM);
+        self.modules.get_or_compile(&self.wasm_engine, key, || {
+            Module::new(&self.wasm_engine, BUILTINS_WASM)
+        })
     }
 
     fn image_module(&mut self) -> Result<Module> {
         //let wasm = std::fs::read(VORPAL_IMAGE_PATH)?;
-        if self.cached_image_wasm.is_empty() || self.watcher.changed() {
-            self.cached_image_wasm = std::fs::read(self.watcher.path())?;
-            self.watcher.reset();
+        let watcher = self.watcher.as_ref().context("No plugin is loaded")?;
+        if self.cached_image_wasm.is_empty() || watcher.changed() {
+            self.cached_image_wasm = std::fs::read(watcher.path())?;
+            self.manifest = PluginManifest::from_wasm(&self.cached_image_wasm)?;
+            self.manifest_changed = true;
+            watcher.reset();
         }
-        Module::new(&self.wasm_engine, &self.cached_image_wasm)
+        let key = module_cache::bytes_key(&self.cached_image_wasm);
+        self.modules.get_or_compile(&self.wasm_engine, key, || {
+            Module::new(&self.wasm_engine, &self.cached_image_wasm)
+        })
     }
 
+    /// Compile a function, or load it from the cache if it has been compiled before
     fn compile(
-        &self,
-        node: &Node,
+        &mut self,
+        graph: &Graph,
         input_list: &ParameterList,
         func_name: &str,
+        precision: Precision,
     ) -> Result<(Module, CodeAnalysis)> {
-        let analysis = CodeAnalysis::new(Rc::new(node.clone()), input_list);
-        let wat = analysis.compile_to_wat(func_name)?;
-        let kernel_module = Module::new(&self.wasm_engine, wat)?;
+        let analysis = CodeAnalysis::new(graph, input_list)?.with_precision(precision);
+        let key = module_cache::function_key(graph, input_list, func_name, precision);
+        let kernel_module = self.modules.get_or_compile(&self.wasm_engine, key, || {
+            Module::new(&self.wasm_engine, analysis.compile_to_wat(func_name)?)
+        })?;
         Ok((kernel_module, analysis))
     }
 
@@ -276,3 +809,179 @@ impl VorpalWasmtime {
     }
     */
 }
+
+/// Exported mutable globals of the image module, by name
+fn exported_globals(cache: &mut CachedCompilation) -> Vec<(String, Global)> {
+    let exports: Vec<(String, Global)> = cache
+        .instance
+        .exports(&mut cache.store)
+        .filter_map(|export| {
+            let name = export.name().to_string();
+            Some((name, export.into_global()?))
+        })
+        .collect();
+
+    exports
+        .into_iter()
+        .filter(|(_, global)| global.ty(&cache.store).mutability() == Mutability::Var)
+        .collect()
+}
+
+/// Identifies snapshot files
+const SNAPSHOT_MAGIC: &[u8; 8] = b"VORPSNP2";
+
+impl Snapshot {
+    /// Write the snapshot in a little-endian binary format
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut bytes = SNAPSHOT_MAGIC.to_vec();
+        bytes.extend(self.image_hash.0);
+
+        bytes.extend((self.globals.len() as u32).to_le_bytes());
+        for (name, value) in &self.globals {
+            bytes.extend((name.len() as u32).to_le_bytes());
+            bytes.extend(name.as_bytes());
+            let (tag, bits) = match *value {
+                GlobalValue::I32(v) => (0_u8, v as u32 as u64),
+                GlobalValue::I64(v) => (1, v as u64),
+                GlobalValue::F32(v) => (2, v.into()),
+                GlobalValue::F64(v) => (3, v),
+            };
+            bytes.push(tag);
+            bytes.extend(bits.to_le_bytes());
+        }
+
+        bytes.extend((self.memory.len() as u64).to_le_bytes());
+        bytes.extend(&self.memory);
+
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let bytes = std::fs::read(path)?;
+        let mut reader = SnapshotReader(&bytes);
+
+        ensure!(
+            reader.take(SNAPSHOT_MAGIC.len())? == SNAPSHOT_MAGIC,
+            "Not a snapshot file"
+        );
+        let image_hash = ModuleKey(reader.take(32)?.try_into()?);
+
+        let n_globals = reader.u32()?;
+        let mut globals = vec![];
+        for _ in 0..n_globals {
+            let name_len = reader.u32()? as usize;
+            let name = String::from_utf8(reader.take(name_len)?.to_vec())?;
+            let tag = reader.take(1)?[0];
+            let bits = reader.u64()?;
+            let value = match tag {
+                0 => GlobalValue::I32(bits as u32 as i32),
+                1 => GlobalValue::I64(bits as i64),
+                2 => GlobalValue::F32(bits as u32),
+                3 => GlobalValue::F64(bits),
+                _ => return Err(format_err!("Unknown global type {tag}")),
+            };
+            globals.push((name, value));
+        }
+
+        let memory_len = reader.u64()? as usize;
+        let memory = reader.take(memory_len)?.to_vec();
+        ensure!(reader.0.is_empty(), "Trailing data in snapshot");
+
+        Ok(Self {
+            image_hash,
+            memory,
+            globals,
+        })
+    }
+}
+
+/// Remaining bytes of a snapshot file
+struct SnapshotReader<'a>(&'a [u8]);
+
+impl<'a> SnapshotReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        ensure!(self.0.len() >= n, "Snapshot is truncated");
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+/// The engine shared by every `VorpalWasmtime`, whose epoch is incremented every `EPOCH_PERIOD`
+/// by a single background thread
+fn shared_engine() -> Result<Engine> {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    if let Some(engine) = ENGINE.get() {
+        return Ok(engine.clone());
+    }
+
+    let mut config = Config::new();
+    config.epoch_interruption(true);
+    let engine = Engine::new(&config)?;
+    let engine = ENGINE.get_or_init(|| {
+        let ticked = engine.clone();
+        std::thread::spawn(move || {
+            // Ticks are counted from the start, so that sleeping for too long is caught up on
+            let start = Instant::now();
+            let mut ticks = 0;
+            loop {
+                std::thread::sleep(EPOCH_PERIOD);
+                let elapsed = start.elapsed().as_nanos() / EPOCH_PERIOD.as_nanos();
+                while ticks < elapsed {
+                    ticked.increment_epoch();
+                    ticks += 1;
+                }
+            }
+        });
+        engine
+    });
+    Ok(engine.clone())
+}
+
+/// Wrap the error in a `FunctionError` if the innermost function of its backtrace is one of the
+/// `nodes`, whose kernel modules are named after them
+fn trapping_function(error: anyhow::Error, nodes: &NodeGraphs) -> anyhow::Error {
+    let func_idx = error.downcast_ref::<WasmBacktrace>().and_then(|backtrace| {
+        backtrace.frames().iter().find_map(|frame| {
+            let module_name = frame.module().name()?;
+            nodes
+                .iter()
+                .position(|(func_name, _, _)| func_name == module_name)
+        })
+    });
+
+    match func_idx {
+        Some(func_idx) => FunctionError {
+            func_idx,
+            func_name: nodes[func_idx].0.clone(),
+            error,
+        }
+        .into(),
+        None => error,
+    }
+}
+
+impl std::fmt::Display for TimeLimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Plugin exceeded {} ms", self.limit.as_millis())
+    }
+}
+
+impl std::error::Error for TimeLimitExceeded {}
+
+impl std::fmt::Display for FunctionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "In {}(): {:#}", self.func_name, self.error)
+    }
+}
+
+impl std::error::Error for FunctionError {}
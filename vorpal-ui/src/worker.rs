@@ -0,0 +1,281 @@
+//! Evaluation of images and audio on a background thread, so that the editor stays responsive
+//! while they render
+use anyhow::{format_err, Result};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::time::{Duration, Instant};
+use vorpal_core::{ExternParameters, Precision};
+
+use crate::audio::{AudioSamples, AudioSettings};
+use crate::manifest::PluginManifest;
+use crate::wasi::WasiConfig;
+use crate::wasmtime_integration::{NodeGraphs, Snapshot, VorpalWasmtime};
+
+/// Everything needed to render one frame
+pub struct FrameRequest {
+    pub nodes: NodeGraphs,
+    pub params: ExternParameters,
+    pub time: f64,
+    pub precision: Precision,
+    pub time_limit: Duration,
+    /// WASI access to give the plugin, if any
+    pub wasi: Option<WasiConfig>,
+}
+
+/// Everything needed to render a function as audio
+pub struct AudioRequest {
+    /// The first function is rendered
+    pub nodes: NodeGraphs,
+    pub settings: AudioSettings,
+    pub precision: Precision,
+    pub time_limit: Duration,
+    /// Number of frequencies in the spectrum
+    pub spectrum_bins: usize,
+}
+
+/// Rendered audio, and its spectrum in decibels from 0 Hz to half the sample rate
+pub struct RenderedAudio {
+    pub samples: AudioSamples,
+    pub spectrum: Vec<f32>,
+}
+
+/// Owns a `VorpalWasmtime` on a background thread, which renders one frame at a time
+pub struct EvalWorker {
+    commands: Sender<Command>,
+    replies: Receiver<Reply>,
+    /// Whether the thread stopping was already reported by `poll`
+    stopped: bool,
+    /// Whether a frame is being rendered
+    busy: bool,
+    /// Sent once the frame being rendered is done. Replaced by newer requests, so that frames are
+    /// dropped when they are requested faster than they render.
+    next: Option<FrameRequest>,
+    stats: WorkerStats,
+    last_frame: Option<Instant>,
+    /// Manifest of the plugin, once it has been loaded
+    manifest: Option<PluginManifest>,
+    /// Messages the plugin logged since they were last taken
+    log: Vec<String>,
+    requested_resolution: Option<(u32, u32)>,
+}
+
+/// Frame rate and latency, smoothed over recent frames
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WorkerStats {
+    pub fps: f32,
+    /// Time from requesting a frame to receiving it
+    pub latency: Duration,
+}
+
+/// What a snapshot is taken for, which is handed back along with it
+#[derive(Clone, Debug)]
+pub enum SnapshotPurpose {
+    /// Keep it to go back to later
+    Checkpoint,
+    /// Save it to a file
+    Save(PathBuf),
+}
+
+/// Something the worker finished, returned by `EvalWorker::poll`
+pub enum WorkerEvent {
+    /// A rendered frame. The thread stopping is reported once as an error here.
+    Frame(Result<Vec<f32>>),
+    Snapshot(Result<Snapshot>, SnapshotPurpose),
+    Restored(Result<()>),
+    Audio(Result<RenderedAudio>),
+}
+
+enum Command {
+    Render(FrameRequest, Instant),
+    RenderAudio(AudioRequest),
+    Snapshot(SnapshotPurpose),
+    Restore(Snapshot),
+}
+
+enum Reply {
+    Frame(Frame),
+    /// The audio, and messages logged while rendering it
+    Audio(Result<RenderedAudio>, Vec<String>),
+    Snapshot(Result<Snapshot>, SnapshotPurpose),
+    Restored(Result<()>),
+}
+
+struct Frame {
+    result: Result<Vec<f32>>,
+    requested: Instant,
+    /// Set when the plugin was (re)loaded for this frame
+    manifest: Option<PluginManifest>,
+    log: Vec<String>,
+    requested_resolution: Option<(u32, u32)>,
+}
+
+/// Weight of the newest frame in `WorkerStats`
+const SMOOTHING: f32 = 0.1;
+
+impl EvalWorker {
+    /// Move the engine to a new thread. `on_frame` is called on that thread whenever anything is
+    /// ready to be polled, e.g. to request a repaint.
+    pub fn new(mut engine: VorpalWasmtime, on_frame: impl Fn() + Send + 'static) -> Self {
+        let (commands, command_rx) = mpsc::channel();
+        let (reply_tx, replies) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            // Stops once the worker is dropped
+            while let Ok(command) = command_rx.recv() {
+                let reply = match command {
+                    Command::Render(request, requested) => {
+                        engine.set_time_limit(request.time_limit);
+                        engine.set_wasi(request.wasi);
+                        let result = engine.eval_image(
+                            &request.nodes,
+                            &request.params,
+                            request.time,
+                            request.precision,
+                        );
+                        Reply::Frame(Frame {
+                            result,
+                            requested,
+                            manifest: engine.take_manifest_change(),
+                            log: engine.take_log(),
+                            requested_resolution: engine.take_requested_resolution(),
+                        })
+                    }
+                    Command::RenderAudio(request) => {
+                        engine.set_time_limit(request.time_limit);
+                        let result = engine
+                            .eval_audio(&request.nodes, 0, &request.settings, request.precision)
+                            .map(|samples| RenderedAudio {
+                                spectrum: samples.spectrum(request.spectrum_bins),
+                                samples,
+                            });
+                        Reply::Audio(result, engine.take_log())
+                    }
+                    Command::Snapshot(purpose) => Reply::Snapshot(engine.snapshot(), purpose),
+                    Command::Restore(snapshot) => Reply::Restored(engine.restore(&snapshot)),
+                };
+                if reply_tx.send(reply).is_err() {
+                    break;
+                }
+                on_frame();
+            }
+        });
+
+        Self {
+            commands,
+            replies,
+            stopped: false,
+            busy: false,
+            next: None,
+            stats: WorkerStats::default(),
+            last_frame: None,
+            manifest: None,
+            log: vec![],
+            requested_resolution: None,
+        }
+    }
+
+    /// Render a frame, once the one being rendered is done
+    pub fn request(&mut self, request: FrameRequest) {
+        if self.busy {
+            self.next = Some(request);
+        } else {
+            self.send(request);
+        }
+    }
+
+    /// Take the next thing which finished, if any. Call until it returns `None`.
+    pub fn poll(&mut self) -> Option<WorkerEvent> {
+        let frame = match self.replies.try_recv() {
+            Ok(Reply::Frame(frame)) => frame,
+            Ok(Reply::Snapshot(result, purpose)) => {
+                return Some(WorkerEvent::Snapshot(result, purpose))
+            }
+            Ok(Reply::Restored(result)) => return Some(WorkerEvent::Restored(result)),
+            Ok(Reply::Audio(result, log)) => {
+                self.log.extend(log);
+                return Some(WorkerEvent::Audio(result));
+            }
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => {
+                self.busy = false;
+                if std::mem::replace(&mut self.stopped, true) {
+                    return None;
+                }
+                let error = format_err!("Worker thread stopped");
+                return Some(WorkerEvent::Frame(Err(error)));
+            }
+        };
+
+        let now = Instant::now();
+        let latency = now - frame.requested;
+        if let Some(last_frame) = self.last_frame {
+            let fps = 1. / (now - last_frame).as_secs_f32();
+            self.stats.fps = self.stats.fps * (1. - SMOOTHING) + fps * SMOOTHING;
+            self.stats.latency =
+                self.stats.latency.mul_f32(1. - SMOOTHING) + latency.mul_f32(SMOOTHING);
+        } else {
+            self.stats.latency = latency;
+        }
+        self.last_frame = Some(now);
+
+        if frame.manifest.is_some() {
+            self.manifest = frame.manifest;
+        }
+        self.log.extend(frame.log);
+        if frame.requested_resolution.is_some() {
+            self.requested_resolution = frame.requested_resolution;
+        }
+
+        self.busy = false;
+        if let Some(request) = self.next.take() {
+            self.send(request);
+        }
+
+        Some(WorkerEvent::Frame(frame.result))
+    }
+
+    pub fn manifest(&self) -> Option<&PluginManifest> {
+        self.manifest.as_ref()
+    }
+
+    /// Messages the plugin logged since the last call
+    pub fn take_log(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.log)
+    }
+
+    /// Resolution the plugin asked for since the last call, if any
+    pub fn take_requested_resolution(&mut self) -> Option<(u32, u32)> {
+        self.requested_resolution.take()
+    }
+
+    pub fn stats(&self) -> WorkerStats {
+        self.stats
+    }
+
+    /// Render audio, after the frame being rendered is done. It is returned by `poll`.
+    pub fn render_audio(&mut self, request: AudioRequest) {
+        // A stopped worker is reported by `poll`
+        let _ = self.commands.send(Command::RenderAudio(request));
+    }
+
+    /// Capture the plugin's state, after the frame being rendered is done. The snapshot is
+    /// returned by `poll`, along with `purpose`.
+    pub fn snapshot(&mut self, purpose: SnapshotPurpose) {
+        // A stopped worker is reported by `poll`
+        let _ = self.commands.send(Command::Snapshot(purpose));
+    }
+
+    /// Restore the plugin's state, after the frame being rendered is done. Whether it worked is
+    /// returned by `poll`.
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        let _ = self.commands.send(Command::Restore(snapshot));
+    }
+
+    fn send(&mut self, request: FrameRequest) {
+        // A stopped worker is reported by `poll`
+        self.busy = self
+            .commands
+            .send(Command::Render(request, Instant::now()))
+            .is_ok();
+    }
+}
@@ -4,7 +4,7 @@ pub extern "C" fn power(base: f32, exponent: f32) -> f32 {
 }
 
 #[no_mangle]
-pub extern "C" fn logbase(base: f32, value: f32) -> f32 {
+pub extern "C" fn logbase(value: f32, base: f32) -> f32 {
     value.log(base)
 }
 
@@ -48,3 +48,54 @@ pub extern "C" fn equal_to(lhs: f32, rhs: f32) -> f32 {
     f32::from(lhs == rhs)
 }
 
+// Double precision variants, for graphs compiled with `Precision::Double`
+
+#[no_mangle]
+pub extern "C" fn power_f64(base: f64, exponent: f64) -> f64 {
+    base.powf(exponent)
+}
+
+#[no_mangle]
+pub extern "C" fn logbase_f64(value: f64, base: f64) -> f64 {
+    value.log(base)
+}
+
+#[no_mangle]
+pub extern "C" fn cosine_f64(value: f64) -> f64 {
+    value.cos()
+}
+
+#[no_mangle]
+pub extern "C" fn sine_f64(value: f64) -> f64 {
+    value.sin()
+}
+
+#[no_mangle]
+pub extern "C" fn tangent_f64(value: f64) -> f64 {
+    value.tan()
+}
+
+#[no_mangle]
+pub extern "C" fn natural_log_f64(value: f64) -> f64 {
+    value.ln()
+}
+
+#[no_mangle]
+pub extern "C" fn natural_exp_f64(value: f64) -> f64 {
+    value.exp()
+}
+
+#[no_mangle]
+pub extern "C" fn greater_than_f64(lhs: f64, rhs: f64) -> f64 {
+    f64::from(lhs > rhs)
+}
+
+#[no_mangle]
+pub extern "C" fn less_than_f64(lhs: f64, rhs: f64) -> f64 {
+    f64::from(lhs < rhs)
+}
+
+#[no_mangle]
+pub extern "C" fn equal_to_f64(lhs: f64, rhs: f64) -> f64 {
+    f64::from(lhs == rhs)
+}
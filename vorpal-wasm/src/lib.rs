@@ -1,12 +1,49 @@
 use anyhow::{ensure, Result};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Write;
-use std::rc::Rc;
+use vorpal_core::graph::{Graph, NodeId, NodeKind};
 use vorpal_core::*;
 
 /// Denotes the "name" of a local variable; e.g. local.get 9
 type LocalVarId = u32;
 
+/// Version of the code generator. Anything compiled from its output, such as cached modules,
+/// must be keyed by it.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Name of the module which the builtin functions are imported from
+pub const BUILTINS_MODULE: &str = "builtins";
+
+/// Component functions which are imported from `BUILTINS_MODULE`, by name. Double precision
+/// variants have an `_f64` suffix.
+pub const BUILTIN_FNS: [(&str, ComponentFn); 5] = [
+    ("sine", ComponentFn::Sine),
+    ("cosine", ComponentFn::Cosine),
+    ("tangent", ComponentFn::Tangent),
+    ("natural_log", ComponentFn::NaturalLog),
+    ("natural_exp", ComponentFn::NaturalExp),
+];
+
+/// Infix operators which are imported from `BUILTINS_MODULE`, by name
+pub const BUILTIN_OPS: [(&str, ComponentInfixOp); 5] = [
+    ("power", ComponentInfixOp::Power),
+    ("logbase", ComponentInfixOp::Logbase),
+    ("greater_than", ComponentInfixOp::GreaterThan),
+    ("less_than", ComponentInfixOp::LessThan),
+    ("equal_to", ComponentInfixOp::EqualTo),
+];
+
+/// How `CodeAnalysis::compile_block_to_wat` computes an input of the function for each sample
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockInput {
+    /// Seconds since the first sample of the first block, `index / rate`
+    Time,
+    /// Index of the sample, counting from the first sample of the first block
+    SampleIndex,
+    /// The same value for every sample, passed to `render_block` with one float per lane
+    Constant,
+}
+
 #[derive(Debug)]
 pub enum InputParameter {
     ExternalVariable(ExternInputId, DataType),
@@ -15,29 +52,34 @@ pub enum InputParameter {
 
 /// Metadata for a node graph
 pub struct CodeAnalysis {
-    /// Mapping of a node to its corresponding local variable id
-    locals: HashMap<HashRcByPtr<Node>, (LocalVarId, DataType)>,
+    /// Mapping of a node to its corresponding local variable id. Ordered, so that locals are
+    /// always declared in the same order.
+    locals: BTreeMap<NodeId, (LocalVarId, DataType)>,
     /// Mapping of an input name to its corresponding local variable id
     input_to_var: HashMap<ExternInputId, (LocalVarId, DataType)>,
     /// Next local variable ID to be produced
     next_var_id: LocalVarId,
-    /// Root node
-    root: HashRcByPtr<Node>,
+    /// The node graph being compiled
+    graph: Graph,
     /// Ordered inputs; the function's parameters will match this order!
     input_list: Vec<InputParameter>,
+    /// Type of every local, parameter and output lane
+    precision: Precision,
 }
 
 impl CodeAnalysis {
-    /// Inputs to the function will be arranged in the given order
-    pub fn new(node: Rc<Node>, extern_inputs: &ParameterList) -> Self {
-        let root = HashRcByPtr(node);
+    /// Inputs to the function will be arranged in the given order. Fails if the graph does not
+    /// typecheck against them.
+    pub fn new(graph: &Graph, extern_inputs: &ParameterList) -> Result<Self> {
+        let dtypes = typecheck::infer_dtypes(graph, extern_inputs).map_err(EvalError::TypeCheck)?;
 
         let mut instance = Self {
             next_var_id: 0,
             input_to_var: Default::default(),
             locals: Default::default(),
             input_list: Default::default(),
-            root,
+            graph: graph.clone(),
+            precision: Precision::Single,
         };
 
         // Add input pointer
@@ -54,14 +96,25 @@ impl CodeAnalysis {
 
         instance.input_list.extend(extern_vars);
 
-        instance.find_inputs_and_locals_recursive(instance.root.clone());
+        instance.find_inputs_and_locals(&dtypes);
 
-        instance
+        Ok(instance)
+    }
+
+    /// Compile to the given float type instead of `f32`. The builtins and the host must use the
+    /// same precision.
+    pub fn with_precision(mut self, precision: Precision) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    pub fn precision(&self) -> Precision {
+        self.precision
     }
 
     /// Output datatype of the root node
     pub fn final_output_dtype(&self) -> DataType {
-        let (_, final_output_dtype) = self.locals[&self.root];
+        let (_, final_output_dtype) = self.locals[&self.graph.root()];
         final_output_dtype
     }
 
@@ -70,44 +123,57 @@ impl CodeAnalysis {
         &self.input_list
     }
 
+    /// Rust declaration of the function as an import from a wasm module named `func_name`. The
+    /// function and its parameters are named with `sanitize_identifier` and
+    /// `ParameterList::identifiers`, so that any names produce valid Rust.
     pub fn func_name_rust(&self, func_name: &str) -> Result<String> {
+        let float = self.precision.type_name();
         let mut param_list_text = String::new();
 
         writeln!(
             &mut param_list_text,
-            r#"#[link(wasm_import_module = "{func_name}")]"#
+            r#"#[link(wasm_import_module = {func_name:?})]"#
+        )
+        .unwrap();
+        writeln!(&mut param_list_text, r#"extern "C" {{"#).unwrap();
+        writeln!(&mut param_list_text, "#[link_name = {func_name:?}]").unwrap();
+        writeln!(
+            &mut param_list_text,
+            "fn {}(",
+            sanitize_identifier(func_name)
         )
         .unwrap();
-        writeln!(&mut param_list_text, "{}", r#"extern "C" {"#).unwrap();
-        writeln!(&mut param_list_text, "fn {func_name}(").unwrap();
 
         let space = "    ";
 
+        let extern_inputs = ParameterList(
+            self.input_list
+                .iter()
+                .filter_map(|input_param| match input_param {
+                    InputParameter::ExternalVariable(id, dtype) => Some((id.clone(), *dtype)),
+                    InputParameter::OutputPointer(_) => None,
+                })
+                .collect(),
+        );
+        let mut identifiers = extern_inputs.identifiers().into_iter();
+
         for input_param in &self.input_list {
             match input_param {
                 InputParameter::OutputPointer(_) => {
-                    // Pointer for output float data (*mut f32)
-                    writeln!(&mut param_list_text, "{space}out_ptr: *mut f32, ").unwrap();
+                    // Pointer for output float data (*mut f32 or *mut f64)
+                    writeln!(&mut param_list_text, "{space}out_ptr: *mut {float}, ").unwrap();
                 }
-                InputParameter::ExternalVariable(input_name, input_dtype) => {
-                    let nicer_input_name: String = input_name
-                        .to_string()
-                        .to_lowercase()
-                        .chars()
-                        .filter_map(|c| match c {
-                            c if c.is_alphanumeric() => Some(c),
-                            c if c.is_whitespace() => Some('_'),
-                            _ => None,
-                        })
-                        .collect();
+                InputParameter::ExternalVariable(_, input_dtype) => {
+                    let nicer_input_name = identifiers.next().unwrap();
 
                     if input_dtype.n_lanes() == 1 {
-                        writeln!(&mut param_list_text, "{space}{nicer_input_name}: f32, ").unwrap();
+                        writeln!(&mut param_list_text, "{space}{nicer_input_name}: {float}, ")
+                            .unwrap();
                     } else {
                         for lane in "xyzw".chars().take(input_dtype.n_lanes()) {
                             writeln!(
                                 &mut param_list_text,
-                                "{space}{nicer_input_name}_{lane}: f32, "
+                                "{space}{nicer_input_name}_{lane}: {float}, "
                             )
                             .unwrap();
                         }
@@ -117,12 +183,13 @@ impl CodeAnalysis {
         }
 
         writeln!(&mut param_list_text, ");").unwrap();
-        writeln!(&mut param_list_text, "{}", "}").unwrap();
+        writeln!(&mut param_list_text, "}}").unwrap();
 
         Ok(param_list_text)
     }
 
     pub fn func_name_wat(&self, func_name: &str) -> Result<String> {
+        let float = self.precision.type_name();
         let mut param_list_text = String::new();
 
         write!(&mut param_list_text, "(func ${func_name} ").unwrap();
@@ -130,7 +197,7 @@ impl CodeAnalysis {
         for input_param in &self.input_list {
             match input_param {
                 InputParameter::OutputPointer(input_var_id) => {
-                    // Pointer for output float data (*mut f32)
+                    // Pointer for output float data (*mut f32 or *mut f64)
                     write!(&mut param_list_text, "(param ${input_var_id} i32) ").unwrap();
                 }
                 InputParameter::ExternalVariable(input_name, input_dtype) => {
@@ -145,11 +212,14 @@ impl CodeAnalysis {
                                 expected_dtype,
                                 input_dtype
                             );
-                            write!(&mut param_list_text, "(param ${input_var_id}_{lane} f32) ")
-                                .unwrap();
+                            write!(
+                                &mut param_list_text,
+                                "(param ${input_var_id}_{lane} {float}) "
+                            )
+                            .unwrap();
                         } else {
                             // Dummy parameter to keep the ordering of the inputs
-                            write!(&mut param_list_text, "(param f32) ").unwrap();
+                            write!(&mut param_list_text, "(param {float}) ").unwrap();
                         }
                     }
                 }
@@ -161,31 +231,40 @@ impl CodeAnalysis {
 
     /// Compile this analysis to webassembly
     pub fn compile_function_to_wat(&self, func_name: &str) -> Result<String> {
+        self.compile_function_to_wat_annotated(func_name, &[])
+    }
+
+    /// Compile this analysis to webassembly, preceding the instructions of each node with a
+    /// comment describing where it came from. `sources` is indexed by node.
+    pub fn compile_function_to_wat_annotated(
+        &self,
+        func_name: &str,
+        sources: &[Option<String>],
+    ) -> Result<String> {
+        let float = self.precision.type_name();
+
         // Build parameter list
         let mut input_var_ids = HashSet::new();
         for input_param in &self.input_list {
-            match input_param {
-                InputParameter::ExternalVariable(input_name, input_dtype) => {
-                    for _ in 0..input_dtype.n_lanes() {
-                        if let Some((input_var_id, _)) = self.input_to_var.get(input_name) {
-                            input_var_ids.insert(input_var_id);
-                        }
+            if let InputParameter::ExternalVariable(input_name, input_dtype) = input_param {
+                for _ in 0..input_dtype.n_lanes() {
+                    if let Some((input_var_id, _)) = self.input_to_var.get(input_name) {
+                        input_var_ids.insert(input_var_id);
                     }
                 }
-                _ => (),
             }
         }
 
         // Build local list
         let mut locals_text = String::new();
-        for (_node, (var_id, dtype)) in &self.locals {
+        for (var_id, dtype) in self.locals.values() {
             // Ignore inputs, which are already locals!
             if input_var_ids.contains(&var_id) {
                 continue;
             }
 
             for lane in "xyzw".chars().take(dtype.n_lanes()) {
-                writeln!(&mut locals_text, "(local ${var_id}_{lane} f32) ").unwrap();
+                writeln!(&mut locals_text, "(local ${var_id}_{lane} {float}) ").unwrap();
             }
         }
 
@@ -193,19 +272,26 @@ impl CodeAnalysis {
 
         // Compile instructions
         let mut function_body_text = String::new();
-        self.compile_to_wat_recursive(&self.root, &mut function_body_text, &mut HashSet::new());
+        for (node_id, node) in self.graph.iter() {
+            if let Some(Some(source)) = sources.get(node_id.index()) {
+                if !matches!(node, NodeKind::ExternInput(_, _)) {
+                    writeln!(&mut function_body_text, ";; From {source}").unwrap();
+                }
+            }
+            self.compile_node_to_wat(node_id, node, &mut function_body_text);
+        }
 
         // Write to output pointer
         let mut output_stack_text = String::new();
-        let (var_id, _) = self.locals[&self.root];
+        let (var_id, _) = self.locals[&self.graph.root()];
         let InputParameter::OutputPointer(output_ptr_id) = self.input_list[0] else {
             unreachable!()
         };
         for (idx, lane) in self.final_output_dtype().lane_names().enumerate() {
-            let offset = idx * 4; // f32 is 4 bytes
+            let offset = idx * self.precision.size_of();
             writeln!(&mut output_stack_text, "local.get ${output_ptr_id}").unwrap();
             writeln!(&mut output_stack_text, "local.get ${var_id}_{lane}").unwrap();
-            writeln!(&mut output_stack_text, "f32.store offset={offset}").unwrap();
+            writeln!(&mut output_stack_text, "{float}.store offset={offset}").unwrap();
         }
 
         let func_text = format!(
@@ -235,22 +321,22 @@ impl CodeAnalysis {
     }
 
     pub fn compile_to_wat(&self, func_name: &str) -> Result<String> {
-        let func = self.compile_function_to_wat(func_name)?;
+        self.compile_to_wat_annotated(func_name, &[])
+    }
 
-        let builtin_imports = r#"(import "builtins" "sine" (func $builtin_sine (param f32) (result f32)))
-(import "builtins" "cosine" (func $builtin_cosine (param f32) (result f32)))
-(import "builtins" "tangent" (func $builtin_tangent (param f32) (result f32)))
-(import "builtins" "natural_log" (func $builtin_natural_log (param f32) (result f32)))
-(import "builtins" "natural_exp" (func $builtin_natural_exp (param f32) (result f32)))
+    /// Compile a whole module, annotated as in `compile_function_to_wat_annotated`
+    pub fn compile_to_wat_annotated(
+        &self,
+        func_name: &str,
+        sources: &[Option<String>],
+    ) -> Result<String> {
+        let func = self.compile_function_to_wat_annotated(func_name, sources)?;
 
-(import "builtins" "power" (func $builtin_power (param f32 f32) (result f32)))
-(import "builtins" "logbase" (func $builtin_logbase (param f32 f32) (result f32)))
-(import "builtins" "greater_than" (func $builtin_greater_than (param f32 f32) (result f32)))
-(import "builtins" "less_than" (func $builtin_less_than (param f32 f32) (result f32)))
-(import "builtins" "equal_to" (func $equal_to (param f32 f32) (result f32)))"#;
+        let builtin_imports = self.builtin_imports();
 
+        // Named after the function, so that traps can be traced back to it
         let module_text = format!(
-            r#"(module
+            r#"(module ${func_name}
 ;; Import memory
 (import "env" "memory" (memory (;0;) 17))
 ;; == External imports ==
@@ -264,64 +350,198 @@ impl CodeAnalysis {
         Ok(module_text)
     }
 
-    /// A first pass which finds all local variables and inputs which are used
-    fn find_inputs_and_locals_recursive(&mut self, node_hash: HashRcByPtr<Node>) -> DataType {
-        if let Some((_number, dtype)) = self.locals.get(&node_hash) {
-            return *dtype;
+    /// Compile a module exporting `func_name`, which calls whatever function is stored in its
+    /// exported `slot` table. Callers linked against it keep working when the function is
+    /// replaced with `compile_install_to_wat`.
+    pub fn compile_dispatch_to_wat(&self, func_name: &str) -> String {
+        let func_type = self.func_type_wat();
+
+        let mut args_text = String::new();
+        for idx in 0..self.param_types().count() {
+            writeln!(&mut args_text, "local.get {idx}").unwrap();
         }
 
-        // NOTE: May or may not be used
-        let new_id = self.gen_var_id();
-
-        let dtype: DataType = match &*node_hash.0 {
-            Node::ExternInput(name, dtype) => {
-                if let Some((existing_id, _)) = self.input_to_var.get(&name) {
-                    // This input already exists. Stop before generating a new one!
-                    self.locals
-                        .insert(node_hash.clone(), (*existing_id, *dtype));
-                    return *dtype;
-                } else {
-                    self.input_to_var.insert(name.clone(), (new_id, *dtype));
-                    *dtype
-                }
-            }
-            // Depth-first search
-            Node::ComponentInfixOp(a, _, b) => {
-                let a = self.find_inputs_and_locals_recursive(HashRcByPtr(a.clone()));
-                let b = self.find_inputs_and_locals_recursive(HashRcByPtr(b.clone()));
-                assert_eq!(a, b);
-                a
-            }
-            Node::Dot(a, b) | Node::GetComponent(a, b) => {
-                self.find_inputs_and_locals_recursive(HashRcByPtr(a.clone()));
-                self.find_inputs_and_locals_recursive(HashRcByPtr(b.clone()));
-                DataType::Scalar
-            }
-            //Node::ExternSampler(_) => todo!(),
-            Node::Constant(val) => val.dtype(),
-            Node::Make(sub_nodes, _) => {
-                for sub_node in sub_nodes {
-                    assert_eq!(
-                        self.find_inputs_and_locals_recursive(HashRcByPtr(sub_node.clone())),
-                        DataType::Scalar
+        format!(
+            r#"(module
+(type $kernel {func_type})
+(table (export "slot") 1 funcref)
+(func (export "{func_name}") (type $kernel)
+{args_text}i32.const 0
+call_indirect (type $kernel)
+  )
+)"#
+        )
+    }
+
+    /// Compile a module which, when instantiated, stores the function it imports as
+    /// `kernel.func` in the table it imports as `dispatch.slot`
+    pub fn compile_install_to_wat(&self) -> String {
+        let func_type = self.func_type_wat();
+
+        format!(
+            r#"(module
+(type $kernel {func_type})
+(import "dispatch" "slot" (table 1 funcref))
+(import "kernel" "func" (func $kernel (type $kernel)))
+(elem (i32.const 0) $kernel)
+)"#
+        )
+    }
+
+    /// Compile a module which imports the function from `func_name` as `func_name`, and exports
+    /// `render_block(first: i32, count: i32, rate: float, constants..., out: i32)`. That calls
+    /// the function for samples `first..first + count`, storing the outputs one after another
+    /// from `out`. `inputs` says how each of the function's inputs is computed, in order.
+    pub fn compile_block_to_wat(&self, func_name: &str, inputs: &[BlockInput]) -> Result<String> {
+        let float = self.precision.type_name();
+        let func_type = self.func_type_wat();
+        let stride = self.final_output_dtype().n_lanes() * self.precision.size_of();
+
+        let extern_inputs: Vec<(&ExternInputId, DataType)> = self
+            .input_list
+            .iter()
+            .filter_map(|input_param| match input_param {
+                InputParameter::ExternalVariable(id, dtype) => Some((id, *dtype)),
+                InputParameter::OutputPointer(_) => None,
+            })
+            .collect();
+        ensure!(
+            extern_inputs.len() == inputs.len(),
+            "Function has {} inputs, but {} were given",
+            extern_inputs.len(),
+            inputs.len()
+        );
+
+        let mut constants_text = String::new();
+        let mut args_text = String::new();
+        for (idx, ((id, dtype), input)) in extern_inputs.iter().zip(inputs).enumerate() {
+            match input {
+                BlockInput::Time | BlockInput::SampleIndex => {
+                    ensure!(
+                        *dtype == DataType::Scalar,
+                        "{} must be a {}, not {}",
+                        id,
+                        DataType::Scalar,
+                        dtype
                     );
+                    writeln!(&mut args_text, "local.get $index").unwrap();
+                    if *input == BlockInput::Time {
+                        writeln!(&mut args_text, "local.get $rate").unwrap();
+                        writeln!(&mut args_text, "{float}.div").unwrap();
+                    }
                 }
-                match sub_nodes.len() {
-                    1 => DataType::Scalar,
-                    2 => DataType::Vec2,
-                    3 => DataType::Vec3,
-                    4 => DataType::Vec4,
-                    other => panic!("Attempted to make an vector type; {}", other),
+                BlockInput::Constant => {
+                    for lane in dtype.lane_names() {
+                        write!(&mut constants_text, "(param $c{idx}_{lane} {float}) ").unwrap();
+                        writeln!(&mut args_text, "local.get $c{idx}_{lane}").unwrap();
+                    }
                 }
             }
-            Node::ComponentFn(_, a) => {
-                self.find_inputs_and_locals_recursive(HashRcByPtr(a.clone()))
-            }
-        };
+        }
 
-        self.locals.insert(node_hash, (new_id, dtype));
+        Ok(format!(
+            r#"(module
+(type $kernel {func_type})
+(import "{func_name}" "{func_name}" (func $kernel (type $kernel)))
+(func (export "render_block") (param $first i32) (param $count i32) (param $rate {float}) {constants_text}(param $out i32)
+(local $i i32)
+(local $index {float})
+block $done
+local.get $count
+i32.eqz
+br_if $done
+loop $samples
+local.get $first
+local.get $i
+i32.add
+{float}.convert_i32_u
+local.set $index
+;; Output pointer
+local.get $out
+local.get $i
+i32.const {stride}
+i32.mul
+i32.add
+;; Inputs
+{args_text}call $kernel
+local.get $i
+i32.const 1
+i32.add
+local.tee $i
+local.get $count
+i32.lt_u
+br_if $samples
+end
+end
+  )
+)"#
+        ))
+    }
 
-        dtype
+    /// Type of the compiled function, e.g. `(func (param i32) (param f32))`
+    fn func_type_wat(&self) -> String {
+        let params: String = self
+            .param_types()
+            .map(|ty| format!(" (param {ty})"))
+            .collect();
+        format!("(func{params})")
+    }
+
+    /// Wasm type of each parameter of the compiled function, in order
+    fn param_types(&self) -> impl Iterator<Item = &'static str> + '_ {
+        let float = self.precision.type_name();
+        self.input_list
+            .iter()
+            .flat_map(move |input_param| match input_param {
+                InputParameter::OutputPointer(_) => vec!["i32"],
+                InputParameter::ExternalVariable(_, dtype) => vec![float; dtype.n_lanes()],
+            })
+    }
+
+    /// Imports of the builtin functions
+    fn builtin_imports(&self) -> String {
+        let float = self.precision.type_name();
+        let suffix = builtin_suffix(self.precision);
+
+        let mut text = String::new();
+        for (name, _) in BUILTIN_FNS {
+            writeln!(
+                &mut text,
+                r#"(import "{BUILTINS_MODULE}" "{name}{suffix}" (func $builtin_{name} (param {float}) (result {float})))"#
+            )
+            .unwrap();
+        }
+        writeln!(&mut text).unwrap();
+        for (name, _) in BUILTIN_OPS {
+            writeln!(
+                &mut text,
+                r#"(import "{BUILTINS_MODULE}" "{name}{suffix}" (func $builtin_{name} (param {float} {float}) (result {float})))"#
+            )
+            .unwrap();
+        }
+        text.truncate(text.trim_end().len());
+        text
+    }
+
+    /// A first pass which finds all local variables and inputs which are used
+    fn find_inputs_and_locals(&mut self, dtypes: &[DataType]) {
+        for (node_id, node) in self.graph.clone().iter() {
+            let dtype = dtypes[node_id.index()];
+
+            if let NodeKind::ExternInput(name, _) = node {
+                if let Some((existing_id, _)) = self.input_to_var.get(name) {
+                    // This input already exists. Reuse it instead of generating a new one!
+                    self.locals.insert(node_id, (*existing_id, dtype));
+                    continue;
+                }
+            }
+
+            let new_id = self.gen_var_id();
+            if let NodeKind::ExternInput(name, _) = node {
+                self.input_to_var.insert(name.clone(), (new_id, dtype));
+            }
+            self.locals.insert(node_id, (new_id, dtype));
+        }
     }
 
     /// Generate a new local variable ID
@@ -331,47 +551,27 @@ impl CodeAnalysis {
         ret
     }
 
-    // Explore the graph left-hand-side-first, so that inputs are computed before outputs
-    // Meanwhile assemble functions as we go
-    fn compile_to_wat_recursive(
-        &self,
-        node: &HashRcByPtr<Node>,
-        text: &mut String,
-        visited: &mut HashSet<HashRcByPtr<Node>>,
-    ) {
-        if !visited.insert(node.clone()) {
-            return;
-        }
-
-        let (out_var_id, out_dtype) = self.locals[node];
+    // Nodes are visited in topological order, so that inputs are computed before outputs
+    fn compile_node_to_wat(&self, node_id: NodeId, node: &NodeKind, text: &mut String) {
+        let (out_var_id, out_dtype) = self.locals[&node_id];
+        let float = self.precision.type_name();
 
-        match &*node.0 {
+        match node {
             // Don't need to do anything, input is already provided for us
-            Node::Make(sub_nodes, dtype) => {
+            NodeKind::Make(sub_nodes, dtype) => {
                 assert_eq!(dtype.n_lanes(), sub_nodes.len());
 
-                for sub_node in sub_nodes {
-                    let sub_node = HashRcByPtr(sub_node.clone());
-                    self.compile_to_wat_recursive(&sub_node, text, visited);
-                }
-
                 writeln!(text, ";; Make vector ${out_var_id}").unwrap();
                 for (lane, sub_node) in out_dtype.lane_names().zip(sub_nodes) {
-                    let sub_node = HashRcByPtr(sub_node.clone());
-                    let (a_id, dtype) = self.locals[&sub_node];
+                    let (a_id, dtype) = self.locals[sub_node];
                     assert_eq!(dtype, DataType::Scalar);
                     writeln!(text, "local.get ${a_id}_x").unwrap();
                     writeln!(text, "local.set ${out_var_id}_{lane}").unwrap();
                 }
             }
-            Node::GetComponent(vector_node, index_node) => {
-                for sub_node in [vector_node, index_node] {
-                    let sub_node = HashRcByPtr(sub_node.clone());
-                    self.compile_to_wat_recursive(&sub_node, text, visited);
-                }
-
-                let (vector_id, vector_dtype) = self.locals[&HashRcByPtr(vector_node.clone())];
-                let (index_id, index_dtype) = self.locals[&HashRcByPtr(index_node.clone())];
+            NodeKind::GetComponent(vector_node, index_node) => {
+                let (vector_id, vector_dtype) = self.locals[vector_node];
+                let (index_id, index_dtype) = self.locals[index_node];
                 assert_eq!(index_dtype, DataType::Scalar);
 
                 writeln!(
@@ -385,34 +585,34 @@ impl CodeAnalysis {
                 for i in 1..vector_dtype.n_lanes() {
                     // Check if the index equals this lane's index...
                     writeln!(text, "local.get ${index_id}_x ;;").unwrap();
-                    writeln!(text, "f32.floor").unwrap();
-                    writeln!(text, "f32.const {}.0", i).unwrap();
-                    writeln!(text, "f32.ge").unwrap();
+                    writeln!(text, "{float}.floor").unwrap();
+                    writeln!(text, "{float}.const {}.0", i).unwrap();
+                    writeln!(text, "{float}.ge").unwrap();
                     // Then set the output to this value
                     writeln!(text, "select").unwrap();
                 }
 
                 writeln!(text, "local.set ${out_var_id}_x").unwrap();
             }
-            Node::ExternInput(_, _) => (),
-            Node::Constant(value) => {
+            NodeKind::ExternInput(_, _) => (),
+            NodeKind::Constant(value) => {
                 writeln!(text, ";; Constant ${out_var_id} = {value:?}",).unwrap();
 
-                for (float, lane) in value.iter_vector_floats().zip(value.dtype().lane_names()) {
-                    writeln!(text, "f32.const {float}").unwrap();
+                for (value, lane) in value.iter_vector_floats().zip(value.dtype().lane_names()) {
+                    // Widened first, so that double precision gets the same value as natively
+                    match self.precision {
+                        Precision::Single => writeln!(text, "{float}.const {value}").unwrap(),
+                        Precision::Double => {
+                            writeln!(text, "{float}.const {}", f64::from(value)).unwrap()
+                        }
+                    }
                     writeln!(text, "local.set ${out_var_id}_{lane}").unwrap();
                 }
             }
-            Node::ComponentInfixOp(a, infix, b) => {
-                // Visit child nodes first
-                let a = HashRcByPtr(a.clone());
-                let b = HashRcByPtr(b.clone());
-                self.compile_to_wat_recursive(&a, text, visited);
-                self.compile_to_wat_recursive(&b, text, visited);
-
+            NodeKind::ComponentInfixOp(a, infix, b) => {
                 // Write comment
-                let (a_id, _) = self.locals[&a];
-                let (b_id, _) = self.locals[&b];
+                let (a_id, _) = self.locals[a];
+                let (b_id, _) = self.locals[b];
                 writeln!(
                     text,
                     ";; Component infix op ${out_var_id} = ${a_id} {} ${b_id}",
@@ -425,31 +625,25 @@ impl CodeAnalysis {
                     writeln!(text, "local.get ${a_id}_{lane}").unwrap();
                     writeln!(text, "local.get ${b_id}_{lane}").unwrap();
                     let op_text = match infix {
-                        ComponentInfixOp::Add => "f32.add",
-                        ComponentInfixOp::Subtract => "f32.sub",
-                        ComponentInfixOp::Divide => "f32.div",
-                        ComponentInfixOp::Multiply => "f32.mul",
-                        ComponentInfixOp::EqualTo => "call $builtin_equal_to",
-                        ComponentInfixOp::Power => "call $builtin_power",
-                        ComponentInfixOp::Logbase => "call $builtin_logbase",
-                        ComponentInfixOp::GreaterThan => "call $builtin_greater_than",
-                        ComponentInfixOp::LessThan => "call $builtin_less_than",
+                        ComponentInfixOp::Add => format!("{float}.add"),
+                        ComponentInfixOp::Subtract => format!("{float}.sub"),
+                        ComponentInfixOp::Divide => format!("{float}.div"),
+                        ComponentInfixOp::Multiply => format!("{float}.mul"),
+                        ComponentInfixOp::EqualTo => "call $builtin_equal_to".into(),
+                        ComponentInfixOp::Power => "call $builtin_power".into(),
+                        ComponentInfixOp::Logbase => "call $builtin_logbase".into(),
+                        ComponentInfixOp::GreaterThan => "call $builtin_greater_than".into(),
+                        ComponentInfixOp::LessThan => "call $builtin_less_than".into(),
                     };
 
                     writeln!(text, "{}", op_text).unwrap();
                     writeln!(text, "local.set ${out_var_id}_{lane}").unwrap();
                 }
             }
-            Node::Dot(a, b) => {
-                // Visit child nodes first
-                let a = HashRcByPtr(a.clone());
-                let b = HashRcByPtr(b.clone());
-                self.compile_to_wat_recursive(&a, text, visited);
-                self.compile_to_wat_recursive(&b, text, visited);
-
+            NodeKind::Dot(a, b) => {
                 // Write comment
-                let (a_id, a_dtype) = self.locals[&a];
-                let (b_id, b_dtype) = self.locals[&b];
+                let (a_id, a_dtype) = self.locals[a];
+                let (b_id, b_dtype) = self.locals[b];
 
                 assert_eq!(a_dtype, b_dtype);
 
@@ -459,20 +653,16 @@ impl CodeAnalysis {
                 for (idx, lane) in a_dtype.lane_names().enumerate() {
                     writeln!(text, "local.get ${a_id}_{lane}").unwrap();
                     writeln!(text, "local.get ${b_id}_{lane}").unwrap();
-                    writeln!(text, "f32.mul").unwrap();
+                    writeln!(text, "{float}.mul").unwrap();
                     if idx + 1 != out_dtype.n_lanes() {
-                        writeln!(text, "f32.add").unwrap();
+                        writeln!(text, "{float}.add").unwrap();
                     }
                 }
                 writeln!(text, "local.set ${out_var_id}_x").unwrap();
             }
-            Node::ComponentFn(func, a) => {
-                // Visit child nodes first
-                let a = HashRcByPtr(a.clone());
-                self.compile_to_wat_recursive(&a, text, visited);
-
+            NodeKind::ComponentFn(func, a) => {
                 // Write comment
-                let (a_id, _) = self.locals[&a];
+                let (a_id, _) = self.locals[a];
                 writeln!(
                     text,
                     ";; Component function ${out_var_id} = {}(${a_id})",
@@ -484,14 +674,14 @@ impl CodeAnalysis {
                 for lane in out_dtype.lane_names() {
                     writeln!(text, "local.get ${a_id}_{lane}").unwrap();
                     let op_text = match func {
-                        ComponentFn::Ceil => "f32.ceil",
-                        ComponentFn::Floor => "f32.floor",
-                        ComponentFn::Abs => "f32.abs",
-                        ComponentFn::Sine => "call $builtin_sine",
-                        ComponentFn::Cosine => "call $builtin_cosine",
-                        ComponentFn::Tangent => "call $builtin_tangent",
-                        ComponentFn::NaturalLog => "call $builtin_natural_log",
-                        ComponentFn::NaturalExp => "call $builtin_natural_exp",
+                        ComponentFn::Ceil => format!("{float}.ceil"),
+                        ComponentFn::Floor => format!("{float}.floor"),
+                        ComponentFn::Abs => format!("{float}.abs"),
+                        ComponentFn::Sine => "call $builtin_sine".into(),
+                        ComponentFn::Cosine => "call $builtin_cosine".into(),
+                        ComponentFn::Tangent => "call $builtin_tangent".into(),
+                        ComponentFn::NaturalLog => "call $builtin_natural_log".into(),
+                        ComponentFn::NaturalExp => "call $builtin_natural_exp".into(),
                     };
 
                     writeln!(text, "{}", op_text).unwrap();
@@ -503,3 +693,200 @@ impl CodeAnalysis {
         writeln!(text).unwrap();
     }
 }
+
+/// Suffix of the builtin functions of the given precision
+pub fn builtin_suffix(precision: Precision) -> &'static str {
+    match precision {
+        Precision::Single => "",
+        Precision::Double => "_f64",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use vorpal_core::native_backend::Program;
+    use wasmi::core::F64;
+
+    fn input(name: &str, dtype: DataType) -> Rc<Node> {
+        Rc::new(Node::ExternInput(ExternInputId::new(name.into()), dtype))
+    }
+
+    fn constant(value: Value) -> Rc<Node> {
+        Rc::new(Node::Constant(value))
+    }
+
+    fn infix(a: Rc<Node>, op: ComponentInfixOp, b: Rc<Node>) -> Rc<Node> {
+        Rc::new(Node::ComponentInfixOp(a, op, b))
+    }
+
+    /// Run the function compiled to double precision wasm, with every input lane widened
+    fn eval_wasm_f64(graph: &Graph, ctx: &ExternParameters) -> Vec<f64> {
+        let params = ctx.build_parameter_list();
+        let analysis = CodeAnalysis::new(graph, &params)
+            .unwrap()
+            .with_precision(Precision::Double);
+        let wat = analysis.compile_to_wat("kernel").unwrap();
+        let wasm = wat::parse_str(&wat).unwrap();
+
+        let engine = wasmi::Engine::default();
+        let module = wasmi::Module::new(&engine, &wasm[..]).unwrap();
+        let mut store = wasmi::Store::new(&engine, ());
+        let memory_type = wasmi::MemoryType::new(17, None).unwrap();
+        let memory = wasmi::Memory::new(&mut store, memory_type).unwrap();
+        let mut linker = wasmi::Linker::new(&engine);
+        linker.define("env", "memory", memory).unwrap();
+        // The tested graphs only use instructions, not builtins
+        for (name, _) in BUILTIN_FNS {
+            let func = wasmi::Func::wrap(&mut store, |_: F64| -> F64 { unreachable!() });
+            linker
+                .define(BUILTINS_MODULE, &format!("{name}_f64"), func)
+                .unwrap();
+        }
+        for (name, _) in BUILTIN_OPS {
+            let func = wasmi::Func::wrap(&mut store, |_: F64, _: F64| -> F64 { unreachable!() });
+            linker
+                .define(BUILTINS_MODULE, &format!("{name}_f64"), func)
+                .unwrap();
+        }
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .unwrap()
+            .start(&mut store)
+            .unwrap();
+
+        let mut args = vec![wasmi::Value::I32(0)];
+        args.extend(params.inputs().iter().flat_map(|(id, _)| {
+            ctx.inputs()[id]
+                .iter_vector_floats()
+                .map(|lane| wasmi::Value::F64(F64::from(f64::from(lane))))
+        }));
+        let func = instance.get_func(&store, "kernel").unwrap();
+        func.call(&mut store, &args, &mut []).unwrap();
+
+        let n_lanes = analysis.final_output_dtype().n_lanes();
+        let mut bytes = vec![0; n_lanes * 8];
+        memory.read(&store, 0, &mut bytes).unwrap();
+        bytes
+            .chunks_exact(8)
+            .map(|lane| f64::from_le_bytes(lane.try_into().unwrap()))
+            .collect()
+    }
+
+    /// A graph with shared subexpressions, whose nodes are allocated in a different order when
+    /// `reversed`, so that anything keyed by pointer would be ordered differently
+    fn shared_graph(reversed: bool) -> Graph {
+        let (x, t) = if reversed {
+            let t = input("t", DataType::Scalar);
+            (input("x", DataType::Vec2), t)
+        } else {
+            let x = input("x", DataType::Vec2);
+            (x, input("t", DataType::Scalar))
+        };
+        let sin = Rc::new(Node::ComponentFn(ComponentFn::Sine, x.clone()));
+        let dot = Rc::new(Node::Dot(sin.clone(), x));
+        let sum = infix(dot, ComponentInfixOp::Add, t);
+        let index = constant(Value::Scalar(1.));
+        let y = Rc::new(Node::GetComponent(sin, index));
+        let node = Rc::new(Node::Make(vec![sum, y], DataType::Vec2));
+        Graph::from_node(&node)
+    }
+
+    fn shared_graph_params() -> ParameterList {
+        ParameterList(vec![
+            (ExternInputId::new("t".into()), DataType::Scalar),
+            (ExternInputId::new("x".into()), DataType::Vec2),
+        ])
+    }
+
+    #[test]
+    fn wat_is_deterministic() {
+        let params = shared_graph_params();
+        let compile = |graph: &Graph| {
+            CodeAnalysis::new(graph, &params)
+                .unwrap()
+                .compile_to_wat("kernel")
+                .unwrap()
+        };
+
+        let graph = shared_graph(false);
+        let wat = compile(&graph);
+        assert_eq!(wat, compile(&graph));
+        assert_eq!(wat, compile(&shared_graph(false)));
+        let rebuilt = shared_graph(true);
+        assert_eq!(graph, rebuilt);
+        assert_eq!(wat, compile(&rebuilt));
+    }
+
+    #[test]
+    fn annotated_wat_has_node_comments() {
+        let graph = shared_graph(false);
+        let sources: Vec<Option<String>> = graph
+            .iter()
+            .map(|(id, _)| Some(format!("Editor node {}", id.index())))
+            .collect();
+        let analysis = CodeAnalysis::new(&graph, &shared_graph_params()).unwrap();
+        let wat = analysis
+            .compile_to_wat_annotated("kernel", &sources)
+            .unwrap();
+
+        // Every node but the inputs, which have no instructions of their own
+        for (id, node) in graph.iter() {
+            let comment = format!(";; From Editor node {}\n", id.index());
+            let is_input = matches!(node, NodeKind::ExternInput(_, _));
+            assert_eq!(wat.contains(&comment), !is_input, "{comment}");
+        }
+        assert!(!analysis
+            .compile_to_wat("kernel")
+            .unwrap()
+            .contains(";; From"));
+    }
+
+    #[test]
+    fn double_precision_constants_match_native() {
+        use ComponentInfixOp::*;
+
+        let x = input("x", DataType::Vec4);
+        let scaled = infix(x, Multiply, constant(Value::Vec4([0.1, 0.2, 0.3, 1e-7])));
+        let shifted = infix(scaled, Add, constant(Value::Vec4([0.7, -3.3, 1.1, 1e7])));
+        let third = infix(
+            constant(Value::Scalar(1.)),
+            Divide,
+            constant(Value::Scalar(3.)),
+        );
+        let offset = Rc::new(Node::Make(
+            vec![
+                constant(Value::Scalar(0.1)),
+                third.clone(),
+                constant(Value::Scalar(2.6)),
+                third,
+            ],
+            DataType::Vec4,
+        ));
+        let node = infix(shifted, Subtract, offset);
+        let graph = Graph::from_node(&node);
+
+        let ctx = ExternParameters::new(
+            [(
+                ExternInputId::new("x".into()),
+                Value::Vec4([1., 0.3, -2.5, 12345.]),
+            )]
+            .into_iter()
+            .collect(),
+        );
+        let native = Program::new(&graph, &ctx.build_parameter_list())
+            .unwrap()
+            .evaluate_batch::<f64>(&ctx, &HashMap::new())
+            .unwrap();
+
+        let wasm = eval_wasm_f64(&graph, &ctx);
+        let bits = |values: &[f64]| values.iter().map(|x| x.to_bits()).collect::<Vec<_>>();
+        assert_eq!(
+            bits(&wasm),
+            bits(native.data()),
+            "{wasm:?} != {:?}",
+            native.data()
+        );
+    }
+}
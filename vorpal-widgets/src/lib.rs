@@ -1,3 +1,5 @@
 pub use vorpal_core;
 pub mod image_view;
+pub mod mesh_view;
 pub mod node_editor;
+pub mod plot;
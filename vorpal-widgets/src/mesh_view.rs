@@ -0,0 +1,149 @@
+use egui::{epaint, pos2, Color32, Pos2, Response, Sense, Shape, Stroke, Ui};
+
+/// Flat shaded or wireframe preview of a triangle mesh, which is rotated by dragging and zoomed
+/// with ctrl + scroll. Triangles are drawn back to front without a depth buffer.
+pub struct MeshViewWidget {
+    /// Rotation around the Z axis, in radians
+    pub yaw: f32,
+    /// Tilt of the camera from the horizon towards looking down, in radians
+    pub pitch: f32,
+    pub zoom: f32,
+    pub wireframe: bool,
+}
+
+/// The mesh to show. Every slice but `triangles` has one element per vertex.
+pub struct MeshData<'a> {
+    pub positions: &'a [[f32; 3]],
+    pub normals: &'a [[f32; 3]],
+    pub colors: Option<&'a [[f32; 3]]>,
+    pub triangles: &'a [[u32; 3]],
+}
+
+impl Default for MeshViewWidget {
+    fn default() -> Self {
+        Self {
+            yaw: 0.5,
+            pitch: 0.6,
+            zoom: 1.,
+            wireframe: false,
+        }
+    }
+}
+
+impl MeshViewWidget {
+    pub fn show(&mut self, ui: &mut Ui, mesh: MeshData<'_>) -> Response {
+        let (rect, response) = ui.allocate_exact_size(ui.available_size(), Sense::drag());
+        let drag = response.drag_delta();
+        self.yaw -= drag.x * 0.01;
+        self.pitch = (self.pitch + drag.y * 0.01).clamp(-1.57, 1.57);
+        if response.hovered() {
+            self.zoom *= ui.input(|input| input.zoom_delta());
+        }
+
+        let painter = ui.painter_at(rect);
+        painter.rect_filled(rect, 0., ui.visuals().extreme_bg_color);
+
+        let Some((center, radius)) = bounding_sphere(mesh.positions) else {
+            return response;
+        };
+
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
+        // Right, up and towards the viewer
+        let rotate = |[x, y, z]: [f32; 3]| {
+            let (x, y) = (x * cos_yaw - y * sin_yaw, x * sin_yaw + y * cos_yaw);
+            [
+                x,
+                z * cos_pitch + y * sin_pitch,
+                z * sin_pitch - y * cos_pitch,
+            ]
+        };
+
+        let scale = self.zoom * rect.width().min(rect.height()) / (2. * radius);
+        let view: Vec<[f32; 3]> = mesh
+            .positions
+            .iter()
+            .map(|position| rotate(std::array::from_fn(|i| position[i] - center[i])))
+            .collect();
+        let screen = |[x, y, _]: [f32; 3]| rect.center() + egui::vec2(x, -y) * scale;
+
+        // Back to front
+        let mut triangles: Vec<&[u32; 3]> = mesh
+            .triangles
+            .iter()
+            .filter(|triangle| {
+                triangle
+                    .iter()
+                    .all(|&idx| view[idx as usize].iter().all(|x| x.is_finite()))
+            })
+            .collect();
+        let depth =
+            |triangle: &[u32; 3]| -> f32 { triangle.iter().map(|&i| view[i as usize][2]).sum() };
+        triangles.sort_by(|a, b| depth(a).total_cmp(&depth(b)));
+
+        if self.wireframe {
+            let stroke = Stroke::new(1., ui.visuals().text_color());
+            let shapes = triangles.iter().flat_map(|[a, b, c]| {
+                [(a, b), (b, c), (c, a)].map(|(from, to)| {
+                    let points: [Pos2; 2] =
+                        [screen(view[*from as usize]), screen(view[*to as usize])];
+                    Shape::line_segment(points, stroke)
+                })
+            });
+            painter.extend(shapes);
+        } else {
+            // Lit from the upper left, on both sides
+            let light = normalize([-0.4, 0.6, 0.7]);
+            let mut out = epaint::Mesh::default();
+            for (idx, position) in view.iter().enumerate() {
+                let normal = mesh.normals.get(idx).map_or([0., 0., 1.], |n| rotate(*n));
+                let brightness = 0.25 + 0.75 * dot(normal, light).abs();
+                let [r, g, b] = mesh
+                    .colors
+                    .and_then(|colors| colors.get(idx))
+                    .copied()
+                    .unwrap_or([0.8; 3])
+                    .map(|c| (c.clamp(0., 1.) * brightness * 255.) as u8);
+                let pos = if position.iter().all(|x| x.is_finite()) {
+                    screen(*position)
+                } else {
+                    pos2(0., 0.)
+                };
+                out.colored_vertex(pos, Color32::from_rgb(r, g, b));
+            }
+            for [a, b, c] in triangles {
+                out.add_triangle(*a, *b, *c);
+            }
+            painter.add(Shape::mesh(out));
+        }
+
+        response
+    }
+}
+
+/// Center and radius of a sphere around the finite positions
+fn bounding_sphere(positions: &[[f32; 3]]) -> Option<([f32; 3], f32)> {
+    let mut bounds: Option<([f32; 3], [f32; 3])> = None;
+    for position in positions.iter().filter(|p| p.iter().all(|x| x.is_finite())) {
+        let (min, max) = bounds.get_or_insert((*position, *position));
+        for axis in 0..3 {
+            min[axis] = min[axis].min(position[axis]);
+            max[axis] = max[axis].max(position[axis]);
+        }
+    }
+
+    let (min, max) = bounds?;
+    let center = std::array::from_fn(|axis| (min[axis] + max[axis]) / 2.);
+    let half_diagonal: [f32; 3] = std::array::from_fn(|axis| (max[axis] - min[axis]) / 2.);
+    let radius = dot(half_diagonal, half_diagonal).sqrt().max(1e-6);
+    Some((center, radius))
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    (0..3).map(|axis| a[axis] * b[axis]).sum()
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let len = dot(v, v).sqrt();
+    v.map(|x| x / len)
+}
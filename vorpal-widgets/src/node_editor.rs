@@ -5,19 +5,31 @@ use std::{
     collections::{HashMap, HashSet},
     rc::Rc,
 };
-use vorpal_core::highlevel::HighNode;
+use vorpal_core::highlevel::{convert_graph_with_origins, HighNode};
+use vorpal_core::range_analysis::ParameterBounds;
 use vorpal_core::*;
 
 const XYZW: [&str; 4] = ["x", "y", "z", "w"];
 
+const ERROR_COLOR: Color32 = Color32::from_rgb(0xc0, 0x30, 0x30);
+
 /// Widget allowing the user to interactively design
 /// a function using a node and connection paradigm
 #[cfg_attr(feature = "persistence", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Clone)]
 pub struct NodeGraphWidget {
     params: ParameterList,
+    /// Declared range of each parameter, used to find operations which may produce NaN
+    #[cfg_attr(feature = "persistence", serde(default))]
+    bounds: ParameterBounds,
     state: MyEditorState,
     user_state: MyGraphState,
+    /// Editor node which produced each node of the last extracted graph
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    origins: Vec<Option<NodeId>>,
+    /// Node to center the view on the next time the widget is shown
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    focus: Option<NodeId>,
 }
 
 type MyGraph = Graph<MyNodeData, DataType, NodeGuiValue>;
@@ -39,7 +51,8 @@ pub struct NodeGuiValue(Value);
 
 /// NodeTemplate is a mechanism to define node templates. It's what the graph
 /// will display in the "new node" popup. The user code needs to tell the
-/// library how to convert a NodeTemplate into a Node.
+/// library how to convert a NodeTemplate into a Node. Saved projects are also read by
+/// `vorpal_core::project`, which mirrors these variants.
 #[derive(Clone)]
 #[cfg_attr(feature = "persistence", derive(serde::Serialize, serde::Deserialize))]
 pub enum MyNodeTemplate {
@@ -53,6 +66,7 @@ pub enum MyNodeTemplate {
     Normalize(DataType),
     Splat(DataType),
     Swizzle(DataType, DataType),
+    Gradient(DataType),
     Comment,
 }
 
@@ -77,6 +91,12 @@ pub enum MyResponse {
 pub struct MyGraphState {
     active_node: Option<NodeId>,
     comments: UniqueSecondaryMap<NodeId, String>,
+    /// Errors from compiling or evaluating the graph, pinned to the node at fault
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    errors: Vec<(NodeId, String)>,
+    /// Operations which may produce NaN or infinity
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    warnings: Vec<(NodeId, String)>,
 }
 
 // =========== Then, you need to implement some traits ============
@@ -123,6 +143,7 @@ impl NodeTemplateTrait for MyNodeTemplate {
             Self::Output(dtype) => format!("Output ({dtype})"),
             Self::Dot(dtype) => format!("Dot ({dtype})"),
             Self::Swizzle(dtype, other_dtype) => format!("Swizzle {dtype} -> {other_dtype}"),
+            Self::Gradient(dtype) => format!("Gradient ({dtype})"),
             Self::Comment => format!("Comment"),
         })
     }
@@ -136,6 +157,7 @@ impl NodeTemplateTrait for MyNodeTemplate {
             | MyNodeTemplate::ComponentFn(_, dtype)
             | MyNodeTemplate::GetComponent(dtype)
             | MyNodeTemplate::Normalize(dtype)
+            | MyNodeTemplate::Gradient(dtype)
             | MyNodeTemplate::Dot(dtype) => vec![dtype.dtype_name()],
             MyNodeTemplate::Input(_name, dtype) => vec!["Input", dtype.dtype_name()],
             MyNodeTemplate::Swizzle(dtype, _other_dtype) => {
@@ -237,6 +259,11 @@ impl NodeTemplateTrait for MyNodeTemplate {
                 add_input(graph, "indices", *output_dtype);
                 add_output(graph, "out", *output_dtype);
             }
+            MyNodeTemplate::Gradient(dtype) => {
+                add_input(graph, "f", DataType::Scalar);
+                add_input(graph, "wrt", *dtype);
+                add_output(graph, "out", *dtype);
+            }
             MyNodeTemplate::Comment => {}
         }
     }
@@ -278,6 +305,7 @@ impl NodeTemplateIter for AllMyNodeTemplates<'_> {
             }
             types.push(MyNodeTemplate::ComponentFn(ComponentFn::NaturalLog, dtype));
             types.push(MyNodeTemplate::Dot(dtype));
+            types.push(MyNodeTemplate::Gradient(dtype));
         }
 
         for (id, dtype) in self.params.inputs() {
@@ -438,6 +466,16 @@ impl NodeDataTrait for MyNodeData {
             _ => (),
         }
 
+        for (_, msg) in user_state.errors.iter().filter(|(id, _)| *id == node_id) {
+            ui.colored_label(ui.visuals().error_fg_color, "⚠ Error")
+                .on_hover_text(msg);
+        }
+
+        for (_, msg) in user_state.warnings.iter().filter(|(id, _)| *id == node_id) {
+            ui.colored_label(ui.visuals().warn_fg_color, "⚠ Warning")
+                .on_hover_text(msg);
+        }
+
         let is_active = user_state
             .active_node
             .map(|id| id == node_id)
@@ -472,6 +510,10 @@ impl NodeDataTrait for MyNodeData {
         graph: &Graph<Self, Self::DataType, Self::ValueType>,
         user_state: &mut Self::UserState,
     ) -> Option<egui::Color32> {
+        if user_state.errors.iter().any(|(id, _)| *id == node_id) {
+            return Some(ERROR_COLOR);
+        }
+
         graph[node_id]
             .user_data
             .template
@@ -517,7 +559,12 @@ fn detect_cycle_recursive(graph: &MyGraph, node_id: NodeId, stack: &mut HashSet<
 }
 
 fn extract_node_from_graph(graph: &MyGraph, node_id: NodeId) -> anyhow::Result<Rc<HighNode>> {
-    extract_node_from_graph_recursive(graph, node_id, &mut OutputsCache::new())
+    extract_node_from_graph_recursive(
+        graph,
+        node_id,
+        &mut OutputsCache::new(),
+        &mut Origins::new(),
+    )
 }
 
 // Returns the ID of the HighNode corresponding to given parameter of the node "node_id"
@@ -525,6 +572,7 @@ fn extract_node_from_graph_recursive(
     graph: &MyGraph,
     node_id: NodeId,
     cache: &mut OutputsCache,
+    origins: &mut Origins,
 ) -> anyhow::Result<Rc<HighNode>> {
     let node = &graph[node_id];
 
@@ -535,71 +583,89 @@ fn extract_node_from_graph_recursive(
         }
     }
 
-    Ok(match &node.user_data.template {
+    let extracted = match &node.user_data.template {
         MyNodeTemplate::ComponentFn(func, _dtype) => Rc::new(HighNode::ComponentFn(
             *func,
-            get_input_node(graph, node_id, "x", cache)?,
+            get_input_node(graph, node_id, "x", cache, origins)?,
         )),
         MyNodeTemplate::GetComponent(_dtype) => Rc::new(HighNode::GetComponent(
-            get_input_node(graph, node_id, "value", cache)?,
-            get_input_node(graph, node_id, "index", cache)?,
+            get_input_node(graph, node_id, "value", cache, origins)?,
+            get_input_node(graph, node_id, "index", cache, origins)?,
         )),
         MyNodeTemplate::ComponentInfixOp(op, _dtype) => Rc::new(HighNode::ComponentInfixOp(
-            get_input_node(graph, node_id, "x", cache)?,
+            get_input_node(graph, node_id, "x", cache, origins)?,
             *op,
-            get_input_node(graph, node_id, "y", cache)?,
+            get_input_node(graph, node_id, "y", cache, origins)?,
         )),
         MyNodeTemplate::Make(dtype) => Rc::new(HighNode::Make(
             XYZW.iter()
                 .take(dtype.n_lanes())
-                .map(|name| get_input_node(graph, node_id, name, cache))
+                .map(|name| get_input_node(graph, node_id, name, cache, origins))
                 .collect::<Result<_, _>>()?,
             *dtype,
         )),
         MyNodeTemplate::Input(name, dtype) => Rc::new(HighNode::ExternInput(name.clone(), *dtype)),
-        MyNodeTemplate::Output(_dtype) => get_input_node(graph, node_id, "x", cache)?,
+        MyNodeTemplate::Output(_dtype) => get_input_node(graph, node_id, "x", cache, origins)?,
         MyNodeTemplate::Dot(_dtype) => Rc::new(HighNode::Dot(
-            get_input_node(graph, node_id, "x", cache)?,
-            get_input_node(graph, node_id, "y", cache)?,
+            get_input_node(graph, node_id, "x", cache, origins)?,
+            get_input_node(graph, node_id, "y", cache, origins)?,
         )),
         MyNodeTemplate::Normalize(dtype) => Rc::new(HighNode::Normalize(
-            get_input_node(graph, node_id, "x", cache)?,
+            get_input_node(graph, node_id, "x", cache, origins)?,
             *dtype,
         )),
         MyNodeTemplate::Splat(dtype) => Rc::new(HighNode::Splat(
-            get_input_node(graph, node_id, "x", cache)?,
+            get_input_node(graph, node_id, "x", cache, origins)?,
             *dtype,
         )),
         MyNodeTemplate::Swizzle(input_dtype, output_dtype) => Rc::new(HighNode::Swizzle {
-            input_vector: get_input_node(graph, node_id, "x", cache)?,
-            component_vector: get_input_node(graph, node_id, "indices", cache)?,
+            input_vector: get_input_node(graph, node_id, "x", cache, origins)?,
+            component_vector: get_input_node(graph, node_id, "indices", cache, origins)?,
             input_vector_dtype: *input_dtype,
             output_vector_dtype: *output_dtype,
         }),
+        MyNodeTemplate::Gradient(_dtype) => Rc::new(HighNode::Gradient {
+            f: get_input_node(graph, node_id, "f", cache, origins)?,
+            wrt: get_input_node(graph, node_id, "wrt", cache, origins)?,
+        }),
         MyNodeTemplate::Comment => unreachable!(),
-    })
+    };
+
+    // The output node passes its input through, which already has an origin
+    origins
+        .entry(HashRcByPtr(extracted.clone()))
+        .or_insert(node_id);
+
+    Ok(extracted)
 }
 
 type OutputsCache = HashMap<OutputId, Rc<HighNode>>;
 
+/// Editor node which produced each high-level node
+type Origins = HashMap<HashRcByPtr<HighNode>, NodeId>;
+
 fn get_input_node(
     graph: &MyGraph,
     node_id: NodeId,
     param_name: &str,
     cache: &mut OutputsCache,
+    origins: &mut Origins,
 ) -> anyhow::Result<Rc<HighNode>> {
     let input_id = graph[node_id].get_input(param_name)?;
 
     // The output of another node is connected.
     if let Some(other_output_id) = graph.connection(input_id) {
-        let node = extract_node_from_graph_recursive(graph, graph[other_output_id].node, cache)?;
+        let node =
+            extract_node_from_graph_recursive(graph, graph[other_output_id].node, cache, origins)?;
         cache.insert(other_output_id, node.clone());
         Ok(node)
     }
     // No existing connection, take the inline value instead.
     else {
         let NodeGuiValue(value) = graph[input_id].value;
-        Ok(Rc::new(HighNode::Constant(value)))
+        let constant = Rc::new(HighNode::Constant(value));
+        origins.insert(HashRcByPtr(constant.clone()), node_id);
+        Ok(constant)
     }
 }
 
@@ -618,6 +684,8 @@ impl NodeGraphWidget {
         let mut user_state: MyGraphState = MyGraphState {
             active_node: None,
             comments: UniqueSecondaryMap::new_from_key(&state.graph.nodes),
+            errors: vec![],
+            warnings: vec![],
         };
 
         let output = MyNodeTemplate::Output(output_dtype);
@@ -633,8 +701,11 @@ impl NodeGraphWidget {
 
         Self {
             params,
+            bounds: ParameterBounds::new(),
             state,
             user_state,
+            origins: vec![],
+            focus: None,
         }
     }
 
@@ -654,7 +725,21 @@ impl NodeGraphWidget {
         &mut self.params
     }
 
+    pub fn bounds(&self) -> &ParameterBounds {
+        &self.bounds
+    }
+
+    pub fn bounds_mut(&mut self) -> &mut ParameterBounds {
+        &mut self.bounds
+    }
+
     pub fn show(&mut self, ui: &mut Ui) {
+        if let Some(node_id) = self.focus.take() {
+            if let Some(pos) = self.state.node_positions.get(node_id) {
+                self.state.pan_zoom.pan = ui.available_size() / 2. - pos.to_vec2();
+            }
+        }
+
         let before: HashSet<InputId> = self.state.graph.connections.keys().collect();
         let resp = self.state.draw_graph_editor(
             ui,
@@ -719,17 +804,129 @@ impl NodeGraphWidget {
     }
 
     pub fn extract_output_node(&mut self) -> Rc<HighNode> {
-        let node_id = self
-            .state
+        extract_node_from_graph(&self.state.graph, self.output_node_id()).unwrap()
+    }
+
+    /// Extract and lower the output of the graph, remembering which editor node each node of
+    /// the result came from so that errors may be pinned to them with `set_errors`
+    pub fn extract_output_graph(&mut self) -> vorpal_core::graph::Graph {
+        let mut origins = Origins::new();
+        let output_id = self.output_node_id();
+        let high = extract_node_from_graph_recursive(
+            &self.state.graph,
+            output_id,
+            &mut OutputsCache::new(),
+            &mut origins,
+        )
+        .unwrap();
+        let (graph, graph_origins) = convert_graph_with_origins(high, &|node| {
+            origins.get(&HashRcByPtr(node.clone())).copied()
+        });
+        self.origins = graph_origins;
+        graph
+    }
+
+    /// Title and ID of the editor node which produced each node of the last extracted graph,
+    /// for annotating generated code
+    pub fn node_sources(&self) -> Vec<Option<String>> {
+        self.origins
+            .iter()
+            .map(|origin| {
+                origin
+                    .filter(|id| self.state.graph.nodes.contains_key(*id))
+                    .map(|id| format!("{} ({:?})", self.state.graph[id].label, id))
+            })
+            .collect()
+    }
+
+    /// Show errors on the nodes they came from. Errors refer to nodes of the graph last returned
+    /// by `extract_output_graph`; errors which cannot be attributed go to the output node.
+    pub fn set_errors(&mut self, errors: Vec<(Option<vorpal_core::graph::NodeId>, String)>) {
+        self.user_state.errors = self.pin_to_editor_nodes(errors);
+        self.user_state.errors.extend(self.gradient_errors());
+    }
+
+    /// Show warnings on the nodes they came from, in the same way as `set_errors`
+    pub fn set_warnings(&mut self, warnings: Vec<(Option<vorpal_core::graph::NodeId>, String)>) {
+        self.user_state.warnings = self.pin_to_editor_nodes(warnings);
+    }
+
+    pub fn errors(&self) -> &[(NodeId, String)] {
+        &self.user_state.errors
+    }
+
+    pub fn warnings(&self) -> &[(NodeId, String)] {
+        &self.user_state.warnings
+    }
+
+    /// Select the node with the given error, and center the view on it
+    pub fn focus_error(&mut self, idx: usize) {
+        if let Some((node_id, _)) = self.user_state.errors.get(idx) {
+            self.state.selected_nodes = vec![*node_id];
+            self.focus = Some(*node_id);
+        }
+    }
+
+    /// Select the node with the given warning, and center the view on it
+    pub fn focus_warning(&mut self, idx: usize) {
+        if let Some((node_id, _)) = self.user_state.warnings.get(idx) {
+            self.state.selected_nodes = vec![*node_id];
+            self.focus = Some(*node_id);
+        }
+    }
+
+    /// Find the editor node which produced each node of the last extracted graph
+    fn pin_to_editor_nodes(
+        &self,
+        messages: Vec<(Option<vorpal_core::graph::NodeId>, String)>,
+    ) -> Vec<(NodeId, String)> {
+        let output_id = self.output_node_id();
+        messages
+            .into_iter()
+            .map(|(graph_node, msg)| {
+                let node_id = graph_node
+                    .and_then(|id| self.origins.get(id.index()).copied().flatten())
+                    .filter(|id| self.state.graph.nodes.contains_key(*id))
+                    .unwrap_or(output_id);
+                (node_id, msg)
+            })
+            .collect()
+    }
+
+    /// Gradient nodes whose `wrt` is not connected to an input node. Anything else, such as an
+    /// inline constant, would silently give a gradient of zero.
+    fn gradient_errors(&self) -> Vec<(NodeId, String)> {
+        let graph = &self.state.graph;
+        graph
+            .nodes
+            .iter()
+            .filter(|(_, node)| matches!(node.user_data.template, MyNodeTemplate::Gradient(_)))
+            .filter(|(_, node)| {
+                let wrt_node = node
+                    .get_input("wrt")
+                    .ok()
+                    .and_then(|input_id| graph.connection(input_id))
+                    .map(|output_id| graph[output_id].node);
+                !wrt_node.is_some_and(|id| {
+                    matches!(graph[id].user_data.template, MyNodeTemplate::Input(..))
+                })
+            })
+            .map(|(id, _)| {
+                let msg = "Gradient must be taken with respect to an input".to_string();
+                (id, msg)
+            })
+            .collect()
+    }
+
+    fn output_node_id(&self) -> NodeId {
+        self.state
             .graph
             .nodes
             .iter()
             .find_map(|(id, node)| {
                 matches!(node.user_data.template, MyNodeTemplate::Output(_)).then(|| id)
             })
-            .unwrap();
-        let extracted = extract_node_from_graph(&self.state.graph, node_id).unwrap();
-        extracted
+            .unwrap()
     }
 }
 
@@ -767,6 +964,7 @@ impl MyNodeTemplate {
             | MyNodeTemplate::Output(dtype)
             | MyNodeTemplate::Normalize(dtype)
             | MyNodeTemplate::Swizzle(_, dtype)
+            | MyNodeTemplate::Gradient(dtype)
             | MyNodeTemplate::Dot(dtype) => Some(*dtype),
             MyNodeTemplate::Comment => None,
         }
@@ -0,0 +1,163 @@
+use egui::{
+    pos2, vec2, Align2, Color32, FontId, Painter, Rect, Response, Sense, Shape, Stroke, Ui,
+};
+
+/// Plot evenly spaced values from left to right, with `y_range` filling the height. Where there
+/// are more values than pixels, each column spans the values which fall in it.
+pub fn line_plot(ui: &mut Ui, values: &[f32], y_range: (f32, f32), height: f32) -> Response {
+    let (rect, response) =
+        ui.allocate_exact_size(vec2(ui.available_width(), height), Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0., ui.visuals().extreme_bg_color);
+
+    let (min, max) = y_range;
+    let to_y = |value: f32| {
+        let t = ((value - min) / (max - min)).clamp(0., 1.);
+        rect.bottom() - t * rect.height()
+    };
+
+    let axis = Stroke::new(1., ui.visuals().weak_text_color());
+    if min < 0. && max > 0. {
+        painter.hline(rect.x_range(), to_y(0.), axis);
+    }
+
+    let stroke = Stroke::new(1., ui.visuals().selection.bg_fill);
+    paint_values(&painter, rect, values, to_y, stroke);
+
+    painter.rect_stroke(rect, 0., axis);
+    response
+}
+
+/// Plot several series of evenly spaced values over each other, each in its own color. The
+/// bounds of `y_range` are written at the left, and the pointer is marked with a vertical line.
+pub fn overlay_plot(ui: &mut Ui, series: &[&[f32]], y_range: (f32, f32), height: f32) -> Response {
+    let (rect, response) =
+        ui.allocate_exact_size(vec2(ui.available_width(), height), Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0., ui.visuals().extreme_bg_color);
+
+    let (min, max) = y_range;
+    let to_y = |value: f32| {
+        let t = ((value - min) / (max - min)).clamp(0., 1.);
+        rect.bottom() - t * rect.height()
+    };
+
+    let axis = Stroke::new(1., ui.visuals().weak_text_color());
+    if min < 0. && max > 0. {
+        painter.hline(rect.x_range(), to_y(0.), axis);
+    }
+    if let Some(pos) = response.hover_pos() {
+        painter.vline(pos.x, rect.y_range(), axis);
+    }
+
+    for (idx, values) in series.iter().enumerate() {
+        let stroke = Stroke::new(1.5, series_color(idx));
+        paint_values(&painter, rect, values, to_y, stroke);
+    }
+
+    let font = FontId::monospace(10.);
+    let text_color = ui.visuals().weak_text_color();
+    let margin = vec2(2., 2.);
+    painter.text(
+        rect.left_top() + margin,
+        Align2::LEFT_TOP,
+        format!("{max:.3}"),
+        font.clone(),
+        text_color,
+    );
+    painter.text(
+        rect.left_bottom() + vec2(margin.x, -margin.y),
+        Align2::LEFT_BOTTOM,
+        format!("{min:.3}"),
+        font,
+        text_color,
+    );
+
+    painter.rect_stroke(rect, 0., axis);
+    response
+}
+
+/// Color of the series at `idx` in `overlay_plot`
+pub fn series_color(idx: usize) -> Color32 {
+    const PALETTE: [Color32; 6] = [
+        Color32::from_rgb(90, 170, 255),
+        Color32::from_rgb(255, 140, 60),
+        Color32::from_rgb(100, 210, 110),
+        Color32::from_rgb(230, 90, 200),
+        Color32::from_rgb(240, 210, 70),
+        Color32::from_rgb(70, 210, 210),
+    ];
+    PALETTE[idx % PALETTE.len()]
+}
+
+/// Smallest and largest of the finite values, widened a little so that the plotted lines stay
+/// clear of the edges. Flat series get a range around their value.
+pub fn value_range<'a>(series: impl IntoIterator<Item = &'a [f32]>) -> (f32, f32) {
+    let finite = series
+        .into_iter()
+        .flatten()
+        .filter(|value| value.is_finite());
+    let (min, max) = finite.fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), v| {
+        (min.min(*v), max.max(*v))
+    });
+    if min > max {
+        (-1., 1.)
+    } else if min == max {
+        (min - 1., max + 1.)
+    } else {
+        let pad = (max - min) * 0.05;
+        (min - pad, max + pad)
+    }
+}
+
+/// Draw values from the left of `rect` to the right
+fn paint_values(
+    painter: &Painter,
+    rect: Rect,
+    values: &[f32],
+    to_y: impl Fn(f32) -> f32,
+    stroke: Stroke,
+) {
+    let finite = |value: &&f32| value.is_finite();
+    let n_columns = rect.width().max(1.) as usize;
+    if values.len() > n_columns {
+        for column in 0..n_columns {
+            let start = column * values.len() / n_columns;
+            let end = ((column + 1) * values.len() / n_columns).max(start + 1);
+            let column_values = values[start..end].iter().filter(finite);
+            let (lo, hi) = column_values.fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), v| {
+                (lo.min(*v), hi.max(*v))
+            });
+            if lo <= hi {
+                let x = rect.left() + column as f32 + 0.5;
+                // Keep flat stretches visible
+                let (top, bottom) = (to_y(hi) - 0.5, to_y(lo) + 0.5);
+                painter.vline(x, top..=bottom, stroke);
+            }
+        }
+    } else if values.len() > 1 {
+        let dx = rect.width() / (values.len() - 1) as f32;
+        let points = values
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| value.is_finite())
+            .map(|(idx, value)| pos2(rect.left() + idx as f32 * dx, to_y(*value)))
+            .collect();
+        painter.add(Shape::line(points, stroke));
+    }
+}
+
+/// Index of the value under the pointer, for a plot of `n_values` shown with `line_plot` or
+/// `overlay_plot`
+pub fn hovered_index(response: &Response, n_values: usize) -> Option<usize> {
+    let pos = response.hover_pos()?;
+    let rect = response.rect;
+    let t = ((pos.x - rect.left()) / rect.width()).clamp(0., 1.);
+    if n_values > rect.width().max(1.) as usize {
+        // Within the column of values under the pointer
+        Some(((t * n_values as f32) as usize).min(n_values - 1))
+    } else {
+        // The nearest of the points the line passes through
+        (n_values > 0).then(|| (t * (n_values - 1) as f32).round() as usize)
+    }
+}